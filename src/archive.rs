@@ -0,0 +1,297 @@
+//! Rotating archive of the mic capture, for round-the-clock monitoring.
+//!
+//! Capture is written to 16-bit WAV segments of a fixed number of samples each
+//! (`archive_<start ms>.wav`), so segment boundaries are sample-accurate and nothing is lost or
+//! repeated between files. `index.txt` in the archive directory maps wall-clock time to file and
+//! sample offset, one line per segment start and one more whenever capture resumes after a gap:
+//!
+//! ```text
+//! <unix time ms> <file> <sample offset>
+//! ```
+//!
+//! Old segments are deleted when they are older than `archive_max_age_secs` or when the archive
+//! grows beyond `archive_max_bytes`. Like the recorder, the writing happens on its own thread.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const ARCHIVE_QUEUE: usize = 1024;
+
+const FLUSH_EVERY_SAMPLES: u64 = 44_100;
+
+// Capture arriving this much later than expected starts a new index entry
+const MAX_DRIFT: Duration = Duration::from_secs(1);
+
+const INDEX_FILE: &str = "index.txt";
+
+// Blocks of capture, with when they were received
+type Block = (SystemTime, Vec<f32>);
+
+#[derive(Debug, Clone)]
+pub struct ArchiveSettings {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    pub segment_secs: f64,
+    /// Delete segments older than this, 0 keeps them forever.
+    pub max_age_secs: u64,
+    /// Delete the oldest segments beyond this total size, 0 for no limit.
+    pub max_bytes: u64,
+}
+
+impl Default for ArchiveSettings {
+    fn default() -> ArchiveSettings {
+        ArchiveSettings {
+            enabled: false,
+            dir: PathBuf::from("archive"),
+            segment_secs: 600.0,
+            max_age_secs: 7 * 24 * 3600,
+            max_bytes: 0,
+        }
+    }
+}
+
+pub struct Archive {
+    sender: Mutex<Option<SyncSender<Block>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Archive {
+
+    /// Creates the archive directory and starts the writer thread.
+    pub fn start(settings: &ArchiveSettings, sample_rate: u32) -> io::Result<Archive> {
+        fs::create_dir_all(&settings.dir)?;
+
+        let (sender, receiver) = sync_channel(ARCHIVE_QUEUE);
+        let mut segmenter = Segmenter {
+            settings: settings.clone(),
+            sample_rate,
+            segment_length: std::cmp::max(1, (settings.segment_secs * sample_rate as f64) as u64),
+            writer: None,
+            segment_name: String::new(),
+            segment_written: 0,
+            since_flush: 0,
+            next_time: None,
+        };
+
+        let handle = std::thread::spawn(move || {
+            if let Err(e) = segmenter.run(receiver) {
+                println!("Archive stopped: {}", e);
+            }
+        });
+
+        println!("Archiving capture to {}", settings.dir.display());
+
+        Ok(Archive {
+            sender: Mutex::new(Some(sender)),
+            handle: Some(handle),
+        })
+    }
+
+    /// Queues captured *samples*, captured just now. Never blocks.
+    pub fn write(&self, samples: &[f32]) {
+        let mut sender = self.sender.lock().unwrap();
+        let result = match sender.as_ref() {
+            Some(sender) => sender.try_send((SystemTime::now(), samples.to_vec())),
+            None => return,
+        };
+        match result {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => println!("Archive can't keep up, dropping audio."),
+            // Writer thread failed, it already said why.
+            Err(TrySendError::Disconnected(_)) => *sender = None,
+        }
+    }
+}
+
+impl Drop for Archive {
+    fn drop(&mut self) {
+        // Closing the channel lets the writer finalize the current segment
+        *self.sender.lock().unwrap() = None;
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+/// The writer thread's side.
+struct Segmenter {
+    settings: ArchiveSettings,
+    sample_rate: u32,
+    segment_length: u64,
+    writer: Option<hound::WavWriter<BufWriter<File>>>,
+    segment_name: String,
+    segment_written: u64,
+    since_flush: u64,
+    // When the next sample is due if capture is continuous
+    next_time: Option<SystemTime>,
+}
+
+impl Segmenter {
+
+    fn run(&mut self, receiver: Receiver<Block>) -> Result<(), hound::Error> {
+        // Ends when the Archive is dropped
+        for (time, block) in receiver {
+            self.write_block(time, &block)?;
+        }
+        self.close_segment()
+    }
+
+    fn write_block(&mut self, received: SystemTime, mut samples: &[f32]) -> Result<(), hound::Error> {
+        // The block was captured just before it was received
+        let block_duration = Duration::from_secs_f64(samples.len() as f64 / self.sample_rate as f64);
+        let mut time = received.checked_sub(block_duration).unwrap_or(received);
+
+        // Carry on from the previous block unless capture stopped in between
+        let mut resumed = true;
+        if let Some(expected) = self.next_time {
+            let late = time.duration_since(expected).unwrap_or(Duration::from_secs(0));
+            if late < MAX_DRIFT {
+                time = expected;
+                resumed = false;
+            }
+        }
+
+        while !samples.is_empty() {
+            if self.writer.is_none() {
+                self.open_segment(time)?;
+            } else if resumed {
+                let name = self.segment_name.clone();
+                self.write_index(time, &name, self.segment_written)?;
+            }
+            resumed = false;
+
+            // Split exactly at the segment boundary
+            let room = (self.segment_length - self.segment_written) as usize;
+            let len = std::cmp::min(room, samples.len());
+            let writer = self.writer.as_mut().unwrap();
+            for &sample in &samples[..len] {
+                writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+            }
+
+            self.segment_written += len as u64;
+            self.since_flush += len as u64;
+            if self.since_flush >= FLUSH_EVERY_SAMPLES {
+                self.since_flush = 0;
+                writer.flush()?;
+            }
+
+            samples = &samples[len..];
+            time += Duration::from_secs_f64(len as f64 / self.sample_rate as f64);
+
+            if self.segment_written == self.segment_length {
+                self.close_segment()?;
+            }
+        }
+
+        self.next_time = Some(time);
+        Ok(())
+    }
+
+    fn open_segment(&mut self, start: SystemTime) -> Result<(), hound::Error> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let name = format!("archive_{:013}.wav", unix_ms(start));
+        self.writer = Some(hound::WavWriter::create(self.settings.dir.join(&name), spec)?);
+        self.write_index(start, &name, 0)?;
+        self.segment_name = name;
+        self.segment_written = 0;
+        self.since_flush = 0;
+        Ok(())
+    }
+
+    fn close_segment(&mut self) -> Result<(), hound::Error> {
+        if let Some(writer) = self.writer.take() {
+            writer.finalize()?;
+            // Not being able to clean up is no reason to stop archiving
+            if let Err(e) = self.apply_retention() {
+                println!("Archive: retention failed: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_index(&self, time: SystemTime, name: &str, offset: u64) -> io::Result<()> {
+        let mut index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.settings.dir.join(INDEX_FILE))?;
+        writeln!(index, "{} {} {}", unix_ms(time), name, offset)
+    }
+
+    /// Deletes segments that are too old or too many, and their index entries.
+    fn apply_retention(&self) -> io::Result<()> {
+        let dir = &self.settings.dir;
+        // File names sort by start time
+        let mut segments: Vec<(PathBuf, u64)> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| is_segment(&entry.path()))
+            .map(|entry| (entry.path(), entry.metadata().map(|m| m.len()).unwrap_or(0)))
+            .collect();
+        segments.sort();
+
+        let mut total: u64 = segments.iter().map(|(_, size)| size).sum();
+        let now = SystemTime::now();
+        let mut deleted = 0;
+
+        for (path, size) in &segments {
+            let too_old = self.settings.max_age_secs > 0 && segment_start(path)
+                .map(|start| now.duration_since(start).unwrap_or(Duration::from_secs(0))
+                    > Duration::from_secs(self.settings.max_age_secs))
+                .unwrap_or(false);
+            let too_big = self.settings.max_bytes > 0 && total > self.settings.max_bytes;
+            if !too_old && !too_big {
+                break;
+            }
+            match fs::remove_file(path) {
+                Ok(()) => {
+                    println!("Archive: deleted {}", path.display());
+                    total -= size;
+                    deleted += 1;
+                }
+                Err(e) => println!("Archive: couldn't delete {}: {}", path.display(), e),
+            }
+        }
+
+        if deleted > 0 {
+            // Forget about the deleted files in the index
+            let index_path = dir.join(INDEX_FILE);
+            let index = fs::read_to_string(&index_path).unwrap_or_default();
+            let kept: String = index.lines()
+                .filter(|line| line.split(' ').nth(1).map(|name| dir.join(name).exists()).unwrap_or(false))
+                .map(|line| format!("{}\n", line))
+                .collect();
+            fs::write(&index_path, kept)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn is_segment(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with("archive_") && name.ends_with(".wav"))
+        .unwrap_or(false)
+}
+
+/// Start time of a segment, from its file name.
+fn segment_start(path: &Path) -> Option<SystemTime> {
+    let stem = path.file_stem()?.to_str()?;
+    let ms: u64 = stem.trim_start_matches("archive_").parse().ok()?;
+    Some(UNIX_EPOCH + Duration::from_millis(ms))
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|t| t.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -12,6 +12,8 @@ Use read() and write() to interact with the buffer.
 read_ptr and write_ptr are indexes for reading and writing and should not
 be accessed directly.
 **/
+// Unused, the client and server have ringbuf
+#[allow(dead_code)]
 pub struct AudioBuffer {
     buffer_size: usize,
     buffer: Box::<[f32; 5000]>,
@@ -19,6 +21,7 @@ pub struct AudioBuffer {
     write_ptr: usize
 }
 
+#[allow(dead_code)]
 impl AudioBuffer {
 
     pub fn new(size: usize) -> AudioBuffer {
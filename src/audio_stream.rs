@@ -1,16 +1,16 @@
 //! A demonstration of constructing and using a non-blocking stream.
 //!
 //! Audio from the default input device is passed directly to the default output device in the
-//! stream, so beware of feedback!
+//! stream, so beware of feedback! When streaming in -> out, the input goes through an echo
+//! canceller (using what was played as reference) and a howl detector.
 
 extern crate portaudio;
 use portaudio as pa;
 
-use ringbuf;
+use crate::echo::{EchoCanceller, HowlDetector};
 const RINGBUFFER_SIZE:usize = 5000;
 
 const SAMPLE_RATE: f64 = 44_100.0;
-const FRAMES: u32 = 256;
 const CHANNELS: i32 = 1;
 const INTERLEAVED: bool = true;
 const INPUT_FRAMES_PER_BUFFER: u32 = 256;
@@ -19,6 +19,8 @@ const OUTPUT_FRAMES_PER_BUFFER: u32 = 256;
 struct AudioStream {
     is_input:bool,
     is_output:bool,
+    // Keeps PortAudio up while the streams are open
+    #[allow(dead_code)]
     pa:pa::PortAudio,
     duration:f64,
     input_stream:Option<pa::Stream<pa::stream::NonBlocking, pa::stream::Input<f32>>>,
@@ -36,6 +38,10 @@ impl AudioStream {
         let pa =  pa::PortAudio::new()?;
         let (mut rb_producer, mut rb_consumer) = ringbuf::RingBuffer::<f32>::new(RINGBUFFER_SIZE).split();
 
+        // What the output played, fed back to the input as the echo canceller's reference.
+        let (mut ref_producer, mut ref_consumer) = ringbuf::RingBuffer::<f32>::new(RINGBUFFER_SIZE).split();
+        let cancel_echo = is_input && is_output;
+
         let mut input_stream = None;
         let mut output_stream = None;
 
@@ -55,6 +61,12 @@ impl AudioStream {
             pa.is_input_format_supported(input_params, SAMPLE_RATE)?;
             let input_settings = pa::InputStreamSettings::new(input_params, SAMPLE_RATE, INPUT_FRAMES_PER_BUFFER);
 
+            // Echo cancelling and feedback suppression, with buffers allocated up front
+            let mut echo_canceller = EchoCanceller::new();
+            let mut howl_detector = HowlDetector::new();
+            let mut mic = vec![0.0; INPUT_FRAMES_PER_BUFFER as usize];
+            let mut reference = vec![0.0; INPUT_FRAMES_PER_BUFFER as usize];
+
             // Define callback -> send input stream into ringbuffer
             let input_stream_callback = move |pa::InputStreamCallbackArgs {
                                                   buffer,
@@ -69,8 +81,22 @@ impl AudioStream {
                     std::thread::sleep(std::time::Duration::from_millis(1));
                 }
 
+                let mic = &mut mic[..frames];
+                mic.copy_from_slice(buffer);
+
+                if cancel_echo {
+                    // Nothing played yet counts as silence
+                    let reference = &mut reference[..frames];
+                    let len = ref_consumer.pop_slice(reference);
+                    for sample in reference[len..].iter_mut() {
+                        *sample = 0.0;
+                    }
+                    echo_canceller.process(mic, reference);
+                }
+                howl_detector.process(mic);
+
                 // Push audio to the RingBuffer
-                rb_producer.push_slice(buffer);
+                rb_producer.push_slice(mic);
 
                 if duration > 0.0 {
                     println!("Input: {} frames", rb_producer.len());
@@ -100,24 +126,19 @@ impl AudioStream {
                 // Copy buffer_from_stream to audio_buffer
                 assert_eq!(buffer.len(), frames);
                 let len = rb_consumer.pop_slice(&mut buffer[..frames]);
+                for sample in buffer[len..frames].iter_mut() {
+                    *sample = 0.0;
+                }
+
+                // Keep a copy of what is played for the echo canceller
+                if cancel_echo {
+                    ref_producer.push_slice(&buffer[..frames]);
+                }
 
                 if len > 0 {
                     println!("Output: {} frames", rb_consumer.len());
-                    pa::Continue
-                } else {
-                    for time_out in 10..0 {
-                        std::thread::sleep(std::time::Duration::from_millis(1));
-                        if !rb_consumer.is_empty() {
-                            break;
-                        }
-                        if time_out == 0 {
-                            // Timed out
-                            println!("Done playing.");
-                            return pa::Complete
-                        }
-                    }
-                    pa::Continue
                 }
+                pa::Continue
             };
 
             // Construct output audio stream
@@ -137,14 +158,14 @@ impl AudioStream {
         })
     }
 
-    pub fn stream(&mut self) {
+    pub fn stream(&mut self) -> Result<(), pa::error::Error> {
         if self.is_input {
             assert!(self.input_stream.is_some());
-            self.input_stream.as_mut().unwrap().start();
+            self.input_stream.as_mut().unwrap().start()?;
         }
         if self.is_output {
             assert!(self.output_stream.is_some());
-            self.output_stream.as_mut().unwrap().start();
+            self.output_stream.as_mut().unwrap().start()?;
         }
 
         // Loop while the non-blocking stream is active.
@@ -158,11 +179,12 @@ impl AudioStream {
         }
 
         if self.is_input {
-            self.input_stream.as_mut().unwrap().stop();
+            self.input_stream.as_mut().unwrap().stop()?;
         }
         if self.is_output {
-            self.output_stream.as_mut().unwrap().stop();
+            self.output_stream.as_mut().unwrap().stop()?;
         }
+        Ok(())
     }
 
     /// This doesn't actually work, the init. function sets the data before.
@@ -179,7 +201,7 @@ pub fn audio_stream_test(duration:f64) -> Result<(), pa::error::Error> {
     let mut stream_test = AudioStream::new(true, true)?;
 
     stream_test.set_duration(duration); //doesn't work, see above.
-    stream_test.stream();
+    stream_test.stream()?;
 
     println!("test");
     std::thread::sleep(std::time::Duration::from_secs(1));
@@ -191,7 +213,8 @@ pub fn audio_stream_test(duration:f64) -> Result<(), pa::error::Error> {
     Ok(())
 }
 
-pub fn audio_stream(mut duration:f64) -> Result<(), Box::<std::error::Error>>{
+#[allow(dead_code)]
+pub fn audio_stream(mut duration:f64) -> Result<(), Box::<dyn std::error::Error>>{
 
     //===============================================
     // Create input audio stream
@@ -223,7 +246,6 @@ pub fn audio_stream(mut duration:f64) -> Result<(), Box::<std::error::Error>>{
     let input_stream_callback = move |pa::InputStreamCallbackArgs {
                              buffer,
                              frames,
-                             time,
                              ..
                          }| {
         duration -= frames as f64 / SAMPLE_RATE;
@@ -269,40 +291,27 @@ pub fn audio_stream(mut duration:f64) -> Result<(), Box::<std::error::Error>>{
 
         if len > 0 {
             println!("Output: {} frames", consumer.len());
-            pa::Continue
-        } else {
-            for time_out in 500..0 {
-                std::thread::sleep(std::time::Duration::from_millis(1));
-                if !consumer.is_empty() {
-                    break;
-                }
-                if time_out == 0 {
-                    // Timed out
-                    println!("Done playing.");
-                    return pa::Complete
-                }
-            }
-            pa::Continue
         }
+        pa::Continue
     };
 
     // Construct the audio stream
     let mut input_stream = pa.open_non_blocking_stream(input_settings, input_stream_callback)?;
     let mut output_stream = pa.open_non_blocking_stream(output_settings, output_stream_callback)?;
 
-    input_stream.start();
-    output_stream.start();
+    input_stream.start()?;
+    output_stream.start()?;
 
     // Loop while the non-blocking stream is active.
-    while let true = input_stream.is_active()? {
+    while input_stream.is_active()? {
         // Write countdown message from msg channel.
         while let Ok(count_down) = receiver.try_recv() {
             println!("count_down: {:?}", count_down);
         }
     }
 
-    input_stream.stop();
-    output_stream.stop();
+    input_stream.stop()?;
+    output_stream.stop()?;
 
     Ok(())
 }
@@ -0,0 +1,346 @@
+//! Pre-shared-key authentication, and which modes each identity may use.
+//!
+//! With `auth_enabled = true`, clients prove they know their key before the handshake line:
+//!
+//! ```text
+//! client: auth alice
+//! server: challenge <64 hex digits, random>
+//! client: response <hex HMAC-SHA256 of the challenge line's hex digits, keyed with the key>
+//! server: ok                  (or "denied", and the connection is closed)
+//! client: stream mic 10s ...
+//! ```
+//!
+//! The keys never cross the network. Identities, keys and allowed modes come from the users file
+//! (`auth_users`, "users.txt" by default), one per line, "*" allowing every mode:
+//!
+//! ```text
+//! # identity  key          modes
+//! alice       correct-horse  mic,sin,file
+//! bob         battery-staple *
+//! ```
+//!
+//! Over WebSocket the same lines are text messages. Plain HTTP streams can't go through the
+//! challenge, so they carry a token instead (`?user=alice&token=<hex>`, see http.rs): the hex
+//! HMAC-SHA256 of "stream" keyed with the key (`response(key, "stream")`), for instance from
+//! `printf stream | openssl dgst -sha256 -hmac correct-horse`. It is good for nothing else, but
+//! anyone who sees it can use it until the key changes.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::handshake;
+
+const CHALLENGE_BYTES: usize = 32;
+
+// What stream tokens are signed over
+const STREAM_TOKEN_INFO: &[u8] = b"stream";
+
+#[derive(Debug, Clone)]
+pub struct AuthSettings {
+    pub enabled: bool,
+    pub users: PathBuf,
+}
+
+impl Default for AuthSettings {
+    fn default() -> AuthSettings {
+        AuthSettings {
+            enabled: false,
+            users: PathBuf::from("users.txt"),
+        }
+    }
+}
+
+/// What a client logs in with.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    pub identity: String,
+    pub key: String,
+}
+
+pub struct User {
+    pub identity: String,
+    key: hmac::Key,
+    modes: Vec<String>,
+}
+
+impl User {
+
+    /// The pre-shared key, also vouching for the server's encryption key (see e2e.rs).
+    pub fn psk(&self) -> &hmac::Key {
+        &self.key
+    }
+
+    /// Whether this user may stream *mode*.
+    pub fn allows(&self, mode: &str) -> bool {
+        // Same thing, two names
+        let mode = if mode == "gen" { "sin" } else { mode };
+        self.modes.iter().any(|allowed| allowed == "*" || allowed == mode)
+    }
+}
+
+pub struct Users {
+    users: Vec<User>,
+}
+
+impl Users {
+
+    pub fn load(path: &PathBuf) -> Result<Users, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        let mut users = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.len() != 3 {
+                return Err(format!("{}:{}: expected 'identity key modes'", path.display(), number + 1));
+            }
+            users.push(User {
+                identity: words[0].to_string(),
+                key: psk(words[1]),
+                modes: words[2].split(',').map(|mode| mode.to_string()).collect(),
+            });
+        }
+
+        println!("{} users in {}", users.len(), path.display());
+        Ok(Users { users })
+    }
+
+    /// Server side of the exchange with *peer*, *first_line* being the "auth <identity>" line
+    /// already read. Returns the user, or who was turned away and why, for the log (they are
+    /// told "denied").
+    pub fn authenticate(
+        &self,
+        peer: &str,
+        first_line: &str,
+        read_line: &mut dyn FnMut() -> io::Result<String>,
+        write_line: &mut dyn FnMut(&str) -> io::Result<()>,
+    ) -> Result<&User, String> {
+        self.exchange(first_line, read_line, write_line)
+            .map_err(|e| format!("{}: {}", peer, e))
+    }
+
+    fn exchange(
+        &self,
+        first_line: &str,
+        read_line: &mut dyn FnMut() -> io::Result<String>,
+        write_line: &mut dyn FnMut(&str) -> io::Result<()>,
+    ) -> Result<&User, String> {
+        let identity = match first_line.strip_prefix("auth ") {
+            Some(identity) => identity.trim(),
+            None => {
+                write_line("denied").ok();
+                return Err("no authentication".to_string());
+            }
+        };
+
+        // Always go through with the challenge, so unknown identities look like wrong keys
+        let challenge = challenge();
+        write_line(&format!("challenge {}", challenge)).map_err(|e| e.to_string())?;
+        let line = read_line().map_err(|e| e.to_string())?;
+        let response = line.strip_prefix("response ").and_then(|hex| from_hex(hex.trim()));
+
+        let user = self.users.iter().find(|user| user.identity == identity);
+        match (user, response) {
+            (Some(user), Some(response))
+                if hmac::verify(&user.key, challenge.as_bytes(), &response).is_ok() => {
+                write_line("ok").map_err(|e| e.to_string())?;
+                Ok(user)
+            }
+            _ => {
+                write_line("denied").ok();
+                Err(format!("bad credentials for '{}'", identity))
+            }
+        }
+    }
+
+    /// The user an HTTP stream *token* was made for, or why not.
+    pub fn check_token(&self, identity: &str, token: &str) -> Result<&User, String> {
+        let user = self.users.iter().find(|user| user.identity == identity)
+            .ok_or(format!("unknown user '{}'", identity))?;
+        match from_hex(token) {
+            Some(token) if hmac::verify(&user.key, STREAM_TOKEN_INFO, &token).is_ok() => Ok(user),
+            _ => Err(format!("bad token for '{}'", identity)),
+        }
+    }
+}
+
+/// Client side of the exchange, on a freshly opened connection.
+pub fn login<S: Read + Write>(stream: &mut S, credentials: &Credentials) -> io::Result<()> {
+    stream.write_all(format!("auth {}\n", credentials.identity).as_bytes())?;
+
+    let line = handshake::read_line(stream)?;
+    let challenge = line.strip_prefix("challenge ")
+        .ok_or_else(|| denied(&line))?;
+    stream.write_all(format!("response {}\n", response(&credentials.key, challenge)).as_bytes())?;
+
+    let line = handshake::read_line(stream)?;
+    if line != "ok" {
+        return Err(denied(&line));
+    }
+    Ok(())
+}
+
+/// What a client answers to *challenge*.
+pub fn response(key: &str, challenge: &str) -> String {
+    to_hex(hmac::sign(&psk(key), challenge.as_bytes()).as_ref())
+}
+
+/// The HMAC key made from a pre-shared key as written in the users file.
+pub fn psk(key: &str) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes())
+}
+
+fn challenge() -> String {
+    let mut bytes = [0u8; CHALLENGE_BYTES];
+    SystemRandom::new().fill(&mut bytes).expect("no random numbers");
+    to_hex(&bytes)
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(crate) fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn denied(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("authentication failed: {}", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    const PEER: &str = "192.0.2.7:50000";
+
+    fn users() -> Users {
+        let user = |identity: &str, key: &str, modes: &str| User {
+            identity: identity.to_string(),
+            key: psk(key),
+            modes: modes.split(',').map(|mode| mode.to_string()).collect(),
+        };
+        Users {
+            users: vec![user("alice", "correct-horse", "mic,sin"), user("bob", "battery-staple", "*")],
+        }
+    }
+
+    /// Runs the server side against a client that logs in as *identity*, answering the challenge
+    /// with *answer* of the response made with *key*. Returns who got in (or the error) and what
+    /// the server said.
+    fn login_as(identity: &str, key: &str, answer: fn(&str) -> String)
+        -> (Result<String, String>, Vec<String>) {
+        let users = users();
+        let said = RefCell::new(Vec::new());
+        let result = users.authenticate(
+            PEER,
+            &format!("auth {}", identity),
+            &mut || {
+                let said = said.borrow();
+                let challenge = said.last().and_then(|line: &String| line.strip_prefix("challenge "));
+                Ok(answer(&response(key, challenge.unwrap_or(""))))
+            },
+            &mut |line| {
+                said.borrow_mut().push(line.to_string());
+                Ok(())
+            });
+        (result.map(|user| user.identity.clone()), said.into_inner())
+    }
+
+    fn respond(hex: &str) -> String {
+        format!("response {}", hex)
+    }
+
+    #[test]
+    fn accepts_right_response() {
+        let (result, said) = login_as("alice", "correct-horse", respond);
+        assert_eq!(result, Ok("alice".to_string()));
+        assert_eq!(said.last().map(String::as_str), Some("ok"));
+        // A fresh challenge, 32 random bytes in hex
+        assert_eq!(said[0].len(), "challenge ".len() + CHALLENGE_BYTES * 2);
+    }
+
+    #[test]
+    fn denies_wrong_key() {
+        let (result, said) = login_as("alice", "guess", respond);
+        let e = result.unwrap_err();
+        assert!(e.contains(PEER) && e.contains("alice"), "{}", e);
+        assert_eq!(said.last().map(String::as_str), Some("denied"));
+    }
+
+    #[test]
+    fn denies_unknown_identity_like_a_wrong_key() {
+        let (result, said) = login_as("mallory", "correct-horse", respond);
+        assert!(result.unwrap_err().contains(PEER));
+        // Challenged all the same
+        assert!(said[0].starts_with("challenge "));
+        assert_eq!(said.last().map(String::as_str), Some("denied"));
+    }
+
+    #[test]
+    fn denies_malformed_responses() {
+        // What to send instead, given the right response
+        let answers: [fn(&str) -> String; 6] = [
+            |_| String::new(),
+            |_| "response".to_string(),
+            |_| "response zz".to_string(),
+            |right| format!("answer {}", right),
+            // Odd length, then a byte short
+            |right| format!("response {}", &right[1..]),
+            |right| format!("response {}", &right[2..]),
+        ];
+        for answer in answers {
+            let (result, said) = login_as("alice", "correct-horse", answer);
+            assert!(result.unwrap_err().contains(PEER));
+            assert_eq!(said.last().map(String::as_str), Some("denied"));
+        }
+
+        // Not even the first line
+        let users = users();
+        let mut said = Vec::new();
+        let result = users.authenticate(PEER, "stream mic 10s", &mut || Ok(String::new()),
+                                        &mut |line| { said.push(line.to_string()); Ok(()) });
+        assert!(result.is_err());
+        assert_eq!(said, vec!["denied".to_string()]);
+    }
+
+    #[test]
+    fn allows_listed_modes_only() {
+        let users = users();
+        let alice = &users.users[0];
+        assert!(alice.allows("mic"));
+        assert!(alice.allows("sin"));
+        assert!(alice.allows("gen"));
+        assert!(!alice.allows("file"));
+        assert!(!alice.allows("upload"));
+        let bob = &users.users[1];
+        assert!(bob.allows("file") && bob.allows("upload"));
+    }
+
+    #[test]
+    fn checks_stream_tokens() {
+        let users = users();
+        let token = response("correct-horse", "stream");
+        assert_eq!(users.check_token("alice", &token).unwrap().identity, "alice");
+        assert!(users.check_token("alice", &response("guess", "stream")).is_err());
+        assert!(users.check_token("alice", &response("correct-horse", "other")).is_err());
+        assert!(users.check_token("bob", &token).is_err());
+        assert!(users.check_token("mallory", &token).is_err());
+        assert!(users.check_token("alice", "").is_err());
+    }
+}
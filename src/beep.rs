@@ -6,13 +6,16 @@
 extern crate portaudio;
 
 use portaudio as pa;
-use std::f64::consts::PI;
+use crate::generator::{Generator, GeneratorSettings};
 
 const CHANNELS: i32 = 2;
 const NUM_SECONDS: i32 = 1;
 const SAMPLE_RATE: f64 = 44_100.0;
 const FRAMES_PER_BUFFER: u32 = 64;
-const TABLE_SIZE: usize = 200;
+
+// Left and right frequencies (the right channel is an octave and a fifth up).
+const LEFT_FREQUENCY: f64 = 441.0;
+const RIGHT_FREQUENCY: f64 = 1323.0;
 
 pub(crate) fn beep() {
     match run() {
@@ -29,13 +32,11 @@ fn run() -> Result<(), pa::Error> {
         SAMPLE_RATE, FRAMES_PER_BUFFER
     );
 
-    // Initialise sinusoidal wavetable.
-    let mut sine = [0.0; TABLE_SIZE];
-    for i in 0..TABLE_SIZE {
-        sine[i] = (i as f64 / TABLE_SIZE as f64 * PI * 4.0).sin() as f32;
-    }
-    let mut left_phase = 0;
-    let mut right_phase = 0;
+    // Initialise sine generators.
+    let mut left = Generator::new(
+        GeneratorSettings { frequency: LEFT_FREQUENCY, ..GeneratorSettings::default() }, SAMPLE_RATE);
+    let mut right = Generator::new(
+        GeneratorSettings { frequency: RIGHT_FREQUENCY, ..GeneratorSettings::default() }, SAMPLE_RATE);
 
     let pa = pa::PortAudio::new()?;
 
@@ -50,16 +51,8 @@ fn run() -> Result<(), pa::Error> {
     let callback = move |pa::OutputStreamCallbackArgs { buffer, frames, .. }| {
         let mut idx = 0;
         for _ in 0..frames {
-            buffer[idx] = sine[left_phase];
-            buffer[idx + 1] = sine[right_phase];
-            left_phase += 1;
-            if left_phase >= TABLE_SIZE {
-                left_phase -= TABLE_SIZE;
-            }
-            right_phase += 3;
-            if right_phase >= TABLE_SIZE {
-                right_phase -= TABLE_SIZE;
-            }
+            buffer[idx] = left.next_sample();
+            buffer[idx + 1] = right.next_sample();
             idx += 2;
         }
         pa::Continue
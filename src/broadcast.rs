@@ -0,0 +1,53 @@
+//! One source, many listeners.
+//!
+//! Each listener gets its own bounded queue of packets. Sending never blocks: a listener that
+//! can't keep up loses packets rather than holding everyone else back, and listeners that went
+//! away are forgotten on the next send.
+
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+
+use crate::packet::Packet;
+
+// ~2.5s of audio in 250 sample packets.
+const LISTENER_QUEUE: usize = 440;
+
+pub struct Broadcast {
+    listeners: Mutex<Vec<SyncSender<Packet>>>,
+}
+
+impl Broadcast {
+
+    pub fn new() -> Broadcast {
+        Broadcast {
+            listeners: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn subscribe(&self) -> Receiver<Packet> {
+        let (sender, receiver) = sync_channel(LISTENER_QUEUE);
+        self.listeners.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Same as subscribe(), with *first* queued before anything else.
+    pub fn subscribe_with(&self, first: Packet) -> Receiver<Packet> {
+        let (sender, receiver) = sync_channel(LISTENER_QUEUE);
+        sender.try_send(first).ok();
+        self.listeners.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn send(&self, packet: &Packet) {
+        self.listeners.lock().unwrap().retain(|listener| {
+            match listener.try_send(packet.clone()) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    pub fn listener_count(&self) -> usize {
+        self.listeners.lock().unwrap().len()
+    }
+}
@@ -1,87 +1,364 @@
-use std::net::{TcpStream, TcpListener};
-use std::io::{Read, Write};
-use byte_strings::concat_bytes;
+use std::io::Write;
 
 extern crate portaudio;
 use portaudio as pa;
 
-use ringbuf;
-use crate::server::f32_to_u8;
+use crate::control::Control;
+use crate::packet::Packet;
+use crate::handshake::{self, Handshake};
+use crate::vad::ComfortNoise;
+use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::PathBuf;
+use crate::recorder::Recorder;
+use crate::wire::Wire;
+use crate::transport::{Address, Connection};
+use crate::tls::{self, TlsConnector};
+use crate::auth::{self, Credentials};
+use crate::e2e::{GroupKey, KeyExchange, Opener};
+use crate::mixer;
+use crate::echo::{EchoCanceller, DEFAULT_ECHO_FILTER_LENGTH};
+use ring::hmac;
 
 const RINGBUFFER_SIZE:usize = 5000;
 
 const SAMPLE_RATE: f64 = 44_100.0;
-const FRAMES: u32 = 256;
 const CHANNELS: i32 = 1;
-const INTERLEAVED: bool = true;
 const OUTPUT_FRAMES_PER_BUFFER: u32 = 256;
+const INPUT_FRAMES_PER_BUFFER: u32 = 256;
 
-pub(crate) fn run_client(mic_mode:bool, duration:i32) -> Result<(), Box::<dyn std::error::Error>> {
-    TcpStream::connect("localhost:3333")?;
+// Samples per audio packet when uploading, as the server sends them
+const UPLOAD_BLOCK_LENGTH: usize = 250;
 
-    let mut tcp_stream = result.unwrap();
-    println!("Successfully connected to server in port 3333.");
+/// How the client connects, and what it does with the audio it receives.
+pub struct ClientOptions {
+    /// Connect with TLS, see tls.rs.
+    pub tls: Option<tls::ClientSettings>,
+    /// Log in with these, for servers that require it (see auth.rs).
+    pub credentials: Option<Credentials>,
+    /// Have the server encrypt everything for us alone (see e2e.rs).
+    pub e2e: bool,
+    /// Or for everyone with this group key, through relays too.
+    pub group_key: Option<String>,
+    /// Save the stream to this WAV file as well.
+    pub record_path: Option<PathBuf>,
+    /// Play the stream through the speakers. Without it, nothing PortAudio is opened.
+    pub play: bool,
+    /// In samples, how late an echo the duplex mode can take out of the mic (see echo.rs).
+    pub echo_filter_length: usize,
+}
 
-    let mode = if mic_mode { "mic" } else { "sin" };
-    let msg = format!("stream {} {:02}s", mode, duration);
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions {
+            tls: None,
+            credentials: None,
+            e2e: false,
+            group_key: None,
+            record_path: None,
+            play: true,
+            echo_filter_length: DEFAULT_ECHO_FILTER_LENGTH,
+        }
+    }
+}
 
-    println!("Sending message: {}", msg);
-    tcp_stream.write_all(msg.as_bytes());
+pub(crate) fn run_client(address: &Address, mut handshake: Handshake, options: ClientOptions)
+    -> Result<(), Box::<dyn std::error::Error>> {
+    let wire = Wire::from_handshake(&handshake)?;
+    if (options.e2e || options.group_key.is_some())
+        && (handshake.mode == "upload" || handshake.mode == "duplex") {
+        return Err("uploads can't be encrypted end to end".into());
+    }
+    let mut tcp_stream = connect(address, options.tls.as_ref(), options.credentials.as_ref())?;
 
-    let mut dummy = [0;10];
-    loop {
-        // Wait to catch signal.
-        if tcp_stream.peek(&mut dummy).is_ok() {
-            break;
+    // Our half of the key exchange goes in the handshake
+    let stream_key = match (&options.group_key, &options.credentials) {
+        (Some(group_key), _) => {
+            handshake = handshake.with("e2e", "group");
+            Some(StreamKey::Group(GroupKey::new(group_key)))
+        }
+        (None, Some(credentials)) if options.e2e => {
+            let key_exchange = KeyExchange::new()?;
+            handshake = handshake.with("e2e", &auth::to_hex(key_exchange.public_key()));
+            Some(StreamKey::Exchange(key_exchange, auth::psk(&credentials.key)))
+        }
+        (None, None) if options.e2e => {
+            return Err("end-to-end encryption needs user= and psk=, to check the server's key".into());
+        }
+        _ => None,
+    };
+
+    let msg = handshake.to_line();
+
+    println!("Sending message: {}", msg.trim_end());
+    tcp_stream.write_all(msg.as_bytes())?;
+
+    // Not a stream: the server lists its rooms, one line each
+    if handshake.mode == "rooms" {
+        while let Ok(line) = handshake::read_line(&mut tcp_stream) {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+
+    // Uploads go the other way: nothing to play, and no control messages
+    if handshake.mode == "upload" {
+        return upload_mic(tcp_stream, handshake.duration as f64, None, options.echo_filter_length);
+    }
+
+    // What we play, for the mic's echo canceller
+    let mut echo_reference = None;
+    if handshake.mode == "duplex" {
+        // Talking in a room: the mic goes up while we play the room without us. The mic picks
+        // that up again, so it goes through an echo canceller first.
+        let (reference_producer, reference_consumer)
+            = ringbuf::RingBuffer::<f32>::new(RINGBUFFER_SIZE).split();
+        echo_reference = Some(reference_producer);
+        let upload_stream = tcp_stream.try_clone()?;
+        let duration = handshake.duration as f64;
+        let echo_filter_length = options.echo_filter_length;
+        std::thread::spawn(move || {
+            if let Err(e) = upload_mic(upload_stream, duration, Some(reference_consumer), echo_filter_length) {
+                println!("Upload failed: {}", e);
+            }
+        });
+    } else {
+        // Forward control messages typed on stdin to the server
+        spawn_stdin_control(tcp_stream.try_clone()?);
+    }
+
+    // Begin audio stream, reading blocks until the server answers
+    stream_audio(tcp_stream, wire, handshake.duration as i32, options, stream_key, echo_reference)?;
+
+    Ok(())
+}
+
+/// Connects to the server at *address*, with TLS and logging in if asked to, ready for the
+/// handshake. The relay connects to its upstream server this way too.
+pub(crate) fn connect(address: &Address, tls: Option<&tls::ClientSettings>, credentials: Option<&Credentials>)
+    -> Result<Connection, Box<dyn std::error::Error>> {
+    let mut tcp_stream = Connection::connect(address)?;
+    if let Some(settings) = tls {
+        tcp_stream = TlsConnector::new(settings)?.connect(tcp_stream, address.host())?;
+    }
+    println!("Successfully connected to server at {}{}.", address,
+             if tls.is_some() { " (TLS)" } else { "" });
+
+    if let Some(credentials) = credentials {
+        auth::login(&mut tcp_stream, credentials)?;
+        println!("Logged in as {}.", credentials.identity);
+    }
+    Ok(tcp_stream)
+}
+
+/// Sends a single control message to the server, e.g. to change the gain mid-stream.
+pub fn send_control(tcp_stream: &mut Connection, control: &Control) -> std::io::Result<()> {
+    tcp_stream.write_all(control.to_line().as_bytes())
+}
+
+/// Reads control messages from stdin ("gain -6", "mute on", "limiter off"...) and sends
+/// them to the server.
+fn spawn_stdin_control(mut tcp_stream: Connection) {
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        let mut line = String::new();
+        while let Ok(n) = stdin.read_line(&mut line) {
+            if n == 0 {
+                break;
+            }
+            match Control::parse(&line) {
+                Some(control) => {
+                    if send_control(&mut tcp_stream, &control).is_err() {
+                        break;
+                    }
+                }
+                None => println!("Unknown control message: {}", line.trim()),
+            }
+            line.clear();
+        }
+    });
+}
+
+/// What the server's 'K' packet is opened with (see e2e.rs).
+enum StreamKey {
+    /// Our half of the key exchange, and the pre-shared key the server's half must be signed with.
+    Exchange(KeyExchange, hmac::Key),
+    Group(GroupKey),
+}
+
+/// Decrypts *packet*, nothing being accepted in clear but the server's 'K' packet, for which
+/// there is nothing to return. A key exchange happens once, a group stream starts again with a
+/// new 'K' packet whenever the relay in between reconnects.
+fn unseal(packet: Packet, stream_key: &mut Option<StreamKey>, opener: &mut Option<Opener>)
+    -> std::io::Result<Option<Packet>> {
+    match (packet, opener.as_mut()) {
+        (Packet::Sealed { seq, data }, Some(opener)) => opener.open(seq, &data).map(Some),
+        (Packet::Key(key), _) => {
+            let new_opener = match stream_key.take() {
+                Some(StreamKey::Exchange(key_exchange, psk)) => key_exchange.open_from(&key, &psk)?,
+                Some(StreamKey::Group(group)) => {
+                    let result = group.open_from(&key);
+                    *stream_key = Some(StreamKey::Group(group));
+                    result?
+                }
+                None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "second key exchange")),
+            };
+            if opener.is_none() {
+                println!("Stream is encrypted end to end.");
+            }
+            *opener = Some(new_opener);
+            Ok(None)
         }
+        _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "packet not encrypted")),
     }
-    // Begin audio stream
-    stream_audio(tcp_stream, duration);
 }
 
-/// On connection with TCP Stream: this creates a PortAudio instance
+/// On connection with the server: this creates a PortAudio instance
 /// and streams the TCP data through to it using a ringbuffer.
-fn stream_audio (mut tcp_stream: TcpStream, duration:i32) -> Result<(), pa::Error> {
+/// Depending on *options*, the stream is also (or only) recorded to a WAV file.
+/// With *stream_key*, the server must encrypt everything. Whatever is played also goes to
+/// *echo_reference*, if given.
+fn stream_audio (mut tcp_stream: Connection, mut wire: Wire, duration:i32, options: ClientOptions,
+                 stream_key: Option<StreamKey>, mut echo_reference: Option<ringbuf::Producer<f32>>)
+    -> Result<(), Box<dyn std::error::Error>> {
+    wire.read_header(&mut tcp_stream)?;
 
-    // Allocate TCP buffer
-    let mut tcp_buffer = [0 as u8; 300];
+    // The recording is in the same format as what comes over the wire (mono f32, 44.1K)
+    let mut recorder = match options.record_path {
+        Some(ref path) => Some(Recorder::start(path, SAMPLE_RATE as u32)?),
+        None => None,
+    };
+    let play = options.play;
 
     // Allocate audio ringbuffer
-    const AUDIO_BUFFER_LENGTH:usize = 50000;
     let (mut rb_producer, mut rb_consumer)
         = ringbuf::RingBuffer::<f32>::new(RINGBUFFER_SIZE).split();
 
+    // Voice activity events from the server
+    let (vad_sender, vad_receiver) = ::std::sync::mpsc::channel();
+
+    // Set once the server is done, the output stops when it has played everything
+    let finished = Arc::new(AtomicBool::new(false));
+    let tcp_finished = finished.clone();
+
+    // Set to have the output drop what is in the ringbuffer, it clears it once done
+    let flush = Arc::new(AtomicBool::new(false));
+    let tcp_flush = flush.clone();
+
     // Run TCP Listener
     let tcp_listener_handle = std::thread::spawn(move || {
-        let mut time_out = false;
-        while !time_out {
-            if tcp_stream.read_exact(&mut tcp_buffer).is_ok() {
-                // Fill audio buffer with floats
-                rb_producer.push_slice(u8_to_f32(&mut tcp_buffer));
-            } else {
-                // Timeout check - 50ms waiting time
-                for t in 50..0
-                {
-                    // Wait 1ms before check
-                    std::thread::sleep(std::time::Duration::from_millis(1));
-
-                    // Use peek to determine if any data has come through
-                    let peek = tcp_stream.peek(&mut tcp_buffer); //inefficient
-                    if peek.is_ok() && peek.unwrap() > 0 as usize { //todo err means peek @ nothing?
+        let mut comfort_noise = ComfortNoise::new();
+        let mut noise = [0.0f32; OUTPUT_FRAMES_PER_BUFFER as usize];
+
+        let encrypted = stream_key.is_some();
+        let mut stream_key = stream_key;
+        let mut opener = None;
+        let mut dropped: u64 = 0;
+
+        loop {
+            let packet = match wire.read_packet(&mut tcp_stream) {
+                Ok(packet) if encrypted => match unseal(packet, &mut stream_key, &mut opener) {
+                    Ok(Some(packet)) => Ok(packet),
+                    // The key exchange
+                    Ok(None) => continue,
+                    // Nothing can be trusted without the server's key
+                    Err(e) if opener.is_none() => {
+                        println!("Encryption failed: {}", e);
                         break;
-                    } else if t == 0 {
-                        // Timed out
-                        println!("Timed out TCP stream.");
-                        // todo return error
-                        time_out = true;
+                    }
+                    Err(e) => {
+                        if dropped == 0 {
+                            println!("Dropping packets: {}", e);
+                        }
+                        dropped += 1;
+                        continue;
+                    }
+                },
+                other => other,
+            };
+            match packet {
+                Ok(Packet::Audio(samples)) => {
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder.write(&samples);
+                    }
+                    // Fill audio buffer with floats
+                    if play {
+                        push_all(&mut rb_producer, &samples);
                     }
                 }
-                println!("Finished receiving TCP stream.");
-                break;
+                Ok(Packet::Stereo(samples)) => {
+                    // The mix, we play and record mono
+                    let samples = mixer::downmix(&samples);
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder.write(&samples);
+                    }
+                    if play {
+                        push_all(&mut rb_producer, &samples);
+                    }
+                }
+                Ok(Packet::Silence { frames, level }) => {
+                    // Server suppressed silence, play comfort noise instead
+                    let mut frames_left = frames as usize;
+                    while frames_left > 0 {
+                        let len = std::cmp::min(frames_left, noise.len());
+                        comfort_noise.fill(&mut noise[..len], level);
+                        if let Some(recorder) = recorder.as_mut() {
+                            recorder.write(&noise[..len]);
+                        }
+                        if play {
+                            push_all(&mut rb_producer, &noise[..len]);
+                        }
+                        frames_left -= len;
+                    }
+                }
+                Ok(Packet::Vad(speech)) => {
+                    vad_sender.send(speech).ok();
+                }
+                Ok(Packet::Track(title)) => {
+                    println!("Now playing: {}", title);
+                }
+                Ok(Packet::Flush) => {
+                    // Wait for the output to empty the ringbuffer before pushing anything new
+                    if play {
+                        tcp_flush.store(true, Ordering::SeqCst);
+                        for _ in 0..1000 {
+                            if !tcp_flush.load(Ordering::SeqCst) {
+                                break;
+                            }
+                            std::thread::sleep(Duration::from_millis(1));
+                        }
+                    }
+                }
+                Ok(Packet::End) => break,
+                Ok(Packet::Key(_)) | Ok(Packet::Sealed { .. }) => {
+                    println!("Encrypted packet, but we didn't ask for encryption.");
+                }
+                Err(_) => break,
             }
         }
+        if dropped > 0 {
+            println!("Dropped {} packets that weren't encrypted or didn't decrypt.", dropped);
+        }
+        // Finalize the recording before saying we're done
+        drop(recorder);
+        tcp_finished.store(true, Ordering::SeqCst);
+        println!("Finished receiving TCP stream.");
     });
 
+    if !play {
+        // Record only: no output, just wait for the server to be done.
+        println!("Recording only, not playing.");
+        while !finished.load(Ordering::SeqCst) {
+            while let Ok(speech) = vad_receiver.try_recv() {
+                println!("VAD: {}", if speech { "talking" } else { "silent" });
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        tcp_listener_handle.join().ok();
+        return Ok(());
+    }
+
     println!("Creating audio stream on client side..");
 
     // Create Portaudio object
@@ -92,6 +369,7 @@ fn stream_audio (mut tcp_stream: TcpStream, duration:i32) -> Result<(), pa::Erro
         <f32>(CHANNELS, SAMPLE_RATE, OUTPUT_FRAMES_PER_BUFFER)?;
 
     // Define Output callback -> send ringbuffer into output stream
+    let output_finished = finished.clone();
     let output_stream_callback = move |pa::OutputStreamCallbackArgs {
                                            buffer,
                                            frames,
@@ -99,22 +377,26 @@ fn stream_audio (mut tcp_stream: TcpStream, duration:i32) -> Result<(), pa::Erro
                                        }| {
         // Copy buffer_from_stream to audio_buffer
         assert_eq!(buffer.len(), frames);
+        if flush.load(Ordering::SeqCst) {
+            let stale = rb_consumer.len();
+            rb_consumer.discard(stale);
+            flush.store(false, Ordering::SeqCst);
+        }
         let len = rb_consumer.pop_slice(&mut buffer[..frames]);
 
-        if len > 0 {
-            pa::Continue
+        for sample in buffer[len..frames].iter_mut() {
+            *sample = 0.0;
+        }
+
+        // Keep a copy of what is played for the echo canceller
+        if let Some(reference) = echo_reference.as_mut() {
+            reference.push_slice(&buffer[..frames]);
+        }
+
+        if len == 0 && output_finished.load(Ordering::SeqCst) {
+            println!("Done playing.");
+            pa::Complete
         } else {
-            for time_out in 10..0 {
-                std::thread::sleep(std::time::Duration::from_millis(1));
-                if !rb_consumer.is_empty() {
-                    break;
-                }
-                if time_out == 0 {
-                    // Timed out
-                    println!("Done playing.");
-                    return pa::Complete
-                }
-            }
             pa::Continue
         }
     };
@@ -124,23 +406,106 @@ fn stream_audio (mut tcp_stream: TcpStream, duration:i32) -> Result<(), pa::Erro
         = pa.open_non_blocking_stream(output_settings, output_stream_callback)?;
     output_stream.start()?;
 
-    // plays for pre-programmed amount of time.
+    // plays until the server is done, showing when the server side is talking.
     println!("Play for {} seconds.", duration);
-    pa.sleep(duration * 1_000 + 3);
+    while output_stream.is_active()? {
+        while let Ok(speech) = vad_receiver.try_recv() {
+            println!("VAD: {}", if speech { "talking" } else { "silent" });
+        }
+        pa.sleep(10);
+    }
 
     output_stream.stop()?;
     output_stream.close()?;
 
+    tcp_listener_handle.join().ok();
+
     Ok(())
 }
 
+/// Captures the mic and sends it to the server's mix for *duration* seconds, in the same packets
+/// the server sends. With *echo_reference* (what is being played), the echo is taken out first,
+/// by a filter *echo_filter_length* samples long.
+fn upload_mic(mut tcp_stream: Connection, mut duration: f64, mut echo_reference: Option<ringbuf::Consumer<f32>>,
+              echo_filter_length: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let pa = pa::PortAudio::new()?;
+    let input_settings =
+        pa.default_input_stream_settings::<f32>(CHANNELS, SAMPLE_RATE, INPUT_FRAMES_PER_BUFFER)?;
 
-// fn from byte slice to float
-pub fn u8_to_f32(bytes: &[u8]) -> &[f32] {
-    unsafe {
-        let floats = bytes.align_to::<f32>();
-        assert_eq!(floats.0.len() + floats.2.len(), 0);
-        assert_eq!(floats.1.len() * 4, bytes.len());
-        floats.1
+    // Create audio -> tcp ringbuffer
+    let (mut rb_producer, mut rb_consumer)
+        = ringbuf::RingBuffer::<f32>::new(RINGBUFFER_SIZE).split();
+
+    // Echo cancelling, with buffers allocated up front
+    let mut echo_canceller = EchoCanceller::with_length(echo_filter_length);
+    let mut mic = vec![0.0; INPUT_FRAMES_PER_BUFFER as usize];
+    let mut reference = vec![0.0; INPUT_FRAMES_PER_BUFFER as usize];
+
+    let input_stream_callback = move |pa::InputStreamCallbackArgs {
+                                          buffer,
+                                          frames,
+                                          ..
+                                      }| {
+        duration -= frames as f64 / SAMPLE_RATE;
+
+        let mic = &mut mic[..frames];
+        mic.copy_from_slice(buffer);
+        if let Some(echo_reference) = echo_reference.as_mut() {
+            // Only what was played last: the filter covers the delay from there to the mic,
+            // anything older queued up here would put the echo out of its reach
+            let stale = echo_reference.len().saturating_sub(frames);
+            echo_reference.discard(stale);
+            // Nothing played yet counts as silence
+            let reference = &mut reference[..frames];
+            let len = echo_reference.pop_slice(reference);
+            for sample in reference[len..].iter_mut() {
+                *sample = 0.0;
+            }
+            echo_canceller.process(mic, reference);
+        }
+
+        // If the connection can't keep up, the mix drops what's late anyway
+        rb_producer.push_slice(mic);
+
+        if duration > 0.0 {
+            pa::Continue
+        } else {
+            pa::Complete
+        }
+    };
+
+    let mut input_stream = pa.open_non_blocking_stream(input_settings, input_stream_callback)?;
+    input_stream.start()?;
+    println!("Uploading the mic.");
+
+    let mut block = [0.0f32; UPLOAD_BLOCK_LENGTH];
+    loop {
+        let active = input_stream.is_active()?;
+        let len = rb_consumer.pop_slice(&mut block);
+        if len == 0 {
+            if !active {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+            continue;
+        }
+        Packet::Audio(block[..len].to_vec()).write_to(&mut tcp_stream)?;
     }
-}
\ No newline at end of file
+
+    input_stream.stop()?;
+    Packet::End.write_to(&mut tcp_stream)?;
+    println!("Finished uploading.");
+
+    Ok(())
+}
+
+/// Pushes all of *samples* into the ringbuffer, waiting for the output to make room.
+fn push_all(rb_producer: &mut ringbuf::Producer<f32>, mut samples: &[f32]) {
+    while !samples.is_empty() {
+        let len = rb_producer.push_slice(samples);
+        samples = &samples[len..];
+        if len == 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Server configuration, read from a simple "key = value" text file.
+//!
+//! Lines starting with '#' are comments. Unknown keys are reported and ignored, and a missing
+//! file just means the defaults are used.
+//!
+//! ```text
+//! # server.conf
+//! media_dir = /srv/audio
+//! agc_enabled = true
+//! agc_target_db = -18
+//! tls_enabled = true
+//! allow_ips = 10.0.0.0/8, 127.0.0.1
+//! ```
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::processing::AgcSettings;
+use crate::vad::VadSettings;
+use crate::archive::ArchiveSettings;
+use crate::tls::TlsSettings;
+use crate::auth::AuthSettings;
+use crate::guard::{Cidr, GuardSettings};
+use crate::relay::RelaySettings;
+use crate::mixer::MixerSettings;
+use crate::transport::Address;
+
+pub const CONFIG_PATH: &str = "server.conf";
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// HTTP streaming of the live mic and WebSocket sessions, 0 (the default) for none.
+    pub http_port: u16,
+    /// Where the files for the "file" mode are looked up.
+    pub media_dir: PathBuf,
+    /// Directory of WAV files or M3U file for the jukebox.
+    pub playlist: Option<PathBuf>,
+    /// Record every session to a WAV file in *record_dir*. The template can use {peer},
+    /// {timestamp} and {session}.
+    pub record_enabled: bool,
+    pub record_dir: PathBuf,
+    pub record_template: String,
+    /// How much of the mic capture the instant replay buffer keeps, 0 (the default) for none.
+    /// Dumps go to *record_dir*.
+    pub replay_secs: f64,
+    pub archive: ArchiveSettings,
+    /// TLS on the audio and control connection.
+    pub tls: TlsSettings,
+    /// Pre-shared-key authentication, and who may use which mode.
+    pub auth: AuthSettings,
+    /// The key for `e2e=group` streams, which relays pass on without decrypting (see e2e.rs).
+    pub e2e_group_key: Option<String>,
+    /// Handshake timeout, connection limits and IP filtering, for every listener.
+    pub guard: GuardSettings,
+    /// Restream another server's stream (the "relay" mode).
+    pub relay: RelaySettings,
+    /// The conference mix (the "upload" and "mix" modes).
+    pub mixer: MixerSettings,
+    pub agc: AgcSettings,
+    pub vad: VadSettings,
+    /// Duck the mic when it starts howling, for when a client plays it back in the same room.
+    pub howl_detector: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            http_port: 0,
+            media_dir: PathBuf::from("media"),
+            playlist: None,
+            record_enabled: false,
+            record_dir: PathBuf::from("recordings"),
+            record_template: "{peer}_{timestamp}_{session}.wav".to_string(),
+            replay_secs: 0.0,
+            archive: ArchiveSettings::default(),
+            tls: TlsSettings::default(),
+            auth: AuthSettings::default(),
+            e2e_group_key: None,
+            guard: GuardSettings::default(),
+            relay: RelaySettings::default(),
+            mixer: MixerSettings::default(),
+            agc: AgcSettings::default(),
+            vad: VadSettings::default(),
+            howl_detector: false,
+        }
+    }
+}
+
+impl ServerConfig {
+
+    /// Loads the config at *path*, falling back to the defaults if there is no such file.
+    pub fn load(path: &str) -> Result<ServerConfig, String> {
+        let mut config = ServerConfig::default();
+
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(_) => {
+                println!("No config file at {}, using defaults.", path);
+                return Ok(config);
+            }
+        };
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts.next()
+                .ok_or(format!("{}:{}: expected 'key = value'", path, number + 1))?
+                .trim();
+
+            config.set(key, value)
+                .map_err(|e| format!("{}:{}: {}", path, number + 1, e))?;
+        }
+
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "http_port" => self.http_port = parse(key, value)?,
+            "media_dir" => self.media_dir = PathBuf::from(value),
+            "playlist" => self.playlist = Some(PathBuf::from(value)),
+            "record_enabled" => self.record_enabled = parse(key, value)?,
+            "record_dir" => self.record_dir = PathBuf::from(value),
+            "record_template" => self.record_template = value.to_string(),
+            "replay_secs" => self.replay_secs = parse(key, value)?,
+            "archive_enabled" => self.archive.enabled = parse(key, value)?,
+            "archive_dir" => self.archive.dir = PathBuf::from(value),
+            "archive_segment_secs" => self.archive.segment_secs = parse(key, value)?,
+            "archive_max_age_secs" => self.archive.max_age_secs = parse(key, value)?,
+            "archive_max_bytes" => self.archive.max_bytes = parse(key, value)?,
+            "tls_enabled" => self.tls.enabled = parse(key, value)?,
+            "tls_cert" => self.tls.cert = PathBuf::from(value),
+            "tls_key" => self.tls.key = PathBuf::from(value),
+            "tls_client_ca" => self.tls.client_ca = Some(PathBuf::from(value)),
+            "tls_generate" => self.tls.generate = parse(key, value)?,
+            "auth_enabled" => self.auth.enabled = parse(key, value)?,
+            "auth_users" => self.auth.users = PathBuf::from(value),
+            "e2e_group_key" => self.e2e_group_key = Some(value.to_string()),
+            "handshake_timeout_secs" => {
+                self.guard.handshake_timeout_secs = parse(key, value)
+                    .ok()
+                    .filter(|secs: &f64| *secs > 0.0 && Duration::try_from_secs_f64(*secs).is_ok())
+                    .ok_or(format!("invalid value for {}: {}", key, value))?
+            }
+            "max_connections_per_ip" => self.guard.max_per_ip = parse(key, value)?,
+            "connection_rate" => self.guard.rate = parse(key, value)?,
+            "connection_burst" => self.guard.burst = parse(key, value)?,
+            "allow_ips" => self.guard.allow = Cidr::parse_list(value)?,
+            "deny_ips" => self.guard.deny = Cidr::parse_list(value)?,
+            "relay_upstream" => self.relay.upstream = Some(Address::parse(value)?),
+            "relay_handshake" => self.relay.handshake = value.to_string(),
+            "relay_tls_ca" => self.relay.tls.get_or_insert_with(Default::default).ca = PathBuf::from(value),
+            "relay_tls_name" => {
+                self.relay.tls.get_or_insert_with(Default::default).server_name = Some(value.to_string())
+            }
+            "relay_user" => self.relay.credentials.get_or_insert_with(Default::default).identity = value.to_string(),
+            "relay_psk" => self.relay.credentials.get_or_insert_with(Default::default).key = value.to_string(),
+            "mix_enabled" => self.mixer.enabled = parse(key, value)?,
+            "mix_latency_ms" => self.mixer.latency_ms = parse(key, value)?,
+            "mix_max_latency_ms" => self.mixer.max_latency_ms = parse(key, value)?,
+            "mix_file" => self.mixer.file = Some(value.to_string()),
+            "mix_file_gain_db" => self.mixer.file_gain_db = parse(key, value)?,
+            "mix_file_pan" => self.mixer.file_pan = parse(key, value)?,
+            "mix_generator" => self.mixer.generator = Some(value.to_string()),
+            "mix_generator_gain_db" => self.mixer.generator_gain_db = parse(key, value)?,
+            "mix_generator_pan" => self.mixer.generator_pan = parse(key, value)?,
+            "agc_enabled" => self.agc.enabled = parse(key, value)?,
+            "agc_target_db" => self.agc.target_db = parse(key, value)?,
+            "agc_attack_ms" => self.agc.attack_ms = parse(key, value)?,
+            "agc_release_ms" => self.agc.release_ms = parse(key, value)?,
+            "agc_max_gain_db" => self.agc.max_gain_db = parse(key, value)?,
+            "vad_enabled" => self.vad.enabled = parse(key, value)?,
+            "vad_margin_db" => self.vad.margin_db = parse(key, value)?,
+            "vad_hangover_ms" => self.vad.hangover_ms = parse(key, value)?,
+            "howl_detector" => self.howl_detector = parse(key, value)?,
+            _ => println!("Unknown config key: {}", key),
+        }
+        Ok(())
+    }
+}
+
+fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value for {}: {}", key, value))
+}
@@ -0,0 +1,154 @@
+//! Control messages sent from the client to the server while a stream is running.
+//!
+//! After the header, the client may write newline-terminated text commands on the same
+//! connection, e.g. "gain -6.0", "mute on", "limiter off", "agc on", or for the jukebox
+//! "next", "prev", "queue <file>" and "shuffle on". "dump" or "dump 30" saves the instant
+//! replay buffer (all of it, or the last 30 seconds) to a WAV file. File streams also take
+//! "pause", "resume", "seek 90" (seconds, or "seek 3969000 samples") and "rate 1.5" (0.25 to
+//! 4). The server reads them on a separate thread and passes them on to the audio side through
+//! a channel.
+//!
+//! Listeners of the mix can set each source's level and position: "source alice gain -6",
+//! "source alice pan -0.5".
+
+use std::io::{BufRead, BufReader};
+use std::sync::mpsc::{channel, Receiver};
+
+use crate::transport::Connection;
+
+// For seeks given in samples
+const SAMPLE_RATE: f64 = 44_100.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    Gain(f32),
+    Mute(bool),
+    Limiter(bool),
+    Agc(bool),
+    // Jukebox
+    Next,
+    Previous,
+    Queue(String),
+    Shuffle(bool),
+    // Instant replay, seconds back from now (all of the buffer if None)
+    Dump(Option<f64>),
+    // File transport, seek position in seconds
+    Pause,
+    Resume,
+    Seek(f64),
+    Rate(f64),
+    // Mixer, for the source with that name: gain in dB, pan from -1 (left) to 1 (right)
+    SourceGain(String, f32),
+    SourcePan(String, f32),
+}
+
+impl Control {
+
+    /// Parses a single command line. Returns None if the command is unknown or malformed.
+    pub fn parse(line: &str) -> Option<Control> {
+        let mut words = line.split_whitespace();
+        let command = words.next()?;
+        let arg = words.next();
+
+        match command {
+            "gain" => arg?.parse().ok().filter(|db: &f32| db.is_finite()).map(Control::Gain),
+            "mute" => parse_on_off(arg?).map(Control::Mute),
+            "unmute" => Some(Control::Mute(false)),
+            "limiter" => parse_on_off(arg?).map(Control::Limiter),
+            "agc" => parse_on_off(arg?).map(Control::Agc),
+            "next" => Some(Control::Next),
+            "prev" | "previous" => Some(Control::Previous),
+            "queue" => Some(Control::Queue(arg?.to_string())),
+            "shuffle" => parse_on_off(arg?).map(Control::Shuffle),
+            "pause" => Some(Control::Pause),
+            "resume" | "play" => Some(Control::Resume),
+            "seek" => {
+                let position: f64 = arg?.parse().ok()?;
+                match words.next() {
+                    None | Some("s") | Some("sec") | Some("seconds") => Some(Control::Seek(position)),
+                    Some("samples") => Some(Control::Seek(position / SAMPLE_RATE)),
+                    Some(_) => None,
+                }
+            }
+            "rate" => arg?.parse().ok()
+                .filter(|rate: &f64| rate.is_finite() && (0.25..=4.0).contains(rate))
+                .map(Control::Rate),
+            "source" => {
+                let name = arg?.to_string();
+                let parameter = words.next()?;
+                let value: f32 = words.next()?.parse().ok().filter(|value: &f32| value.is_finite())?;
+                match parameter {
+                    "gain" => Some(Control::SourceGain(name, value)),
+                    "pan" if (-1.0..=1.0).contains(&value) => Some(Control::SourcePan(name, value)),
+                    _ => None,
+                }
+            }
+            "dump" => match arg {
+                Some(seconds) => seconds.parse().ok().map(|s| Control::Dump(Some(s))),
+                None => Some(Control::Dump(None)),
+            },
+            _ => None,
+        }
+    }
+
+    /// Formats the message as sent over the TCP stream, including the newline.
+    pub fn to_line(&self) -> String {
+        match self {
+            Control::Gain(db) => format!("gain {}\n", db),
+            Control::Mute(mute) => format!("mute {}\n", on_off(*mute)),
+            Control::Limiter(enabled) => format!("limiter {}\n", on_off(*enabled)),
+            Control::Agc(enabled) => format!("agc {}\n", on_off(*enabled)),
+            Control::Next => "next\n".to_string(),
+            Control::Previous => "prev\n".to_string(),
+            Control::Queue(name) => format!("queue {}\n", name),
+            Control::Shuffle(shuffle) => format!("shuffle {}\n", on_off(*shuffle)),
+            Control::Dump(Some(seconds)) => format!("dump {}\n", seconds),
+            Control::Dump(None) => "dump\n".to_string(),
+            Control::Pause => "pause\n".to_string(),
+            Control::Resume => "resume\n".to_string(),
+            Control::Seek(seconds) => format!("seek {}\n", seconds),
+            Control::Rate(rate) => format!("rate {}\n", rate),
+            Control::SourceGain(name, db) => format!("source {} gain {}\n", name, db),
+            Control::SourcePan(name, pan) => format!("source {} pan {}\n", name, pan),
+        }
+    }
+}
+
+fn parse_on_off(arg: &str) -> Option<bool> {
+    match arg {
+        "on" | "1" | "true" => Some(true),
+        "off" | "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value { "on" } else { "off" }
+}
+
+/// Spawns a thread reading control messages from *tcp_stream*.
+/// The thread ends when the connection closes.
+pub fn spawn_reader(tcp_stream: Connection) -> Receiver<Control> {
+    let (sender, receiver) = channel();
+
+    std::thread::spawn(move || {
+        let reader = BufReader::new(tcp_stream);
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            match Control::parse(&line) {
+                Some(control) => {
+                    println!("Control message: {:?}", control);
+                    if sender.send(control).is_err() {
+                        break;
+                    }
+                }
+                None => println!("Unknown control message: {}", line),
+            }
+        }
+    });
+
+    receiver
+}
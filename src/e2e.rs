@@ -0,0 +1,331 @@
+//! End-to-end encryption of the packets, whatever carries them.
+//!
+//! TLS only protects one hop. With `e2e=<public key>` in the handshake, the client asks for
+//! every packet to be encrypted for it alone, so whatever sits in between (a proxy, a plain TCP
+//! hop) only ever sees ciphertext. It goes like this:
+//!
+//! 1. The client sends an ephemeral X25519 public key in the handshake (hex).
+//! 2. The server answers with a 'K' packet holding its own ephemeral public key, in clear, and
+//!    an HMAC-SHA256 of both public keys keyed with the client's pre-shared key (see auth.rs).
+//!    The client checks it before going any further, so nobody in between can swap in their own
+//!    key. That's why encryption needs authentication.
+//! 3. Both derive the session key from the shared secret with HKDF-SHA256, salted with both
+//!    public keys.
+//! 4. Every packet after that is sent as an 'X' packet: a sequence number, then the whole
+//!    framed packet encrypted with ChaCha20-Poly1305, the sequence number being the nonce.
+//!
+//! The receiver drops anything that doesn't decrypt, and anything it has already seen or that is
+//! too old for its replay window, so packets can't be replayed even on transports that reorder.
+//!
+//! Only what the server sends is encrypted, so uploads can't ask for it.
+//!
+//! Relays (relay.rs) pass one stream on to many listeners, so a key for each listener can't work
+//! through them. With `e2e=group` instead, the server encrypts for everyone who knows the group
+//! key (`e2e_group_key` on the server, given to listeners ahead of time, never to relays): its 'K'
+//! packet is a random salt and an HMAC-SHA256 of it keyed with the group key, the stream key is
+//! HKDF-SHA256 of the group key with that salt, and the 'X' packets are the same as above. Relays
+//! pass the 'K' and 'X' packets on as they are, so they never see the audio. They can't make a
+//! 'K' packet of their own, but they could play an older stream again from its 'K' packet.
+
+use std::io;
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::packet::Packet;
+
+// Binds the derived key to this protocol
+const KEY_INFO: &[u8] = b"rust_test e2e v1";
+
+// How far behind the newest sequence number a packet may still arrive
+const REPLAY_WINDOW: u64 = 64;
+
+const PUBLIC_KEY_LENGTH: usize = 32;
+
+// Same as KEY_INFO, for group streams
+const GROUP_INFO: &[u8] = b"rust_test e2e group v1";
+const SALT_LENGTH: usize = 32;
+
+/// One side's half of the key exchange.
+pub struct KeyExchange {
+    private: EphemeralPrivateKey,
+    public: Vec<u8>,
+}
+
+impl KeyExchange {
+
+    pub fn new() -> io::Result<KeyExchange> {
+        let private = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+            .map_err(|_| crypto_error("could not generate a key"))?;
+        let public = private.compute_public_key()
+            .map_err(|_| crypto_error("could not generate a key"))?
+            .as_ref()
+            .to_vec();
+        Ok(KeyExchange { private, public })
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public
+    }
+
+    /// Server side: the 'K' packet answering *client_public*, vouched for with the client's
+    /// pre-shared key.
+    pub fn key_packet(&self, client_public: &[u8], psk: &hmac::Key) -> Packet {
+        let tag = hmac::sign(psk, &transcript(client_public, &self.public));
+        Packet::Key([&self.public[..], tag.as_ref()].concat())
+    }
+
+    /// Server side: the key to encrypt for the client that sent *client_public*.
+    pub fn seal_for(self, client_public: &[u8]) -> io::Result<Sealer> {
+        let salt = [client_public, &self.public[..]].concat();
+        let key = derive(self.private, client_public, &salt)?;
+        Ok(Sealer { key, next_seq: 0 })
+    }
+
+    /// Client side: the key to decrypt what the server encrypts, from its 'K' packet. Fails
+    /// unless the packet was made with our pre-shared key.
+    pub fn open_from(self, key_packet: &[u8], psk: &hmac::Key) -> io::Result<Opener> {
+        if key_packet.len() <= PUBLIC_KEY_LENGTH {
+            return Err(crypto_error("server key too short"));
+        }
+        let (server_public, tag) = key_packet.split_at(PUBLIC_KEY_LENGTH);
+        hmac::verify(psk, &transcript(&self.public, server_public), tag)
+            .map_err(|_| crypto_error("server key not signed with our pre-shared key"))?;
+
+        let salt = [&self.public[..], server_public].concat();
+        let key = derive(self.private, server_public, &salt)?;
+        Ok(Opener { key, window: ReplayWindow::new() })
+    }
+}
+
+/// The key shared by a server and its listeners for group streams, the ones relays can pass on.
+pub struct GroupKey {
+    secret: Vec<u8>,
+    signing: hmac::Key,
+}
+
+impl GroupKey {
+
+    pub fn new(secret: &str) -> GroupKey {
+        GroupKey {
+            secret: secret.as_bytes().to_vec(),
+            signing: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+        }
+    }
+
+    /// Server side: the 'K' packet starting a stream, and the key to encrypt it with.
+    pub fn start(&self) -> io::Result<(Packet, Sealer)> {
+        let mut salt = [0u8; SALT_LENGTH];
+        SystemRandom::new().fill(&mut salt).map_err(|_| crypto_error("no random numbers"))?;
+        let tag = hmac::sign(&self.signing, &[GROUP_INFO, &salt].concat());
+        let key = self.derive(&salt)?;
+        Ok((Packet::Key([&salt[..], tag.as_ref()].concat()), Sealer { key, next_seq: 0 }))
+    }
+
+    /// Listener side: the key to decrypt the stream that *key_packet* starts. Fails unless the
+    /// packet was made with the group key.
+    pub fn open_from(&self, key_packet: &[u8]) -> io::Result<Opener> {
+        if key_packet.len() <= SALT_LENGTH {
+            return Err(crypto_error("stream key too short"));
+        }
+        let (salt, tag) = key_packet.split_at(SALT_LENGTH);
+        hmac::verify(&self.signing, &[GROUP_INFO, salt].concat(), tag)
+            .map_err(|_| crypto_error("stream key not signed with our group key"))?;
+        Ok(Opener { key: self.derive(salt)?, window: ReplayWindow::new() })
+    }
+
+    fn derive(&self, salt: &[u8]) -> io::Result<LessSafeKey> {
+        let prk = Salt::new(HKDF_SHA256, salt).extract(&self.secret);
+        let okm = prk.expand(&[GROUP_INFO], &CHACHA20_POLY1305)
+            .map_err(|_| crypto_error("key derivation failed"))?;
+        Ok(LessSafeKey::new(UnboundKey::from(okm)))
+    }
+}
+
+/// Encrypts packets, numbering them as it goes.
+pub struct Sealer {
+    key: LessSafeKey,
+    next_seq: u64,
+}
+
+impl Sealer {
+
+    pub fn seal(&mut self, packet: &Packet) -> io::Result<Packet> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let mut data = Vec::new();
+        packet.write_to(&mut data)?;
+        self.key.seal_in_place_append_tag(nonce(seq), Aad::empty(), &mut data)
+            .map_err(|_| crypto_error("encryption failed"))?;
+        Ok(Packet::Sealed { seq, data })
+    }
+}
+
+/// Decrypts packets, refusing replays.
+pub struct Opener {
+    key: LessSafeKey,
+    window: ReplayWindow,
+}
+
+impl Opener {
+
+    pub fn open(&mut self, seq: u64, data: &[u8]) -> io::Result<Packet> {
+        if !self.window.is_new(seq) {
+            return Err(crypto_error("replayed packet"));
+        }
+        let mut data = data.to_vec();
+        let plain = self.key.open_in_place(nonce(seq), Aad::empty(), &mut data)
+            .map_err(|_| crypto_error("packet does not decrypt"))?;
+        let packet = Packet::read_from(&mut &plain[..])?;
+        // Only counts as seen once we know it's genuine
+        self.window.mark(seq);
+        Ok(packet)
+    }
+}
+
+/// The newest sequence number seen, and which of the ones just before it were seen too.
+struct ReplayWindow {
+    newest: Option<u64>,
+    // Bit i set: newest - i was seen
+    seen: u64,
+}
+
+impl ReplayWindow {
+
+    fn new() -> ReplayWindow {
+        ReplayWindow { newest: None, seen: 0 }
+    }
+
+    fn is_new(&self, seq: u64) -> bool {
+        match self.newest {
+            None => true,
+            Some(newest) if seq > newest => true,
+            Some(newest) => newest - seq < REPLAY_WINDOW && self.seen & (1 << (newest - seq)) == 0,
+        }
+    }
+
+    fn mark(&mut self, seq: u64) {
+        match self.newest {
+            Some(newest) if seq <= newest => self.seen |= 1 << (newest - seq),
+            Some(newest) => {
+                let shift = seq - newest;
+                self.seen = if shift < REPLAY_WINDOW { (self.seen << shift) | 1 } else { 1 };
+                self.newest = Some(seq);
+            }
+            None => {
+                self.seen = 1;
+                self.newest = Some(seq);
+            }
+        }
+    }
+}
+
+/// What the server's HMAC covers: both public keys, client's first.
+fn transcript(client_public: &[u8], server_public: &[u8]) -> Vec<u8> {
+    [KEY_INFO, client_public, server_public].concat()
+}
+
+fn derive(private: EphemeralPrivateKey, peer_public: &[u8], salt: &[u8]) -> io::Result<LessSafeKey> {
+    let peer_public = UnparsedPublicKey::new(&X25519, peer_public);
+    let key = agreement::agree_ephemeral(private, &peer_public, |shared_secret| {
+        let prk = Salt::new(HKDF_SHA256, salt).extract(shared_secret);
+        let okm = prk.expand(&[KEY_INFO], &CHACHA20_POLY1305).map_err(|_| ())?;
+        Ok::<UnboundKey, ()>(UnboundKey::from(okm))
+    });
+    match key {
+        Ok(Ok(key)) => Ok(LessSafeKey::new(key)),
+        _ => Err(crypto_error("key exchange failed")),
+    }
+}
+
+fn nonce(seq: u64) -> Nonce {
+    let mut bytes = [0u8; aead::NONCE_LEN];
+    bytes[4..].copy_from_slice(&seq.to_be_bytes());
+    Nonce::assume_unique_for_key(bytes)
+}
+
+fn crypto_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth;
+
+    #[test]
+    fn round_trip_with_authenticated_key() {
+        let psk = auth::psk("correct-horse");
+        let client = KeyExchange::new().unwrap();
+        let server = KeyExchange::new().unwrap();
+        let client_public = client.public_key().to_vec();
+
+        let key = match server.key_packet(&client_public, &psk) {
+            Packet::Key(key) => key,
+            other => panic!("{:?}", other),
+        };
+        let mut sealer = server.seal_for(&client_public).unwrap();
+        let mut opener = client.open_from(&key, &psk).unwrap();
+
+        for _ in 0..3 {
+            match sealer.seal(&Packet::Audio(vec![0.5; 4])).unwrap() {
+                Packet::Sealed { seq, data } => {
+                    assert_eq!(opener.open(seq, &data).unwrap(), Packet::Audio(vec![0.5; 4]));
+                    // Once only
+                    assert!(opener.open(seq, &data).is_err());
+                }
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_key_not_made_with_psk() {
+        let client = KeyExchange::new().unwrap();
+        let client_public = client.public_key().to_vec();
+
+        // Someone in the middle, who doesn't know the key
+        let attacker = KeyExchange::new().unwrap();
+        let key = match attacker.key_packet(&client_public, &auth::psk("guess")) {
+            Packet::Key(key) => key,
+            other => panic!("{:?}", other),
+        };
+        assert!(client.open_from(&key, &auth::psk("correct-horse")).is_err());
+
+        // Or who sends the bare public key
+        let client = KeyExchange::new().unwrap();
+        assert!(client.open_from(attacker.public_key(), &auth::psk("correct-horse")).is_err());
+    }
+
+    #[test]
+    fn group_stream_opens_anywhere_with_the_group_key() {
+        let server = GroupKey::new("shared-secret");
+        let (key, mut sealer) = server.start().unwrap();
+        let key = match key {
+            Packet::Key(key) => key,
+            other => panic!("{:?}", other),
+        };
+        let sealed: Vec<Packet> = (0..3)
+            .map(|i| sealer.seal(&Packet::Audio(vec![i as f32; 4])).unwrap())
+            .collect();
+
+        // A listener joining late, through a relay that only passes packets on
+        let mut opener = GroupKey::new("shared-secret").open_from(&key).unwrap();
+        match sealed[2] {
+            Packet::Sealed { seq, ref data } => {
+                assert_eq!(opener.open(seq, data).unwrap(), Packet::Audio(vec![2.0; 4]));
+            }
+            ref other => panic!("{:?}", other),
+        }
+
+        // Without the group key, nothing
+        assert!(GroupKey::new("guess").open_from(&key).is_err());
+        let mut forged = key.clone();
+        forged[0] ^= 1;
+        assert!(GroupKey::new("shared-secret").open_from(&forged).is_err());
+    }
+}
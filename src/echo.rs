@@ -0,0 +1,212 @@
+//! Echo cancellation and feedback (howl) suppression.
+//!
+//! With the mic and the speaker on the same machine, whatever is played comes straight back
+//! into the mic. The `EchoCanceller` is an NLMS adaptive filter: it learns the path from the
+//! speaker to the mic, using the played signal as reference, and subtracts its estimate of
+//! the echo from the mic signal.
+//!
+//! The `HowlDetector` catches what is left: once the loop gain gets over 1 the system howls on a
+//! single tone. When a strong tone is sustained, the gain is ducked until it dies out.
+
+use crate::processing::{db_to_linear, linear_to_db};
+
+const SAMPLE_RATE: f32 = 44_100.0;
+
+// ~23ms of echo path at 44.1K, the output and input latencies on top of the acoustic path.
+// Every tap costs two multiply-adds per sample, so longer (for high latency audio devices)
+// costs CPU in proportion.
+pub const DEFAULT_ECHO_FILTER_LENGTH: usize = 1024;
+const ECHO_STEP_SIZE: f32 = 0.1;
+// Avoids dividing by ~0 when nothing is being played.
+const ECHO_REGULARISATION: f32 = 1e-3;
+
+// Howl detection runs on blocks of this size, looking for periods in the lag range
+// (~110Hz to ~2.2kHz, where feedback usually builds up).
+const HOWL_BLOCK: usize = 1024;
+const HOWL_MIN_LAG: usize = 20;
+const HOWL_MAX_LAG: usize = 400;
+const HOWL_TONALITY: f32 = 0.9;
+const HOWL_MIN_LEVEL_DB: f32 = -30.0;
+const HOWL_SUSTAIN_SECS: f32 = 0.3;
+const HOWL_DUCK_DB: f32 = -18.0;
+const HOWL_RECOVERY_SECS: f32 = 2.0;
+
+pub struct EchoCanceller {
+    weights: Vec<f32>,
+    // Reference history, written twice so the filter always reads a contiguous slice
+    history: Vec<f32>,
+    pos: usize,
+    power: f32,
+}
+
+impl EchoCanceller {
+
+    pub fn new() -> EchoCanceller {
+        EchoCanceller::with_length(DEFAULT_ECHO_FILTER_LENGTH)
+    }
+
+    /// Cancels echoes up to *length* samples late.
+    pub fn with_length(length: usize) -> EchoCanceller {
+        let length = std::cmp::max(length, 1);
+        EchoCanceller {
+            weights: vec![0.0; length],
+            history: vec![0.0; length * 2],
+            pos: 0,
+            power: 0.0,
+        }
+    }
+
+    /// Removes the echo of *reference* (what was played) from *mic*, in place.
+    /// Both slices must be the same length, and line up in time.
+    pub fn process(&mut self, mic: &mut [f32], reference: &[f32]) {
+        assert_eq!(mic.len(), reference.len());
+        let length = self.weights.len();
+
+        for (sample, &far) in mic.iter_mut().zip(reference.iter()) {
+            // Update the reference history and its power
+            let oldest = self.history[self.pos];
+            self.power += far * far - oldest * oldest;
+            self.power = self.power.max(0.0);
+            self.history[self.pos] = far;
+            self.history[self.pos + length] = far;
+
+            // Oldest sample first, so the weights are read backwards (newest tap first)
+            let start = self.pos + 1;
+            let x = &self.history[start..start + length];
+
+            // Echo estimate
+            let estimate: f32 = self.weights.iter().rev().zip(x.iter()).map(|(w, x)| w * x).sum();
+            let error = *sample - estimate;
+
+            // NLMS weight update
+            let step = ECHO_STEP_SIZE * error / (self.power + ECHO_REGULARISATION);
+            for (w, x) in self.weights.iter_mut().rev().zip(x.iter()) {
+                *w += step * x;
+            }
+
+            self.pos = (self.pos + 1) % length;
+            *sample = error;
+        }
+    }
+}
+
+pub struct HowlDetector {
+    block: Vec<f32>,
+    filled: usize,
+    howling_secs: f32,
+    duck: f32,
+    duck_gain: f32,
+    recovery_step: f32,
+}
+
+impl HowlDetector {
+
+    pub fn new() -> HowlDetector {
+        HowlDetector {
+            block: vec![0.0; HOWL_BLOCK],
+            filled: 0,
+            howling_secs: 0.0,
+            duck: 1.0,
+            duck_gain: db_to_linear(HOWL_DUCK_DB),
+            // Back up to unity gain over HOWL_RECOVERY_SECS
+            recovery_step: (1.0 - db_to_linear(HOWL_DUCK_DB)) / (HOWL_RECOVERY_SECS * SAMPLE_RATE),
+        }
+    }
+
+    pub fn is_ducking(&self) -> bool {
+        self.duck < 1.0
+    }
+
+    /// Analyses *buffer* and applies the ducking gain to it, in place.
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        for sample in buffer.iter_mut() {
+            self.block[self.filled] = *sample;
+            self.filled += 1;
+            if self.filled == HOWL_BLOCK {
+                self.analyse_block();
+                self.filled = 0;
+            }
+
+            *sample *= self.duck;
+            if self.duck < 1.0 {
+                self.duck = (self.duck + self.recovery_step).min(1.0);
+            }
+        }
+    }
+
+    fn analyse_block(&mut self) {
+        let block_secs = HOWL_BLOCK as f32 / SAMPLE_RATE;
+
+        if self.is_tonal() {
+            self.howling_secs += block_secs;
+        } else {
+            self.howling_secs = 0.0;
+        }
+
+        if self.howling_secs >= HOWL_SUSTAIN_SECS {
+            if !self.is_ducking() {
+                println!("Feedback detected, ducking gain by {}dB.", HOWL_DUCK_DB);
+            }
+            self.duck = self.duck_gain;
+        }
+    }
+
+    /// A loud block that (nearly) repeats itself at some period is a sustained tone.
+    fn is_tonal(&self) -> bool {
+        let energy: f32 = self.block.iter().map(|s| s * s).sum();
+        let rms = (energy / HOWL_BLOCK as f32).sqrt();
+        if linear_to_db(rms) < HOWL_MIN_LEVEL_DB {
+            return false;
+        }
+
+        // Autocorrelation, normalised by the energy of the overlapping part
+        (HOWL_MIN_LAG..HOWL_MAX_LAG).any(|lag| {
+            let a = &self.block[..HOWL_BLOCK - lag];
+            let b = &self.block[lag..];
+            let correlation: f32 = a.iter().zip(b.iter()).map(|(a, b)| a * b).sum();
+            let overlap = (HOWL_BLOCK - lag) as f32 / HOWL_BLOCK as f32;
+            correlation / (energy * overlap) > HOWL_TONALITY
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Repeatable white-ish noise, played and heard back *delay* samples later at half the level
+    fn echo(delay: usize, len: usize) -> (Vec<f32>, Vec<f32>) {
+        let mut state = 12345u32;
+        let reference: Vec<f32> = (0..len).map(|_| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 8) as f32 / (1 << 23) as f32 - 1.0
+        }).collect();
+        let mic = (0..len).map(|i| if i >= delay { reference[i - delay] * 0.5 } else { 0.0 }).collect();
+        (mic, reference)
+    }
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|sample| sample * sample).sum()
+    }
+
+    #[test]
+    fn cancels_echoes_within_its_length() {
+        let (mut mic, reference) = echo(300, 44_100);
+        let before = energy(&mic[40_000..]);
+        let mut canceller = EchoCanceller::with_length(512);
+        for (mic, reference) in mic.chunks_mut(256).zip(reference.chunks(256)) {
+            canceller.process(mic, reference);
+        }
+        // At least 30dB down once it has converged
+        assert!(energy(&mic[40_000..]) < before / 1000.0);
+    }
+
+    #[test]
+    fn misses_echoes_past_its_length() {
+        let (mut mic, reference) = echo(300, 44_100);
+        let before = energy(&mic[40_000..]);
+        let mut canceller = EchoCanceller::with_length(256);
+        canceller.process(&mut mic, &reference);
+        assert!(energy(&mic[40_000..]) > before / 2.0);
+    }
+}
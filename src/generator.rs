@@ -0,0 +1,240 @@
+//! Test signal generator: sine, square, saw, triangle, white and pink noise, sweeps and
+//! multi-tone.
+//!
+//! Oscillators keep a phase accumulator (in cycles, 0.0 to 1.0) that moves by
+//! frequency / sample rate every sample, so the frequency is exact whatever it is. Square
+//! and saw use PolyBLEP to round off the steps, which keeps the aliasing down.
+//!
+//! Settings come from the handshake options, e.g.
+//! "stream sin 10s gen=square freq=440 level=-12" or
+//! "stream sin 10s gen=logsweep freq=20 to=20000 sweep=5".
+
+use std::f64::consts::PI;
+
+use crate::handshake::Handshake;
+use crate::processing::db_to_linear;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Square,
+    Saw,
+    Triangle,
+    WhiteNoise,
+    PinkNoise,
+    LinearSweep,
+    LogSweep,
+    MultiTone,
+}
+
+impl Waveform {
+    pub fn parse(name: &str) -> Option<Waveform> {
+        match name {
+            "sine" | "sin" => Some(Waveform::Sine),
+            "square" => Some(Waveform::Square),
+            "saw" => Some(Waveform::Saw),
+            "triangle" => Some(Waveform::Triangle),
+            "white" | "noise" => Some(Waveform::WhiteNoise),
+            "pink" => Some(Waveform::PinkNoise),
+            "sweep" | "linsweep" => Some(Waveform::LinearSweep),
+            "logsweep" => Some(Waveform::LogSweep),
+            "multi" => Some(Waveform::MultiTone),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratorSettings {
+    pub waveform: Waveform,
+    /// Frequency in Hz, or start frequency of a sweep.
+    pub frequency: f64,
+    /// End frequency of a sweep.
+    pub end_frequency: f64,
+    /// Length of one sweep, it starts over after that.
+    pub sweep_secs: f64,
+    /// Frequencies for multi-tone.
+    pub tones: Vec<f64>,
+    /// Peak level in dBFS.
+    pub level_db: f32,
+}
+
+impl Default for GeneratorSettings {
+    fn default() -> GeneratorSettings {
+        GeneratorSettings {
+            waveform: Waveform::Sine,
+            frequency: 440.0,
+            end_frequency: 20_000.0,
+            sweep_secs: 10.0,
+            tones: vec![440.0, 554.37, 659.25],
+            level_db: -6.0,
+        }
+    }
+}
+
+impl GeneratorSettings {
+
+    /// Reads the generator options of a handshake, anything not given keeps its default.
+    pub fn from_handshake(handshake: &Handshake) -> Result<GeneratorSettings, String> {
+        let mut settings = GeneratorSettings::default();
+
+        if let Some(name) = handshake.get("gen") {
+            settings.waveform = Waveform::parse(name)
+                .ok_or(format!("unknown waveform: {}", name))?;
+        }
+        if let Some(freq) = handshake.get("freq") {
+            settings.frequency = parse_frequency(freq)?;
+        }
+        if let Some(freq) = handshake.get("to") {
+            settings.end_frequency = parse_frequency(freq)?;
+        }
+        if let Some(secs) = handshake.get("sweep") {
+            settings.sweep_secs = secs.parse().map_err(|_| format!("invalid sweep length: {}", secs))?;
+            if !settings.sweep_secs.is_finite() || settings.sweep_secs <= 0.0 {
+                return Err(format!("invalid sweep length: {}", secs));
+            }
+        }
+        if let Some(tones) = handshake.get("tones") {
+            settings.tones = tones.split(',')
+                .map(parse_frequency)
+                .collect::<Result<Vec<f64>, String>>()?;
+        }
+        if let Some(level) = handshake.get("level") {
+            settings.level_db = level.parse().map_err(|_| format!("invalid level: {}", level))?;
+            settings.level_db = settings.level_db.min(0.0);
+        }
+
+        Ok(settings)
+    }
+}
+
+fn parse_frequency(text: &str) -> Result<f64, String> {
+    match text.parse::<f64>() {
+        Ok(freq) if freq > 0.0 && freq < 22_050.0 => Ok(freq),
+        _ => Err(format!("invalid frequency: {}", text)),
+    }
+}
+
+pub struct Generator {
+    settings: GeneratorSettings,
+    sample_rate: f64,
+    amplitude: f32,
+    phase: f64,
+    tone_phases: Vec<f64>,
+    sweep_position: f64,
+    seed: u32,
+    pink: [f32; 7],
+}
+
+impl Generator {
+
+    pub fn new(settings: GeneratorSettings, sample_rate: f64) -> Generator {
+        let tone_phases = vec![0.0; settings.tones.len()];
+        Generator {
+            amplitude: db_to_linear(settings.level_db),
+            settings,
+            sample_rate,
+            phase: 0.0,
+            tone_phases,
+            sweep_position: 0.0,
+            seed: 0x2545_f491,
+            pink: [0.0; 7],
+        }
+    }
+
+    pub fn fill(&mut self, buffer: &mut [f32]) {
+        for sample in buffer.iter_mut() {
+            *sample = self.next_sample();
+        }
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        let increment = self.settings.frequency / self.sample_rate;
+
+        let value = match self.settings.waveform {
+            Waveform::Sine => (self.phase * 2.0 * PI).sin(),
+            Waveform::Square => {
+                let naive = if self.phase < 0.5 { 1.0 } else { -1.0 };
+                naive + poly_blep(self.phase, increment) - poly_blep((self.phase + 0.5) % 1.0, increment)
+            }
+            Waveform::Saw => 2.0 * self.phase - 1.0 - poly_blep(self.phase, increment),
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            Waveform::WhiteNoise => self.white() as f64,
+            Waveform::PinkNoise => self.pink() as f64,
+            Waveform::LinearSweep | Waveform::LogSweep => {
+                let value = (self.phase * 2.0 * PI).sin();
+                self.advance_sweep();
+                return value as f32 * self.amplitude;
+            }
+            Waveform::MultiTone => {
+                // Each tone gets an equal share so the sum can't go over the level
+                let count = self.tone_phases.len().max(1) as f64;
+                let mut sum = 0.0;
+                for (phase, freq) in self.tone_phases.iter_mut().zip(self.settings.tones.iter()) {
+                    sum += (*phase * 2.0 * PI).sin();
+                    *phase = (*phase + freq / self.sample_rate) % 1.0;
+                }
+                sum / count
+            }
+        };
+
+        self.phase = (self.phase + increment) % 1.0;
+        value as f32 * self.amplitude
+    }
+
+    /// Moves the sweep along by one sample, with the frequency for the current position.
+    fn advance_sweep(&mut self) {
+        let progress = self.sweep_position / self.settings.sweep_secs;
+        let start = self.settings.frequency;
+        let end = self.settings.end_frequency;
+
+        let freq = if self.settings.waveform == Waveform::LogSweep {
+            start * (end / start).powf(progress)
+        } else {
+            start + (end - start) * progress
+        };
+
+        self.phase = (self.phase + freq / self.sample_rate) % 1.0;
+        self.sweep_position += 1.0 / self.sample_rate;
+        if self.sweep_position >= self.settings.sweep_secs {
+            self.sweep_position = 0.0;
+        }
+    }
+
+    /// Uniform white noise in [-1.0, 1.0] (xorshift32).
+    fn white(&mut self) -> f32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+
+    /// Pink noise, Paul Kellet's refined filter on white noise.
+    fn pink(&mut self) -> f32 {
+        let white = self.white();
+        let b = &mut self.pink;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.153852;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+        // The filter has a gain of roughly 9, bring it back to about full scale
+        (pink * 0.11).clamp(-1.0, 1.0)
+    }
+}
+
+/// PolyBLEP residual, smooths out the discontinuity at phase 0.
+fn poly_blep(phase: f64, increment: f64) -> f64 {
+    if phase < increment {
+        let t = phase / increment;
+        2.0 * t - t * t - 1.0
+    } else if phase > 1.0 - increment {
+        let t = (phase - 1.0) / increment;
+        t * t + 2.0 * t + 1.0
+    } else {
+        0.0
+    }
+}
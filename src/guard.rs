@@ -0,0 +1,276 @@
+//! Who may connect, and how often: protection for the listeners against floods and idle clients.
+//!
+//! Every new connection is checked, in order, against:
+//! - `deny_ips`, then `allow_ips` (comma-separated CIDR blocks, e.g. "10.0.0.0/8, ::1"). Anything
+//!   denied is refused, and when there is an allow list, anything not on it is refused too.
+//! - A token bucket per address: `connection_burst` connections at once, refilled at
+//!   `connection_rate` per second.
+//! - `max_connections_per_ip` open at the same time.
+//!
+//! Connections without an IP address (Unix sockets, in-process channels) are local and always
+//! let through. Once in, a client has `handshake_timeout_secs` to get through TLS, the
+//! authentication and the handshake, all together.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::mpsc::{sync_channel, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Forget addresses with nothing open and a full bucket when there are more than this
+const MAX_TRACKED: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct GuardSettings {
+    pub handshake_timeout_secs: f64,
+    /// 0 for no limit.
+    pub max_per_ip: usize,
+    /// New connections per second and per address, 0 for no limit.
+    pub rate: f64,
+    pub burst: f64,
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl Default for GuardSettings {
+    fn default() -> GuardSettings {
+        GuardSettings {
+            handshake_timeout_secs: 10.0,
+            max_per_ip: 8,
+            rate: 2.0,
+            burst: 10.0,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+impl GuardSettings {
+
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.handshake_timeout_secs)
+    }
+}
+
+/// Gives up on a connection that isn't through its handshake in time. Read timeouts alone start
+/// again with every byte, so a client could trickle one in now and then for ever. Dropping the
+/// deadline means the handshake is done.
+pub struct Deadline {
+    _done: SyncSender<()>,
+}
+
+impl Deadline {
+
+    /// Calls *expire*, which should shut the connection down, if still not done after *timeout*.
+    pub fn start<F: FnOnce() + Send + 'static>(timeout: Duration, expire: F) -> Deadline {
+        let (done, waiting) = sync_channel::<()>(0);
+        std::thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = waiting.recv_timeout(timeout) {
+                expire();
+            }
+        });
+        Deadline { _done: done }
+    }
+}
+
+/// A block of IP addresses, "192.168.1.0/24" or a single address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+
+    pub fn parse(text: &str) -> Result<Cidr, String> {
+        let mut parts = text.trim().splitn(2, '/');
+        let written: IpAddr = parts.next().unwrap_or("").parse()
+            .map_err(|_| format!("invalid address: {}", text))?;
+        let address = canonical(written);
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse::<u32>().ok()
+                // "::ffff:10.0.0.0/104" is 10.0.0.0/8
+                .and_then(|p| if written.is_ipv6() && address.is_ipv4() { p.checked_sub(96) } else { Some(p) })
+                .filter(|&p| p <= max)
+                .ok_or(format!("invalid prefix length: {}", text))?,
+            None => max,
+        };
+        Ok(Cidr { address, prefix })
+    }
+
+    /// Comma-separated blocks, as in the config.
+    pub fn parse_list(text: &str) -> Result<Vec<Cidr>, String> {
+        text.split(',')
+            .filter(|block| !block.trim().is_empty())
+            .map(Cidr::parse)
+            .collect()
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, canonical(address)) {
+            (IpAddr::V4(block), IpAddr::V4(address)) => {
+                same_prefix(&block.octets(), &address.octets(), self.prefix)
+            }
+            (IpAddr::V6(block), IpAddr::V6(address)) => {
+                same_prefix(&block.octets(), &address.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+pub struct Guard {
+    settings: GuardSettings,
+    addresses: Arc<Mutex<HashMap<IpAddr, Address>>>,
+}
+
+struct Address {
+    open: usize,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Guard {
+
+    pub fn new(settings: &GuardSettings) -> Guard {
+        Guard { settings: settings.clone(), addresses: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        self.settings.handshake_timeout()
+    }
+
+    /// Lets a new connection from *address* in, or says why not. It counts as open until the
+    /// ticket is dropped.
+    pub fn admit(&self, address: Option<IpAddr>) -> Result<Ticket, String> {
+        let address = match address {
+            Some(address) => canonical(address),
+            None => return Ok(Ticket { address: None, addresses: self.addresses.clone() }),
+        };
+        let settings = &self.settings;
+
+        if settings.deny.iter().any(|block| block.contains(address)) {
+            return Err("denied".to_string());
+        }
+        if !settings.allow.is_empty() && !settings.allow.iter().any(|block| block.contains(address)) {
+            return Err("not allowed".to_string());
+        }
+
+        let mut addresses = self.addresses.lock().unwrap();
+        if addresses.len() > MAX_TRACKED {
+            // Buckets are only refilled on admit, see where they would be by now
+            let now = Instant::now();
+            let (rate, burst) = (settings.rate, settings.burst);
+            addresses.retain(|_, state| {
+                let tokens = state.tokens + now.duration_since(state.refilled).as_secs_f64() * rate;
+                state.open > 0 || tokens < burst
+            });
+        }
+        let state = addresses.entry(address).or_insert(Address {
+            open: 0,
+            tokens: settings.burst,
+            refilled: Instant::now(),
+        });
+
+        if settings.rate > 0.0 {
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.refilled).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * settings.rate).min(settings.burst);
+            state.refilled = now;
+            if state.tokens < 1.0 {
+                return Err("too many new connections".to_string());
+            }
+            state.tokens -= 1.0;
+        }
+        if settings.max_per_ip > 0 && state.open >= settings.max_per_ip {
+            return Err(format!("already {} connections", state.open));
+        }
+
+        state.open += 1;
+        Ok(Ticket { address: Some(address), addresses: self.addresses.clone() })
+    }
+}
+
+/// An admitted connection, for as long as it is open.
+pub struct Ticket {
+    address: Option<IpAddr>,
+    addresses: Arc<Mutex<HashMap<IpAddr, Address>>>,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if let Some(address) = self.address {
+            if let Some(state) = self.addresses.lock().unwrap().get_mut(&address) {
+                state.open = state.open.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// IPv4 clients of an IPv6 socket show up as "::ffff:a.b.c.d", treat them as IPv4.
+fn canonical(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => address,
+        },
+        address => address,
+    }
+}
+
+fn same_prefix(a: &[u8], b: &[u8], prefix: u32) -> bool {
+    let bytes = (prefix / 8) as usize;
+    let bits = prefix % 8;
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    bits == 0 || (a[bytes] ^ b[bytes]) >> (8 - bits) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_blocks() {
+        let block = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(block.contains(ip("10.1.2.3")));
+        assert!(block.contains(ip("::ffff:10.1.2.3")));
+        assert!(!block.contains(ip("11.0.0.1")));
+
+        let single = Cidr::parse("::1").unwrap();
+        assert!(single.contains(ip("::1")));
+        assert!(!single.contains(ip("::2")));
+
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("::/129").is_err());
+        assert!(Cidr::parse("10.0.0.0/x").is_err());
+        assert!(Cidr::parse("nowhere").is_err());
+    }
+
+    #[test]
+    fn mapped_blocks_are_ipv4() {
+        assert_eq!(Cidr::parse("::ffff:10.0.0.0/104").unwrap(), Cidr::parse("10.0.0.0/8").unwrap());
+        assert_eq!(Cidr::parse("::ffff:10.0.0.1").unwrap(), Cidr::parse("10.0.0.1").unwrap());
+        // Wider than the mapped addresses
+        assert!(Cidr::parse("::ffff:10.0.0.0/80").is_err());
+    }
+
+    #[test]
+    fn forgets_idle_addresses() {
+        let guard = Guard::new(&GuardSettings { rate: 1000.0, ..GuardSettings::default() });
+        for i in 0..(MAX_TRACKED * 2) as u32 {
+            let address = IpAddr::V4((0x0A00_0000 + i).into());
+            drop(guard.admit(Some(address)).unwrap());
+        }
+        // Refilled by the time they are looked at again
+        std::thread::sleep(Duration::from_millis(20));
+        guard.admit(Some(ip("192.168.0.1"))).unwrap();
+        assert!(guard.addresses.lock().unwrap().len() <= MAX_TRACKED + 1);
+    }
+}
@@ -0,0 +1,98 @@
+//! The request the client sends when it connects.
+//!
+//! One line of text: "stream <mode> <seconds>s", then optional "key=value" options, then a
+//! newline. For example "stream sin 10s gen=square freq=440 level=-12\n".
+
+use std::io::{self, Read};
+
+// Anything longer than this is not a handshake.
+const MAX_HANDSHAKE_LENGTH: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub mode: String,
+    pub duration: u32,
+    pub options: Vec<(String, String)>,
+}
+
+impl Handshake {
+
+    pub fn new(mode: &str, duration: u32) -> Handshake {
+        Handshake {
+            mode: mode.to_string(),
+            duration,
+            options: Vec::new(),
+        }
+    }
+
+    /// Adds an option, replacing any previous value for *key*.
+    pub fn with(mut self, key: &str, value: &str) -> Handshake {
+        self.options.retain(|(k, _)| k != key);
+        self.options.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.options.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The line sent over the TCP stream, including the newline.
+    pub fn to_line(&self) -> String {
+        let mut line = format!("stream {} {:02}s", self.mode, self.duration);
+        for (key, value) in &self.options {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+        line.push('\n');
+        line
+    }
+
+    pub fn parse(line: &str) -> Result<Handshake, String> {
+        let mut words = line.split_whitespace();
+
+        if words.next() != Some("stream") {
+            return Err("handshake must start with 'stream'".to_string());
+        }
+        let mode = words.next().ok_or("missing mode")?;
+
+        let time = words.next().ok_or("missing duration")?;
+        if !time.ends_with('s') {
+            return Err(format!("invalid duration: {}", time));
+        }
+        let duration = time[..time.len() - 1].parse()
+            .map_err(|_| format!("invalid duration: {}", time))?;
+
+        let mut handshake = Handshake::new(mode, duration);
+        for word in words {
+            let mut parts = word.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().ok_or(format!("invalid option: {}", word))?;
+            handshake = handshake.with(key, value);
+        }
+
+        Ok(handshake)
+    }
+}
+
+/// Reads one line of text, without the newline: the handshake, or what comes before it (see
+/// auth.rs). This reads byte by byte, so nothing past the newline is consumed.
+pub fn read_line<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+
+    loop {
+        reader.read_exact(&mut byte)?;
+        if byte[0] == b'\n' {
+            break;
+        }
+        line.push(byte[0]);
+        if line.len() > MAX_HANDSHAKE_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "handshake too long"));
+        }
+    }
+
+    String::from_utf8(line)
+        .map(|line| line.trim_end_matches('\r').to_string())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "handshake is not text"))
+}
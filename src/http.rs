@@ -0,0 +1,332 @@
+//! HTTP streaming of the live mic, Icecast style, so browsers and media players can listen.
+//!
+//! - `GET /stream.wav`: 16-bit PCM WAV.
+//! - `GET /stream-ulaw.wav`: G.711 µ-law WAV, half the bandwidth.
+//!
+//! Both are endless WAV streams (see wire.rs), sent with chunked transfer encoding to HTTP/1.1
+//! clients and as a plain body until the connection closes to HTTP/1.0 ones. For example
+//! `curl http://localhost:8000/stream.wav > out.wav`.
+//!
+//! `/` is the browser listener page, which connects back to `/ws` (see websocket.rs).
+//!
+//! Off by default (`http_port = 0`), and never TLS. With `auth_enabled`, the streams need
+//! `?user=<identity>&token=<hex>` (see auth.rs) from a user allowed the "mic" mode, and `/ws`
+//! logs in with the challenge like the TCP port does, before its handshake line.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::guard::{Deadline, Guard};
+use crate::live::Live;
+use crate::packet::Packet;
+use crate::wire;
+
+// The browser listener page
+const LISTEN_PAGE: &str = include_str!("../static/listen.html");
+
+const SAMPLE_RATE: u32 = 44_100;
+
+// Limits on what a client can send us before we answer, the timeout being for the whole request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_HEADER_LINES: usize = 100;
+const MAX_LINE_LENGTH: usize = 8192;
+
+// Before accepting again after an error (out of file descriptors...)
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+/// The request line and headers of an HTTP request.
+pub struct Request {
+    pub method: String,
+    /// Without the query string.
+    pub path: String,
+    /// The query string's "name=value" pairs, as they are (not percent-decoded).
+    pub query: Vec<(String, String)>,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+
+    pub fn read_from<R: BufRead>(reader: &mut R) -> io::Result<Request> {
+        let line = read_line(reader)?;
+        let mut parts = line.split_whitespace();
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) => (method, target, version),
+            _ => return Err(invalid("bad request line")),
+        };
+        let mut target = target.splitn(2, '?');
+        let path = target.next().unwrap_or("/").to_string();
+        let query = target.next().unwrap_or("")
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                (parts.next().unwrap_or("").to_string(), parts.next().unwrap_or("").to_string())
+            })
+            .collect();
+
+        let mut headers = Vec::new();
+        loop {
+            let line = read_line(reader)?;
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() >= MAX_HEADER_LINES {
+                return Err(invalid("too many headers"));
+            }
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next().unwrap_or("").trim().to_string();
+            let value = parts.next().ok_or_else(|| invalid("bad header"))?.trim().to_string();
+            headers.push((name, value));
+        }
+
+        Ok(Request {
+            method: method.to_string(),
+            path,
+            query,
+            version: version.to_string(),
+            headers,
+        })
+    }
+
+    /// Value of the header *name*, whatever its case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of the query parameter *name*.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query.iter()
+            .find(|(parameter, _)| parameter == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Whether a request may have the live stream: Ok, or why not.
+pub type Authorize = dyn Fn(&Request) -> Result<(), String> + Send + Sync;
+
+/// Serves HTTP on *port*, a thread per connection that *guard* lets in. The live streams only
+/// go to requests *authorize* accepts, WebSocket requests are handed over to *on_websocket*.
+/// Doesn't return unless the port can't be listened on.
+pub fn run(port: u16, live: Arc<Live>, guard: Arc<Guard>, authorize: Arc<Authorize>,
+           on_websocket: Arc<dyn Fn(TcpStream, Request) + Send + Sync>) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    println!("HTTP listening on port {}", port);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("HTTP: accept failed: {}", e);
+                std::thread::sleep(ACCEPT_RETRY);
+                continue;
+            }
+        };
+        let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+        let ticket = match guard.admit(stream.peer_addr().ok().map(|a| a.ip())) {
+            Ok(ticket) => ticket,
+            Err(e) => {
+                println!("HTTP: refused connection from {}: {}", peer, e);
+                continue;
+            }
+        };
+        let live = live.clone();
+        let authorize = authorize.clone();
+        let on_websocket = on_websocket.clone();
+        std::thread::spawn(move || {
+            let _ticket = ticket;
+            if let Err(e) = handle(stream, &peer, &live, &*authorize, &*on_websocket) {
+                println!("HTTP {}: {}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle(mut stream: TcpStream, peer: &str, live: &Live, authorize: &Authorize,
+          on_websocket: &dyn Fn(TcpStream, Request)) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let socket = stream.try_clone()?;
+    let deadline = Deadline::start(REQUEST_TIMEOUT, move || {
+        socket.shutdown(Shutdown::Both).ok();
+    });
+    let request = Request::read_from(&mut BufReader::new(stream.try_clone()?))?;
+    drop(deadline);
+    stream.set_read_timeout(None)?;
+    println!("HTTP {} {}", request.method, request.path);
+
+    if request.method != "GET" {
+        return respond(&mut stream, "405 Method Not Allowed", "text/plain", b"Only GET here.\n");
+    }
+    match request.path.as_str() {
+        "/" | "/listen.html" => respond(&mut stream, "200 OK", "text/html; charset=utf-8", LISTEN_PAGE.as_bytes()),
+        "/ws" => {
+            on_websocket(stream, request);
+            Ok(())
+        }
+        "/stream.wav" | "/stream-ulaw.wav" => {
+            if let Err(e) = authorize(&request) {
+                println!("HTTP: refused {} to {}: {}", request.path, peer, e);
+                return respond(&mut stream, "403 Forbidden", "text/plain", b"Forbidden.\n");
+            }
+            let ulaw = request.path == "/stream-ulaw.wav";
+            stream_live(stream, &request, live, ulaw)
+        }
+        _ => respond(&mut stream, "404 Not Found", "text/plain", b"Try /stream.wav\n"),
+    }
+}
+
+/// A complete (not streamed) response.
+pub fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, content_type, body.len());
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)
+}
+
+/// Streams the live mic as WAV until the client goes away.
+fn stream_live(mut stream: TcpStream, request: &Request, live: &Live, ulaw: bool) -> io::Result<()> {
+    let chunked = request.version == "HTTP/1.1";
+    let mut head = "HTTP/1.1 200 OK\r\nContent-Type: audio/wav\r\nCache-Control: no-cache\r\n\
+                    Connection: close\r\n".to_string();
+    if chunked {
+        head.push_str("Transfer-Encoding: chunked\r\n");
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.set_nodelay(true)?;
+
+    let header = if ulaw {
+        wire::wav_header(wire::FORMAT_MULAW, 8, SAMPLE_RATE)
+    } else {
+        wire::wav_header(wire::FORMAT_PCM, 16, SAMPLE_RATE)
+    };
+    write_body(&mut stream, &header, chunked)?;
+
+    let receiver = live.subscribe();
+    for packet in receiver {
+        if let Packet::Audio(samples) = packet {
+            let bytes = if ulaw {
+                samples.iter().map(|&s| mulaw(s)).collect()
+            } else {
+                wire::pcm_bytes(&samples, false)
+            };
+            write_body(&mut stream, &bytes, chunked)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_body(stream: &mut TcpStream, bytes: &[u8], chunked: bool) -> io::Result<()> {
+    if !chunked {
+        return stream.write_all(bytes);
+    }
+    // One write per chunk
+    let mut chunk = format!("{:X}\r\n", bytes.len()).into_bytes();
+    chunk.extend_from_slice(bytes);
+    chunk.extend_from_slice(b"\r\n");
+    stream.write_all(&chunk)
+}
+
+/// G.711 µ-law encoding of one sample.
+fn mulaw(sample: f32) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32635;
+
+    let mut pcm = (sample.clamp(-1.0, 1.0) * 32767.0) as i32;
+    let sign = if pcm < 0 { pcm = -pcm; 0x80 } else { 0 };
+    pcm = std::cmp::min(pcm, CLIP) + BIAS;
+
+    // Segment: position of the highest bit set among bits 7 to 14
+    let mut exponent = 7;
+    let mut mask = 0x4000;
+    while exponent > 0 && pcm & mask == 0 {
+        exponent -= 1;
+        mask >>= 1;
+    }
+    let mantissa = (pcm >> (exponent + 3)) & 0x0F;
+    !(sign | (exponent << 4) | mantissa) as u8
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    reader.by_ref().take(MAX_LINE_LENGTH as u64).read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Err(invalid("line too long or connection closed"));
+    }
+    let line = String::from_utf8(line).map_err(|_| invalid("request is not UTF-8"))?;
+    Ok(line.trim_end().to_string())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mulaw_known_values() {
+        assert_eq!(mulaw(0.0), 0xFF);
+        assert_eq!(mulaw(1.0), 0x80);
+        assert_eq!(mulaw(-1.0), 0x00);
+        // Clipped, not wrapped
+        assert_eq!(mulaw(2.0), 0x80);
+        assert_eq!(mulaw(-2.0), 0x00);
+    }
+
+    #[test]
+    fn mulaw_is_monotonic() {
+        // Positive codes go down from 0xFF as the level goes up
+        let mut previous = mulaw(0.0);
+        for i in 1..=1000 {
+            let code = mulaw(i as f32 / 1000.0);
+            assert!(code <= previous && code >= 0x80, "{} at {}", code, i);
+            previous = code;
+        }
+    }
+
+    #[test]
+    fn reads_request() {
+        let text = "GET /stream.wav?user=alice&token=00ff&flag HTTP/1.1\r\nHost: localhost\r\nupgrade:  websocket \r\n\r\nbody";
+        let mut reader = text.as_bytes();
+        let request = Request::read_from(&mut reader).unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/stream.wav");
+        assert_eq!(request.query("user"), Some("alice"));
+        assert_eq!(request.query("token"), Some("00ff"));
+        assert_eq!(request.query("flag"), Some(""));
+        assert_eq!(request.query("session"), None);
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.header("Host"), Some("localhost"));
+        assert_eq!(request.header("Upgrade"), Some("websocket"));
+        assert_eq!(request.header("Connection"), None);
+        // The body is left for whoever reads next
+        assert_eq!(reader, b"body");
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        let bad = [
+            "GET /\r\n\r\n",
+            "GET / HTTP/1.1\r\nno colon\r\n\r\n",
+            // Connection closed before the end of the headers
+            "GET / HTTP/1.1\r\nHost: localhost\r\n",
+        ];
+        for text in bad.iter() {
+            assert!(Request::read_from(&mut text.as_bytes()).is_err(), "{:?}", text);
+        }
+
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_LENGTH));
+        assert!(Request::read_from(&mut long.as_bytes()).is_err());
+
+        let many = format!("GET / HTTP/1.1\r\n{}\r\n", "X: y\r\n".repeat(MAX_HEADER_LINES + 1));
+        assert!(Request::read_from(&mut many.as_bytes()).is_err());
+    }
+}
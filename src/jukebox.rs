@@ -0,0 +1,274 @@
+//! Jukebox: a playlist streamed back-to-back to every listener of the "jukebox" mode.
+//!
+//! The playlist is either a directory (every audio file in it, in name order) or an M3U file.
+//! The jukebox runs on its own thread and broadcasts to all listeners. Tracks are gapless: when
+//! one ends halfway through a block, the rest of the block comes from the next one. Any listener
+//! can send "next", "prev", "queue <file>" and "shuffle on/off", and everyone gets a track
+//! packet when the track changes.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::broadcast::Broadcast;
+use crate::control::Control;
+use crate::packet::Packet;
+use crate::server::media_path;
+use crate::media::{self, MediaSource};
+
+const SAMPLE_RATE: f64 = 44_100.0;
+const BLOCK_LENGTH: usize = 250;
+
+// How far ahead of real time the jukebox runs
+const JUKEBOX_LEAD: Duration = Duration::from_millis(50);
+
+pub struct Playlist {
+    tracks: Vec<PathBuf>,
+    // Play order, indexes into tracks (shuffled or not)
+    order: Vec<usize>,
+    position: usize,
+    queue: VecDeque<PathBuf>,
+    seed: u32,
+}
+
+impl Playlist {
+
+    /// Loads a playlist from a directory of audio files or an M3U file.
+    pub fn load(path: &Path) -> Result<Playlist, String> {
+        let tracks = if path.is_dir() {
+            let mut tracks: Vec<PathBuf> = fs::read_dir(path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| p.is_file() && media::probe(p).is_some())
+                .collect();
+            tracks.sort();
+            tracks
+        } else {
+            let text = fs::read_to_string(path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            // Entries are relative to the M3U file
+            let base = path.parent().unwrap_or(Path::new("."));
+            text.lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| base.join(line))
+                .collect()
+        };
+
+        if tracks.is_empty() {
+            return Err(format!("{}: playlist is empty", path.display()));
+        }
+
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.subsec_nanos())
+            .unwrap_or(1) | 1;
+
+        Ok(Playlist {
+            order: (0..tracks.len()).collect(),
+            tracks,
+            position: 0,
+            queue: VecDeque::new(),
+            seed,
+        })
+    }
+
+    fn current(&self) -> &Path {
+        &self.tracks[self.order[self.position]]
+    }
+
+    /// Moves on to the next track, queued tracks first. Returns the track to play.
+    fn next(&mut self) -> PathBuf {
+        if let Some(track) = self.queue.pop_front() {
+            return track;
+        }
+        self.position = (self.position + 1) % self.order.len();
+        self.current().to_path_buf()
+    }
+
+    fn previous(&mut self) -> PathBuf {
+        self.position = (self.position + self.order.len() - 1) % self.order.len();
+        self.current().to_path_buf()
+    }
+
+    fn queue(&mut self, track: PathBuf) {
+        self.queue.push_back(track);
+    }
+
+    /// Shuffles everything after the current track, or goes back to playlist order.
+    fn shuffle(&mut self, shuffle: bool) {
+        let current = self.order[self.position];
+        if shuffle {
+            // Fisher-Yates with xorshift32
+            for i in (self.position + 2..self.order.len()).rev() {
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 17;
+                self.seed ^= self.seed << 5;
+                let span = i - self.position;
+                let j = self.position + 1 + self.seed as usize % span;
+                self.order.swap(i, j);
+            }
+        } else {
+            self.order = (0..self.tracks.len()).collect();
+            self.position = current;
+        }
+    }
+}
+
+pub struct Jukebox {
+    broadcast: Arc<Broadcast>,
+    controls: Mutex<Sender<Control>>,
+    now_playing: Arc<Mutex<String>>,
+}
+
+impl Jukebox {
+
+    /// Starts playing *playlist* on a new thread. Files queued by listeners are looked up in
+    /// *media_dir*.
+    pub fn start(playlist: Playlist, media_dir: PathBuf) -> Arc<Jukebox> {
+        let broadcast = Arc::new(Broadcast::new());
+        let (sender, receiver) = channel();
+        let now_playing = Arc::new(Mutex::new(String::new()));
+
+        let thread_broadcast = broadcast.clone();
+        let thread_now_playing = now_playing.clone();
+        std::thread::spawn(move || {
+            run(playlist, media_dir, receiver, thread_broadcast, thread_now_playing);
+        });
+
+        Arc::new(Jukebox {
+            broadcast,
+            controls: Mutex::new(sender),
+            now_playing,
+        })
+    }
+
+    /// New listener. The first packet is the track playing right now.
+    pub fn subscribe(&self) -> Receiver<Packet> {
+        let now_playing = self.now_playing.lock().unwrap().clone();
+        self.broadcast.subscribe_with(Packet::Track(now_playing))
+    }
+
+    /// Passes on a control message from a listener. Returns false if it isn't a jukebox one.
+    pub fn control(&self, control: &Control) -> bool {
+        match control {
+            Control::Next | Control::Previous | Control::Queue(_) | Control::Shuffle(_) => {
+                self.controls.lock().unwrap().send(control.clone()).ok();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The jukebox thread.
+fn run(mut playlist: Playlist, media_dir: PathBuf, controls: Receiver<Control>,
+       broadcast: Arc<Broadcast>, now_playing: Arc<Mutex<String>>) {
+    let mut block = [0.0f32; BLOCK_LENGTH];
+    let mut track = playlist.current().to_path_buf();
+    let mut source = open_track(&mut playlist, &mut track, &broadcast, &now_playing);
+
+    let mut start = Instant::now();
+    let mut frames_sent: u64 = 0;
+
+    loop {
+        // Nobody listening, no need to play
+        if broadcast.listener_count() == 0 {
+            std::thread::sleep(Duration::from_millis(100));
+            start = Instant::now();
+            frames_sent = 0;
+            continue;
+        }
+
+        while let Ok(control) = controls.try_recv() {
+            let skip_to = match control {
+                Control::Next => Some(playlist.next()),
+                Control::Previous => Some(playlist.previous()),
+                Control::Queue(name) => {
+                    match media_path(&media_dir, &name) {
+                        Ok(path) => {
+                            println!("Jukebox: queued {}", path.display());
+                            playlist.queue(path);
+                        }
+                        Err(e) => println!("Jukebox: {}", e),
+                    }
+                    None
+                }
+                Control::Shuffle(shuffle) => {
+                    playlist.shuffle(shuffle);
+                    None
+                }
+                _ => None,
+            };
+            if let Some(next) = skip_to {
+                track = next;
+                source = open_track(&mut playlist, &mut track, &broadcast, &now_playing);
+            }
+        }
+
+        // Fill the block, carrying on into the next track if this one ends (gapless)
+        let mut filled = 0;
+        let mut skipped = 0;
+        while filled < BLOCK_LENGTH {
+            let len = match source.as_mut() {
+                Some(source) if skipped <= playlist.tracks.len() => {
+                    source.read(&mut block[filled..]).unwrap_or_else(|e| {
+                        println!("Jukebox: error reading {}: {}", track.display(), e);
+                        0
+                    })
+                }
+                _ => {
+                    // Nothing playable, send silence
+                    for sample in block[filled..].iter_mut() {
+                        *sample = 0.0;
+                    }
+                    BLOCK_LENGTH - filled
+                }
+            };
+            filled += len;
+            if filled < BLOCK_LENGTH {
+                track = playlist.next();
+                source = open_track(&mut playlist, &mut track, &broadcast, &now_playing);
+                skipped += 1;
+            }
+        }
+
+        broadcast.send(&Packet::Audio(block.to_vec()));
+        frames_sent += BLOCK_LENGTH as u64;
+
+        // Pace at real time
+        let due = Duration::from_secs_f64(frames_sent as f64 / SAMPLE_RATE);
+        let elapsed = start.elapsed() + JUKEBOX_LEAD;
+        if due > elapsed {
+            std::thread::sleep(due - elapsed);
+        }
+    }
+}
+
+/// Opens *track*, skipping to the next ones if it can't be read, and lets everyone know what is
+/// playing. Returns None only if no track in the playlist can be opened.
+fn open_track(playlist: &mut Playlist, track: &mut PathBuf, broadcast: &Broadcast,
+              now_playing: &Mutex<String>) -> Option<MediaSource> {
+    for _ in 0..=playlist.tracks.len() + playlist.queue.len() {
+        match MediaSource::open(track, SAMPLE_RATE, None) {
+            Ok(source) => {
+                let title = track.file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                println!("Jukebox: now playing {}", title);
+                *now_playing.lock().unwrap() = title.clone();
+                broadcast.send(&Packet::Track(title));
+                return Some(source);
+            }
+            Err(e) => {
+                println!("Jukebox: skipping {}: {}", track.display(), e);
+                *track = playlist.next();
+            }
+        }
+    }
+    println!("Jukebox: nothing in the playlist can be played.");
+    None
+}
@@ -0,0 +1,115 @@
+//! The live mic: one capture shared by every listener that isn't a `mic` session of its own
+//! (HTTP, WebSocket...).
+//!
+//! The input is only open while someone is listening (the instant replay and the archive count
+//! as listeners, so with either of them on it is open all the time). Capture goes through the
+//! AGC and the limiter once, and is then broadcast to all listeners.
+
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::Duration;
+
+extern crate portaudio;
+use portaudio as pa;
+
+use crate::broadcast::Broadcast;
+use crate::packet::Packet;
+use crate::processing::{AgcSettings, Processor};
+
+const RINGBUFFER_SIZE: usize = 5000;
+
+const SAMPLE_RATE: f64 = 44_100.0;
+const CHANNELS: i32 = 1;
+const INTERLEAVED: bool = true;
+const INPUT_FRAMES_PER_BUFFER: u32 = 256;
+const BLOCK_LENGTH: usize = 250;
+
+pub struct Live {
+    broadcast: Arc<Broadcast>,
+}
+
+impl Live {
+
+    /// Starts the capture thread, it opens the input when the first listener comes.
+    pub fn start(agc: AgcSettings) -> Arc<Live> {
+        let broadcast = Arc::new(Broadcast::new());
+
+        let thread_broadcast = broadcast.clone();
+        std::thread::spawn(move || {
+            run(&thread_broadcast, &agc);
+        });
+
+        Arc::new(Live { broadcast })
+    }
+
+    pub fn subscribe(&self) -> Receiver<Packet> {
+        self.broadcast.subscribe()
+    }
+}
+
+/// The capture thread.
+fn run(broadcast: &Broadcast, agc: &AgcSettings) {
+    loop {
+        if broadcast.listener_count() == 0 {
+            std::thread::sleep(Duration::from_millis(100));
+            continue;
+        }
+        println!("Live: starting capture.");
+        match capture(broadcast, agc) {
+            Ok(()) => println!("Live: nobody listening, capture stopped."),
+            Err(e) => {
+                println!("Live: capture failed: {}", e);
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+/// Captures and broadcasts until there are no listeners left.
+fn capture(broadcast: &Broadcast, agc: &AgcSettings) -> Result<(), pa::Error> {
+    let pa = pa::PortAudio::new()?;
+
+    let def_input = pa.default_input_device()?;
+    let input_info = pa.device_info(def_input)?;
+    let latency = input_info.default_low_input_latency;
+    let input_params = pa::StreamParameters::<f32>::new(
+        def_input, CHANNELS, INTERLEAVED, latency);
+    pa.is_input_format_supported(input_params, SAMPLE_RATE)?;
+    let input_settings = pa::InputStreamSettings::new(
+        input_params, SAMPLE_RATE, INPUT_FRAMES_PER_BUFFER);
+
+    let (mut rb_producer, mut rb_consumer)
+        = ringbuf::RingBuffer::<f32>::new(RINGBUFFER_SIZE).split();
+
+    let mut processor = Processor::with_agc(agc);
+    let mut processed = vec![0.0; INPUT_FRAMES_PER_BUFFER as usize];
+
+    let input_stream_callback = move |pa::InputStreamCallbackArgs {
+                                          buffer,
+                                          frames,
+                                          ..
+                                      }| {
+        let processed = &mut processed[..frames];
+        processed.copy_from_slice(buffer);
+        processor.process(processed);
+        // If the broadcasting falls behind, drop capture rather than block the callback
+        rb_producer.push_slice(processed);
+        pa::Continue
+    };
+
+    let mut input_stream = pa.open_non_blocking_stream(input_settings, input_stream_callback)?;
+    input_stream.start()?;
+
+    let mut block = [0.0f32; BLOCK_LENGTH];
+    while broadcast.listener_count() > 0 && input_stream.is_active()? {
+        if rb_consumer.len() < BLOCK_LENGTH {
+            std::thread::sleep(Duration::from_millis(1));
+            continue;
+        }
+        rb_consumer.pop_slice(&mut block);
+        broadcast.send(&Packet::Audio(block.to_vec()));
+    }
+
+    input_stream.stop()?;
+    Ok(())
+}
@@ -4,10 +4,38 @@ mod wav;
 mod beep;
 mod audio_stream;
 mod audio_buffer;
+mod control;
+mod processing;
+mod config;
+mod packet;
+mod vad;
+mod echo;
+mod handshake;
+mod generator;
+mod session;
+mod recorder;
+mod broadcast;
+mod jukebox;
+mod replay;
+mod archive;
+mod media;
+mod wire;
+mod live;
+mod http;
+mod websocket;
+mod transport;
+mod tls;
+mod auth;
+mod e2e;
+mod guard;
+mod relay;
+mod mixer;
+mod rooms;
 
-use std::thread;
 use std::env;
 
+use transport::Address;
+
 const BEEP_TEST:bool = false;
 const STREAM_TEST:bool = false;
 const CLIENT_SERVER_TEST:bool = true;
@@ -17,35 +45,107 @@ const CLIENT_SERVER_TEST:bool = true;
 fn main() {
 
     //=========================================
-    // Set parameters getting arguments: [mic/sin mode, num seconds]
+    // Set parameters getting arguments:
+    // [mic/sin/file/jukebox/replay/relay/upload/duplex/mix/rooms mode, num seconds, options...]
+    // e.g. "sin 10 gen=logsweep freq=20 to=20000 level=-12" or "file 0 file=song.wav"
     let args: Vec<String> = env::args().collect();
 
-    let mic_mode;
+    let mode;
     let duration;
-    if (args.len() == 2) {
+    if args.len() >= 3 {
         let arg_mode = &args[1];
         let arg_num_seconds = &args[2];
 
-        // Mic/Sin mode argument
+        // Mic/Sin/File mode argument
         if arg_mode.contains("sin") {
-            mic_mode = false;
+            mode = "sin";
+        } else if arg_mode.contains("file") {
+            mode = "file";
+        } else if arg_mode.contains("replay") {
+            mode = "replay";
+        } else if arg_mode.contains("jukebox") {
+            mode = "jukebox";
+        } else if arg_mode.contains("relay") {
+            mode = "relay";
+        } else if arg_mode.contains("duplex") {
+            mode = "duplex";
+        } else if arg_mode.contains("upload") {
+            mode = "upload";
+        } else if arg_mode.contains("mix") {
+            mode = "mix";
+        } else if arg_mode.contains("rooms") {
+            mode = "rooms";
         } else if arg_mode.contains("mic") {
-            mic_mode = true;
+            mode = "mic";
         } else {
             // Mic by default.
-            mic_mode = true;
+            mode = "mic";
         }
         // Duration argument
-        if let s = arg_num_seconds.parse::<u32>() {
-            duration = std::cmp::min::<u32>(s.unwrap(), 99); // Arg should not be 3-digits
+        if let Ok(s) = arg_num_seconds.parse::<u32>() {
+            duration = s;
         } else {
             duration = 10;
         }
     } else {
-        mic_mode = true;
+        mode = "mic";
         duration = 10;
     }
 
+    // Any "key=value" argument after that is passed on in the handshake,
+    // except for the client's own options: "record=<file.wav>" and "play=false",
+    // "address=<unix:/path|channel:name|host:port>" for where the server listens, and
+    // "tls_ca=<ca.crt>" (with "tls_cert", "tls_key" and "tls_name" if needed) to connect with TLS,
+    // "user=<identity> psk=<key>" to log in, and "e2e=true" for end-to-end encryption, or
+    // "e2e_group_key=<key>" for the group's (see e2e.rs), and "echo_filter_length=<samples>" for
+    // the duplex mode's echo canceller (see echo.rs).
+    let mut handshake = handshake::Handshake::new(mode, duration);
+    let mut client_options = client::ClientOptions::default();
+    let mut address = None;
+    let (mut tls_ca, mut tls_cert, mut tls_key, mut tls_name) = (None, None, None, None);
+    let (mut user, mut psk) = (None, None);
+    for arg in args.iter().skip(3) {
+        let mut parts = arg.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("record"), Some(path)) => client_options.record_path = Some(path.into()),
+            (Some("play"), Some(play)) => client_options.play = play != "false",
+            (Some("address"), Some(value)) => match Address::parse(value) {
+                Ok(parsed) => address = Some(parsed),
+                Err(e) => println!("{}", e),
+            },
+            (Some("tls_ca"), Some(path)) => tls_ca = Some(path.into()),
+            (Some("tls_cert"), Some(path)) => tls_cert = Some(path.into()),
+            (Some("tls_key"), Some(path)) => tls_key = Some(path.into()),
+            (Some("tls_name"), Some(name)) => tls_name = Some(name.to_string()),
+            (Some("user"), Some(identity)) => user = Some(identity.to_string()),
+            (Some("psk"), Some(key)) => psk = Some(key.to_string()),
+            (Some("e2e"), Some(e2e)) => client_options.e2e = e2e != "false",
+            (Some("e2e_group_key"), Some(key)) => client_options.group_key = Some(key.to_string()),
+            (Some("echo_filter_length"), Some(length)) => match length.parse() {
+                Ok(length) => client_options.echo_filter_length = length,
+                Err(_) => println!("Invalid echo_filter_length: {}", length),
+            },
+            (Some(key), Some(value)) => handshake = handshake.with(key, value),
+            _ => {}
+        }
+    }
+
+    client_options.tls = tls_ca.map(|ca| tls::ClientSettings {
+        ca,
+        cert: tls_cert,
+        key: tls_key,
+        server_name: tls_name,
+    });
+    if let (Some(identity), Some(key)) = (user, psk) {
+        client_options.credentials = Some(auth::Credentials { identity, key });
+    }
+
+    // Both ends use the given address, otherwise TCP on port 3333
+    let (server_address, client_address) = match address {
+        Some(address) => (address.clone(), address),
+        None => (Address::Tcp("0.0.0.0:3333".to_string()), Address::Tcp("localhost:3333".to_string())),
+    };
+
     //=========================================
 
     // TEST: Output a sine wave using PortAudio
@@ -60,7 +160,9 @@ fn main() {
         println!("Testing stream.");
 
         std::thread::spawn(|| {
-            audio_stream::audio_stream_test(5.0);
+            if let Err(e) = audio_stream::audio_stream_test(5.0) {
+                println!("Stream test failed: {}", e);
+            }
         });
 
         std::thread::sleep(std::time::Duration::from_millis(100));
@@ -74,15 +176,19 @@ fn main() {
         std::thread::sleep(std::time::Duration::from_millis(100));
 
         println!("Running server.");
-        let server_handle = std::thread::spawn(|| {
-            server::run_server();
+        let server_handle = std::thread::spawn(move || {
+            if let Err(e) = server::run_server(&server_address) {
+                println!("Server failed: {}", e);
+            }
         });
 
         std::thread::sleep(std::time::Duration::from_millis(100));
 
         println!("Running client.");
         let client_handle = std::thread::spawn(move || {
-            client::run_client(mic_mode, duration.clone() as i32);
+            if let Err(e) = client::run_client(&client_address, handshake, client_options) {
+                println!("Client failed: {}", e);
+            }
         });
 
         // ========================
@@ -105,6 +211,8 @@ fn main() {
     }
 }
 
+// Not called any more, kept from before the client and server used ringbuf
+#[allow(dead_code)]
 fn test_audio_buffer() {
     //=======================================
     // Test 1 -> normal write and read
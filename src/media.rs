@@ -0,0 +1,809 @@
+//! Audio files for the file source and the recordings.
+//!
+//! `MediaSource` reads WAV, AIFF/AIFC, Ogg Vorbis and MP3 files, and headerless raw PCM given a
+//! format descriptor. The format is told by the first bytes of the file, not its extension.
+//! Whatever the file, it comes out as mono f32 at the stream's sample rate: channels are averaged
+//! together and the sample rate is converted with linear interpolation.
+//!
+//! Vorbis and MP3 decoding use pure-Rust decoders, behind the "vorbis" and "mp3" features (both
+//! on by default).
+//!
+//! Raw PCM descriptors are "<encoding>:<channels>:<sample rate>", e.g. "s16le:2:48000", with
+//! encodings u8, s8, s16le, s16be, s24le, s24be, s32le, s32be, f32le and f32be.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// The most 16-bit mono frames the sizes in an AIFF header can count (~13.5 hours at 44.1K)
+const MAX_AIFF_FRAMES: u32 = (u32::MAX - 46) / 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Wav,
+    Aiff,
+    Vorbis,
+    Mp3,
+}
+
+/// Tells the format from the first bytes of a file, None if it isn't one we know.
+pub fn detect(header: &[u8]) -> Option<Format> {
+    if header.len() >= 12 && &header[8..12] == b"WAVE"
+        && (&header[..4] == b"RIFF" || &header[..4] == b"RIFX" || &header[..4] == b"RF64") {
+        Some(Format::Wav)
+    } else if header.len() >= 12 && &header[..4] == b"FORM"
+        && (&header[8..12] == b"AIFF" || &header[8..12] == b"AIFC") {
+        Some(Format::Aiff)
+    } else if header.len() >= 35 && &header[..4] == b"OggS" && &header[29..35] == b"vorbis" {
+        // The first packet of the first page is the Vorbis identification header
+        Some(Format::Vorbis)
+    } else if header.len() >= 3 && &header[..3] == b"ID3" {
+        Some(Format::Mp3)
+    } else if header.len() >= 2 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0 {
+        // MPEG audio frame sync
+        Some(Format::Mp3)
+    } else {
+        None
+    }
+}
+
+/// Reads the start of the file at *path* and tells its format.
+pub fn probe(path: &Path) -> Option<Format> {
+    let mut header = [0u8; 64];
+    let mut file = File::open(path).ok()?;
+    let mut len = 0;
+    while len < header.len() {
+        match file.read(&mut header[len..]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => len += n,
+        }
+    }
+    detect(&header[..len])
+}
+
+/// Layout of PCM samples: in raw files, and in AIFF once the header is read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PcmFormat {
+    pub bits: u16,
+    pub float: bool,
+    pub signed: bool,
+    pub big_endian: bool,
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+impl PcmFormat {
+
+    /// Parses a raw PCM descriptor, "s16le:2:48000".
+    pub fn parse(descriptor: &str) -> Result<PcmFormat, String> {
+        let invalid = || format!("invalid PCM format '{}', expected e.g. s16le:2:48000", descriptor);
+        let mut parts = descriptor.split(':');
+        let encoding = parts.next().ok_or_else(invalid)?;
+        let channels: u16 = parts.next().and_then(|c| c.parse().ok()).ok_or_else(invalid)?;
+        let sample_rate: u32 = parts.next().and_then(|r| r.parse().ok()).ok_or_else(invalid)?;
+        if parts.next().is_some() || channels == 0 || sample_rate == 0 {
+            return Err(invalid());
+        }
+
+        let (bits, float, signed, big_endian) = match encoding {
+            "u8" => (8, false, false, false),
+            "s8" => (8, false, true, false),
+            "s16le" => (16, false, true, false),
+            "s16be" => (16, false, true, true),
+            "s24le" => (24, false, true, false),
+            "s24be" => (24, false, true, true),
+            "s32le" => (32, false, true, false),
+            "s32be" => (32, false, true, true),
+            "f32le" => (32, true, true, false),
+            "f32be" => (32, true, true, true),
+            _ => return Err(invalid()),
+        };
+
+        Ok(PcmFormat { bits, float, signed, big_endian, channels, sample_rate })
+    }
+
+    fn sample_bytes(&self) -> usize {
+        (self.bits as usize).div_ceil(8)
+    }
+
+    fn frame_bytes(&self) -> usize {
+        self.sample_bytes() * self.channels as usize
+    }
+
+    /// Decodes the sample in *bytes* (sample_bytes() long).
+    fn sample(&self, bytes: &[u8]) -> f32 {
+        // Assemble most significant byte first
+        let mut raw: u32 = 0;
+        for i in 0..bytes.len() {
+            let byte = if self.big_endian { bytes[i] } else { bytes[bytes.len() - 1 - i] };
+            raw = (raw << 8) | byte as u32;
+        }
+
+        if self.float {
+            f32::from_bits(raw)
+        } else if !self.signed {
+            let half = (1u32 << (self.bits - 1)) as f32;
+            (raw as f32 - half) / half
+        } else {
+            // Sign extend
+            let shift = 32 - self.bits as u32;
+            let value = ((raw << shift) as i32) >> shift;
+            value as f32 / (1u64 << (self.bits - 1)) as f32
+        }
+    }
+}
+
+/// Anything that gives frames of a file.
+trait Decoder {
+    fn sample_rate(&self) -> u32;
+    /// Length in frames, if the file says.
+    fn frames(&self) -> Option<u64>;
+    /// Next frame mixed down to mono, None at the end of the file.
+    fn next_frame(&mut self) -> Result<Option<f32>, Box<dyn Error>>;
+    fn seek(&mut self, frame: u64) -> Result<(), Box<dyn Error>>;
+}
+
+/// A file of any supported format, as mono f32 samples at a given sample rate.
+pub struct MediaSource {
+    decoder: Box<dyn Decoder>,
+    // File frames per output sample, at normal rate
+    base_step: f64,
+    step: f64,
+    position: f64,
+    previous: f32,
+    next: f32,
+    finished: bool,
+}
+
+impl MediaSource {
+
+    /// Opens *path*, in the format the file starts with. Raw PCM has no header to tell, so it
+    /// needs its format given in *raw*.
+    pub fn open(path: &Path, sample_rate: f64, raw: Option<&PcmFormat>)
+        -> Result<MediaSource, Box<dyn Error>> {
+        let decoder: Box<dyn Decoder> = match raw {
+            Some(format) => Box::new(PcmDecoder::raw(path, *format)?),
+            None => match probe(path) {
+                Some(Format::Wav) => Box::new(WavDecoder::open(path)?),
+                Some(Format::Aiff) => Box::new(PcmDecoder::aiff(path)?),
+                Some(Format::Vorbis) => open_vorbis(path)?,
+                Some(Format::Mp3) => open_mp3(path)?,
+                None => return Err(format!("{}: unknown file format (raw PCM needs format=...)",
+                                           path.display()).into()),
+            },
+        };
+        println!("Opened {}: sample rate: {}", path.display(), decoder.sample_rate());
+
+        let step = decoder.sample_rate() as f64 / sample_rate;
+        let mut source = MediaSource {
+            decoder,
+            base_step: step,
+            step,
+            position: 0.0,
+            previous: 0.0,
+            next: 0.0,
+            finished: false,
+        };
+        source.prime()?;
+        Ok(source)
+    }
+
+    /// Length of the file in seconds, if known.
+    pub fn duration_secs(&self) -> Option<f64> {
+        self.decoder.frames().map(|frames| frames as f64 / self.decoder.sample_rate() as f64)
+    }
+
+    /// Fills *buffer*, returns how many samples were written. Less than buffer.len() means the
+    /// end of the file was reached.
+    pub fn read(&mut self, buffer: &mut [f32]) -> Result<usize, Box<dyn Error>> {
+        for (index, sample) in buffer.iter_mut().enumerate() {
+            while self.position >= 1.0 {
+                self.position -= 1.0;
+                self.previous = self.next;
+                match self.decoder.next_frame()? {
+                    Some(frame) => self.next = frame,
+                    None => {
+                        self.finished = true;
+                        break;
+                    }
+                }
+            }
+            if self.finished {
+                return Ok(index);
+            }
+
+            *sample = self.previous + (self.next - self.previous) * self.position as f32;
+            self.position += self.step;
+        }
+        Ok(buffer.len())
+    }
+
+    /// Jumps to *seconds* from the start of the file (the end if that's past it).
+    pub fn seek(&mut self, seconds: f64) -> Result<(), Box<dyn Error>> {
+        let mut frame = (seconds.max(0.0) * self.decoder.sample_rate() as f64) as u64;
+        if let Some(frames) = self.decoder.frames() {
+            frame = std::cmp::min(frame, frames);
+        }
+        self.decoder.seek(frame)?;
+        self.prime()
+    }
+
+    /// Playback rate, 1.0 is normal speed. The pitch changes with it.
+    pub fn set_rate(&mut self, rate: f64) {
+        self.step = self.base_step * rate;
+    }
+
+    /// Reads the first two frames to interpolate between.
+    fn prime(&mut self) -> Result<(), Box<dyn Error>> {
+        self.position = 0.0;
+        self.finished = false;
+        match self.decoder.next_frame()? {
+            Some(frame) => self.previous = frame,
+            None => self.finished = true,
+        }
+        self.next = self.decoder.next_frame()?.unwrap_or(self.previous);
+        Ok(())
+    }
+}
+
+/// WAV, any bit depth and channel count hound reads.
+struct WavDecoder {
+    reader: hound::WavReader<BufReader<File>>,
+    spec: hound::WavSpec,
+    scale: f32,
+}
+
+impl WavDecoder {
+    fn open(path: &Path) -> Result<WavDecoder, hound::Error> {
+        let reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        Ok(WavDecoder {
+            reader,
+            spec,
+            // Integer samples go from -2^(bits-1) to 2^(bits-1)
+            scale: 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32,
+        })
+    }
+}
+
+impl Decoder for WavDecoder {
+    fn sample_rate(&self) -> u32 {
+        self.spec.sample_rate
+    }
+
+    fn frames(&self) -> Option<u64> {
+        Some(self.reader.duration() as u64)
+    }
+
+    fn next_frame(&mut self) -> Result<Option<f32>, Box<dyn Error>> {
+        let channels = self.spec.channels as usize;
+        let mut sum = 0.0;
+
+        for _ in 0..channels {
+            let sample = match self.spec.sample_format {
+                hound::SampleFormat::Float => self.reader.samples::<f32>().next(),
+                hound::SampleFormat::Int => self.reader.samples::<i32>().next()
+                    .map(|s| s.map(|s| s as f32 * self.scale)),
+            };
+            match sample {
+                Some(sample) => sum += sample?,
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(sum / channels as f32))
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), Box<dyn Error>> {
+        self.reader.seek(frame as u32)?;
+        Ok(())
+    }
+}
+
+/// Uncompressed PCM at a known place in a file: raw files, and the sound data of AIFF.
+struct PcmDecoder {
+    reader: BufReader<File>,
+    format: PcmFormat,
+    data_start: u64,
+    frames: Option<u64>,
+    frames_read: u64,
+    frame: Vec<u8>,
+}
+
+impl PcmDecoder {
+
+    fn raw(path: &Path, format: PcmFormat) -> io::Result<PcmDecoder> {
+        let file = File::open(path)?;
+        let frames = file.metadata()?.len() / format.frame_bytes() as u64;
+        Ok(PcmDecoder::new(BufReader::new(file), format, 0, Some(frames)))
+    }
+
+    /// Reads the AIFF (or uncompressed AIFC) header, and gets ready to read the sound data.
+    fn aiff(path: &Path) -> Result<PcmDecoder, Box<dyn Error>> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut form = [0u8; 12];
+        reader.read_exact(&mut form)?;
+        let aifc = &form[8..12] == b"AIFC";
+
+        let mut format = None;
+        let mut frames = 0;
+        let mut data_start = None;
+
+        // Walk the chunks until we have both the format and the sound data
+        while format.is_none() || data_start.is_none() {
+            let mut header = [0u8; 8];
+            if reader.read_exact(&mut header).is_err() {
+                break;
+            }
+            let size = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as u64;
+            let chunk_start = reader.stream_position()?;
+
+            match &header[..4] {
+                b"COMM" => {
+                    let mut comm = vec![0u8; size as usize];
+                    reader.read_exact(&mut comm)?;
+                    if comm.len() < 18 {
+                        return Err("bad AIFF COMM chunk".into());
+                    }
+                    let channels = u16::from_be_bytes([comm[0], comm[1]]);
+                    frames = u32::from_be_bytes([comm[2], comm[3], comm[4], comm[5]]) as u64;
+                    let bits = u16::from_be_bytes([comm[6], comm[7]]);
+                    let sample_rate = extended_to_f64(&comm[8..18]) as u32;
+
+                    // AIFC says how the samples are stored, AIFF is always big-endian integers
+                    let compression = if aifc && comm.len() >= 22 { &comm[18..22] } else { b"NONE" };
+                    let (float, big_endian) = match compression {
+                        b"NONE" | b"twos" => (false, true),
+                        b"sowt" => (false, false),
+                        b"fl32" | b"FL32" => (true, true),
+                        other => return Err(format!("unsupported AIFC compression '{}'",
+                                                    String::from_utf8_lossy(other)).into()),
+                    };
+                    if channels == 0 || bits == 0 || bits > 32 || sample_rate == 0 {
+                        return Err("bad AIFF format".into());
+                    }
+                    format = Some(PcmFormat { bits, float, signed: true, big_endian, channels, sample_rate });
+                }
+                b"SSND" => {
+                    let mut ssnd = [0u8; 4];
+                    reader.read_exact(&mut ssnd)?;
+                    let offset = u32::from_be_bytes(ssnd) as u64;
+                    data_start = Some(chunk_start + 8 + offset);
+                }
+                _ => {}
+            }
+
+            // Chunks are padded to an even size
+            reader.seek(SeekFrom::Start(chunk_start + size + (size & 1)))?;
+        }
+
+        match (format, data_start) {
+            (Some(format), Some(data_start)) => {
+                reader.seek(SeekFrom::Start(data_start))?;
+                Ok(PcmDecoder::new(reader, format, data_start, Some(frames)))
+            }
+            _ => Err("AIFF file without COMM or SSND chunk".into()),
+        }
+    }
+
+    fn new(reader: BufReader<File>, format: PcmFormat, data_start: u64, frames: Option<u64>) -> PcmDecoder {
+        PcmDecoder {
+            reader,
+            format,
+            data_start,
+            frames,
+            frames_read: 0,
+            frame: vec![0; format.frame_bytes()],
+        }
+    }
+}
+
+impl Decoder for PcmDecoder {
+    fn sample_rate(&self) -> u32 {
+        self.format.sample_rate
+    }
+
+    fn frames(&self) -> Option<u64> {
+        self.frames
+    }
+
+    fn next_frame(&mut self) -> Result<Option<f32>, Box<dyn Error>> {
+        if self.frames.map(|frames| self.frames_read >= frames).unwrap_or(false) {
+            return Ok(None);
+        }
+        match self.reader.read_exact(&mut self.frame) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        self.frames_read += 1;
+
+        let sum: f32 = self.frame
+            .chunks_exact(self.format.sample_bytes())
+            .map(|bytes| self.format.sample(bytes))
+            .sum();
+        Ok(Some(sum / self.format.channels as f32))
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), Box<dyn Error>> {
+        self.reader.seek(SeekFrom::Start(self.data_start + frame * self.format.frame_bytes() as u64))?;
+        self.frames_read = frame;
+        Ok(())
+    }
+}
+
+/// 80-bit IEEE extended float, as AIFF stores its sample rate.
+fn extended_to_f64(bytes: &[u8]) -> f64 {
+    let exponent = (((bytes[0] & 0x7F) as i32) << 8) | bytes[1] as i32;
+    let mut mantissa: u64 = 0;
+    for &byte in &bytes[2..10] {
+        mantissa = (mantissa << 8) | byte as u64;
+    }
+    let value = mantissa as f64 * 2f64.powi(exponent - 16383 - 63);
+    if bytes[0] & 0x80 != 0 { -value } else { value }
+}
+
+fn f64_to_extended(value: f64) -> [u8; 10] {
+    let mut bytes = [0u8; 10];
+    let integer = value as u64;
+    if integer == 0 {
+        return bytes;
+    }
+    // Normalized: the top bit of the mantissa is set
+    let shift = integer.leading_zeros();
+    let exponent = (16383 + 63 - shift) as u16;
+    bytes[..2].copy_from_slice(&exponent.to_be_bytes());
+    bytes[2..].copy_from_slice(&(integer << shift).to_be_bytes());
+    bytes
+}
+
+#[cfg(feature = "vorbis")]
+fn open_vorbis(path: &Path) -> Result<Box<dyn Decoder>, Box<dyn Error>> {
+    Ok(Box::new(VorbisDecoder::open(path)?))
+}
+
+#[cfg(not(feature = "vorbis"))]
+fn open_vorbis(path: &Path) -> Result<Box<dyn Decoder>, Box<dyn Error>> {
+    Err(format!("{}: built without Ogg Vorbis support", path.display()).into())
+}
+
+#[cfg(feature = "mp3")]
+fn open_mp3(path: &Path) -> Result<Box<dyn Decoder>, Box<dyn Error>> {
+    Ok(Box::new(Mp3Decoder::open(path)?))
+}
+
+#[cfg(not(feature = "mp3"))]
+fn open_mp3(path: &Path) -> Result<Box<dyn Decoder>, Box<dyn Error>> {
+    Err(format!("{}: built without MP3 support", path.display()).into())
+}
+
+/// Ogg Vorbis, decoded a packet at a time.
+#[cfg(feature = "vorbis")]
+struct VorbisDecoder {
+    reader: lewton::inside_ogg::OggStreamReader<BufReader<File>>,
+    channels: usize,
+    // Interleaved samples of the last packet, and how far we are in it
+    packet: Vec<i16>,
+    packet_position: usize,
+}
+
+#[cfg(feature = "vorbis")]
+impl VorbisDecoder {
+    fn open(path: &Path) -> Result<VorbisDecoder, Box<dyn Error>> {
+        let reader = lewton::inside_ogg::OggStreamReader::new(BufReader::new(File::open(path)?))?;
+        let channels = reader.ident_hdr.audio_channels as usize;
+        Ok(VorbisDecoder {
+            reader,
+            channels,
+            packet: Vec::new(),
+            packet_position: 0,
+        })
+    }
+}
+
+#[cfg(feature = "vorbis")]
+impl Decoder for VorbisDecoder {
+    fn sample_rate(&self) -> u32 {
+        self.reader.ident_hdr.audio_sample_rate
+    }
+
+    fn frames(&self) -> Option<u64> {
+        // Would need reading the last page
+        None
+    }
+
+    fn next_frame(&mut self) -> Result<Option<f32>, Box<dyn Error>> {
+        // Packets can be empty, keep going until there's something
+        while self.packet_position + self.channels > self.packet.len() {
+            match self.reader.read_dec_packet_itl()? {
+                Some(packet) => {
+                    self.packet = packet;
+                    self.packet_position = 0;
+                }
+                None => return Ok(None),
+            }
+        }
+
+        let frame = &self.packet[self.packet_position..self.packet_position + self.channels];
+        self.packet_position += self.channels;
+        let sum: f32 = frame.iter().map(|&s| s as f32 / 32768.0).sum();
+        Ok(Some(sum / self.channels as f32))
+    }
+
+    /// Seeks to the page holding *frame*, so it's only as precise as the pages are long.
+    fn seek(&mut self, frame: u64) -> Result<(), Box<dyn Error>> {
+        self.reader.seek_absgp_pg(frame)?;
+        self.packet.clear();
+        self.packet_position = 0;
+        Ok(())
+    }
+}
+
+/// MP3, decoded a frame (1152 samples) at a time.
+#[cfg(feature = "mp3")]
+struct Mp3Decoder {
+    path: std::path::PathBuf,
+    decoder: puremp3::Mp3Decoder<BufReader<File>>,
+    sample_rate: u32,
+    // Mono samples of the last MP3 frame, and how far we are in it
+    samples: Vec<f32>,
+    position: usize,
+}
+
+#[cfg(feature = "mp3")]
+impl Mp3Decoder {
+    fn open(path: &Path) -> Result<Mp3Decoder, Box<dyn Error>> {
+        let mut decoder = Mp3Decoder {
+            path: path.to_path_buf(),
+            decoder: puremp3::Mp3Decoder::new(BufReader::new(File::open(path)?)),
+            sample_rate: 0,
+            samples: Vec::new(),
+            position: 0,
+        };
+        // The sample rate comes with the first frame
+        if !decoder.decode_frame()? {
+            return Err(format!("{}: no MP3 frames", path.display()).into());
+        }
+        Ok(decoder)
+    }
+
+    /// Decodes the next MP3 frame into *samples*. Returns false at the end of the file.
+    fn decode_frame(&mut self) -> Result<bool, Box<dyn Error>> {
+        let frame = match self.decoder.next_frame() {
+            Ok(frame) => frame,
+            Err(puremp3::Error::IoError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(false);
+            }
+            Err(e) => return Err(format!("{}", e).into()),
+        };
+
+        self.sample_rate = frame.header.sample_rate.hz();
+        let channels = frame.header.channels.num_channels();
+        self.samples.clear();
+        for i in 0..frame.num_samples {
+            let sum: f32 = (0..channels).map(|channel| frame.samples[channel][i]).sum();
+            self.samples.push(sum / channels as f32);
+        }
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+#[cfg(feature = "mp3")]
+impl Decoder for Mp3Decoder {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn frames(&self) -> Option<u64> {
+        // Not without decoding the whole file
+        None
+    }
+
+    fn next_frame(&mut self) -> Result<Option<f32>, Box<dyn Error>> {
+        while self.position >= self.samples.len() {
+            if !self.decode_frame()? {
+                return Ok(None);
+            }
+        }
+        self.position += 1;
+        Ok(Some(self.samples[self.position - 1]))
+    }
+
+    /// MP3 has no index to seek with: decode from the start and skip up to *frame*.
+    fn seek(&mut self, frame: u64) -> Result<(), Box<dyn Error>> {
+        self.decoder = puremp3::Mp3Decoder::new(BufReader::new(File::open(&self.path)?));
+        self.samples.clear();
+        self.position = 0;
+
+        let mut skipped: u64 = 0;
+        while self.decode_frame()? {
+            let len = self.samples.len() as u64;
+            if skipped + len > frame {
+                self.position = (frame - skipped) as usize;
+                break;
+            }
+            skipped += len;
+        }
+        Ok(())
+    }
+}
+
+/// Writes 16-bit mono AIFF. The header is kept up to date on flush().
+pub struct AiffWriter {
+    writer: BufWriter<File>,
+    sample_rate: u32,
+    frames: u32,
+}
+
+impl AiffWriter {
+
+    pub fn create(path: &Path, sample_rate: u32) -> io::Result<AiffWriter> {
+        let mut writer = AiffWriter {
+            writer: BufWriter::new(File::create(path)?),
+            sample_rate,
+            frames: 0,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    /// Fails once the file is as long as AIFF allows.
+    pub fn write_sample(&mut self, sample: f32) -> io::Result<()> {
+        if self.frames >= MAX_AIFF_FRAMES {
+            return Err(io::Error::other("AIFF file full"));
+        }
+        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        self.frames += 1;
+        self.writer.write_all(&sample.to_be_bytes())
+    }
+
+    /// Writes the sizes into the header so the file is valid as it is.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+
+    pub fn finalize(mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let data_bytes = self.frames * 2;
+        let w = &mut self.writer;
+        w.write_all(b"FORM")?;
+        w.write_all(&(46 + data_bytes).to_be_bytes())?;
+        w.write_all(b"AIFF")?;
+
+        w.write_all(b"COMM")?;
+        w.write_all(&18u32.to_be_bytes())?;
+        w.write_all(&1u16.to_be_bytes())?;
+        w.write_all(&self.frames.to_be_bytes())?;
+        w.write_all(&16u16.to_be_bytes())?;
+        w.write_all(&f64_to_extended(self.sample_rate as f64))?;
+
+        w.write_all(b"SSND")?;
+        w.write_all(&(8 + data_bytes).to_be_bytes())?;
+        // Offset and block size
+        w.write_all(&0u32.to_be_bytes())?;
+        w.write_all(&0u32.to_be_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rust_test_media_{}_{}", std::process::id(), name))
+    }
+
+    fn header(start: &[u8], at: usize, tag: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0u8; 64];
+        bytes[..start.len()].copy_from_slice(start);
+        bytes[at..at + tag.len()].copy_from_slice(tag);
+        bytes
+    }
+
+    #[test]
+    fn detects_formats() {
+        assert_eq!(detect(&header(b"RIFF", 8, b"WAVE")), Some(Format::Wav));
+        assert_eq!(detect(&header(b"RIFX", 8, b"WAVE")), Some(Format::Wav));
+        assert_eq!(detect(&header(b"RF64", 8, b"WAVE")), Some(Format::Wav));
+        assert_eq!(detect(&header(b"FORM", 8, b"AIFF")), Some(Format::Aiff));
+        assert_eq!(detect(&header(b"FORM", 8, b"AIFC")), Some(Format::Aiff));
+        assert_eq!(detect(&header(b"OggS", 29, b"vorbis")), Some(Format::Vorbis));
+        assert_eq!(detect(&header(b"ID3", 3, b"")), Some(Format::Mp3));
+        assert_eq!(detect(&[0xFF, 0xFB, 0x90, 0x00]), Some(Format::Mp3));
+
+        // Other things in the same containers, and too short to tell
+        assert_eq!(detect(&header(b"RIFF", 8, b"AVI ")), None);
+        assert_eq!(detect(&header(b"FORM", 8, b"8SVX")), None);
+        assert_eq!(detect(&header(b"OggS", 29, b"opus..")), None);
+        assert_eq!(detect(&[0xFF, 0x00]), None);
+        assert_eq!(detect(b"RIFF"), None);
+        assert_eq!(detect(&[]), None);
+    }
+
+    #[test]
+    fn parses_descriptors() {
+        let format = PcmFormat::parse("s24be:2:48000").unwrap();
+        assert_eq!((format.bits, format.signed, format.big_endian), (24, true, true));
+        assert_eq!((format.channels, format.sample_rate), (2, 48000));
+        assert_eq!(format.frame_bytes(), 6);
+
+        for descriptor in ["", "s16le", "s16le:2", "s16le:2:48000:1", "s16:2:48000", "s16le:0:48000",
+                           "s16le:2:0", "s16le:two:48000", "s16le:-1:48000"] {
+            assert!(PcmFormat::parse(descriptor).is_err(), "{}", descriptor);
+        }
+    }
+
+    #[test]
+    fn decodes_samples() {
+        let format = |descriptor| PcmFormat::parse(descriptor).unwrap();
+        // Negative 24-bit, big- and little-endian
+        assert_eq!(format("s24be:1:8000").sample(&[0xFF, 0xFF, 0xFE]), -2.0 / 8_388_608.0);
+        assert_eq!(format("s24be:1:8000").sample(&[0x80, 0x00, 0x00]), -1.0);
+        assert_eq!(format("s24le:1:8000").sample(&[0x00, 0x00, 0x80]), -1.0);
+        assert_eq!(format("s24le:1:8000").sample(&[0xFF, 0xFF, 0x3F]), 0.5 - 1.0 / 8_388_608.0);
+        assert_eq!(format("s16le:1:8000").sample(&[0x00, 0xC0]), -0.5);
+        assert_eq!(format("s8:1:8000").sample(&[0xFF]), -1.0 / 128.0);
+        assert_eq!(format("s8:1:8000").sample(&[0x40]), 0.5);
+        // Unsigned: 0x80 is the middle
+        assert_eq!(format("u8:1:8000").sample(&[0x00]), -1.0);
+        assert_eq!(format("u8:1:8000").sample(&[0x80]), 0.0);
+        assert_eq!(format("u8:1:8000").sample(&[0xC0]), 0.5);
+        assert_eq!(format("f32be:1:8000").sample(&0.25f32.to_be_bytes()), 0.25);
+        assert_eq!(format("f32le:1:8000").sample(&(-0.25f32).to_le_bytes()), -0.25);
+    }
+
+    #[test]
+    fn extended_floats() {
+        // 44100 as AIFF writes it
+        let bytes = f64_to_extended(44100.0);
+        assert_eq!(bytes, [0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0]);
+        for rate in [8000.0, 44100.0, 48000.0, 96000.0, 1.0] {
+            assert_eq!(extended_to_f64(&f64_to_extended(rate)), rate);
+        }
+        assert_eq!(f64_to_extended(0.0), [0; 10]);
+    }
+
+    #[test]
+    fn aiff_round_trip() {
+        let path = temp_path("round_trip.aiff");
+        let samples: Vec<f32> = (0..1000).map(|i| ((i as f32) * 0.05).sin() * 0.8).collect();
+        let mut writer = AiffWriter::create(&path, 22050).unwrap();
+        for &sample in &samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        assert_eq!(probe(&path), Some(Format::Aiff));
+        let mut decoder = PcmDecoder::aiff(&path).unwrap();
+        assert_eq!(decoder.sample_rate(), 22050);
+        assert_eq!(decoder.frames(), Some(1000));
+        for &written in &samples {
+            let read = decoder.next_frame().unwrap().unwrap();
+            assert!((written - read).abs() <= 1.0 / 16384.0, "{} vs {}", written, read);
+        }
+        assert_eq!(decoder.next_frame().unwrap(), None);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn aiff_writer_stops_when_full() {
+        let path = temp_path("full.aiff");
+        let mut writer = AiffWriter::create(&path, 44100).unwrap();
+        writer.frames = MAX_AIFF_FRAMES - 1;
+        writer.write_sample(0.0).unwrap();
+        assert!(writer.write_sample(0.0).is_err());
+        // The sizes still fit in the header
+        writer.flush().unwrap();
+        std::fs::remove_file(&path).ok();
+    }
+}
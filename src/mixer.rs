@@ -0,0 +1,632 @@
+//! Mixer: a conference bridge, one per room (see rooms.rs). Every client uploading its mic to the
+//! room, plus a file and a generator if configured, summed into one stereo mix that all listeners
+//! of the "mix" mode in that room hear.
+//!
+//! Sources:
+//! - Uploads: clients connecting in the "upload" mode send their mic up as audio packets
+//!   ("stream upload 3600s name=alice gain=-3 pan=-0.5"). Each one is in the mix for as long as
+//!   it is connected.
+//! - `mix_file`, a file from the media directory, looped.
+//! - `mix_generator`, generator options as in a handshake ("gen=pink level=-30").
+//!
+//! Every source has a gain in dB and a pan from -1 (left) to 1 (right), constant power, given in
+//! the handshake or the config. Any listener can change them with "source <name> gain <dB>" and
+//! "source <name> pan <position>" (the file and the generator are called "file" and
+//! "generator"). The sum goes through a look-ahead limiter, so however many people talk at once,
+//! the mix never clips.
+//!
+//! Uploads each come with their own network delay and jitter, so each one goes through a jitter
+//! buffer held at `mix_latency_ms`: an upload is heard once that much of it is buffered, what
+//! builds up past `mix_max_latency_ms` (a burst after a stall, a sender whose clock runs fast) is
+//! dropped to get back to it, and when it runs dry it fills up again before being heard. That way
+//! everyone is heard with the same delay, and stays lined up with everyone else, whatever their
+//! connection.
+//!
+//! Clients in the "duplex" mode upload like the others, and get back the mix-minus: the room
+//! without themselves, so they don't hear their own voice coming back. The mixer keeps what each
+//! of them put in the total this block, after their jitter buffer, gain and pan, and takes exactly
+//! that back out. Their own delay is compensated that way, however late their upload is, and at
+//! the cost of one subtraction per participant rather than a mix each. The total is limited after
+//! that, and every mix-minus has a limiter of its own.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::broadcast::Broadcast;
+use crate::control::Control;
+use crate::generator::{Generator, GeneratorSettings};
+use crate::handshake::Handshake;
+use crate::media::MediaSource;
+use crate::packet::Packet;
+use crate::processing::{db_to_linear, Limiter};
+use crate::server::media_path;
+
+const SAMPLE_RATE: f64 = 44_100.0;
+const BLOCK_LENGTH: usize = 250;
+
+// How far ahead of real time the mix runs
+const MIX_LEAD: Duration = Duration::from_millis(50);
+
+// On the mix bus
+const LIMITER_THRESHOLD_DB: f32 = -1.0;
+
+// Per source, louder than this is a mistake
+const MAX_GAIN_DB: f32 = 20.0;
+
+// Blocks waiting to go out to a duplex client (~0.35s), it loses some rather than lag behind
+const MIX_MINUS_QUEUE: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct MixerSettings {
+    pub enabled: bool,
+    /// How long uploads are buffered before they are mixed.
+    pub latency_ms: f64,
+    /// Past this, an upload's buffer is brought back to *latency_ms*.
+    pub max_latency_ms: f64,
+    /// In the media directory, looped.
+    pub file: Option<String>,
+    pub file_gain_db: f32,
+    pub file_pan: f32,
+    /// Generator options as in the handshake, e.g. "gen=pink level=-30".
+    pub generator: Option<String>,
+    pub generator_gain_db: f32,
+    pub generator_pan: f32,
+}
+
+impl Default for MixerSettings {
+    fn default() -> MixerSettings {
+        MixerSettings {
+            enabled: false,
+            latency_ms: 60.0,
+            max_latency_ms: 200.0,
+            file: None,
+            file_gain_db: 0.0,
+            file_pan: 0.0,
+            generator: None,
+            generator_gain_db: 0.0,
+            generator_pan: 0.0,
+        }
+    }
+}
+
+/// One source in the mix.
+struct Channel {
+    id: u64,
+    name: String,
+    gain: f32,
+    pan: f32,
+    feed: Feed,
+    /// For uploads that hear the room back (duplex).
+    mix_minus: Option<MixMinus>,
+}
+
+/// A participant's own output: the total without them.
+struct MixMinus {
+    sender: SyncSender<Packet>,
+    /// What they put in the total this block, stereo.
+    own: Vec<f32>,
+    limiter: Limiter,
+}
+
+enum Feed {
+    Upload(Arc<Mutex<JitterBuffer>>),
+    // These two are read on the mixer thread
+    File,
+    Generator,
+}
+
+pub struct Mixer {
+    /// The room it mixes for, see rooms.rs.
+    pub name: String,
+    broadcast: Arc<Broadcast>,
+    channels: Arc<Mutex<Vec<Channel>>>,
+    next_id: AtomicU64,
+    // In samples
+    latency: usize,
+    max_latency: usize,
+}
+
+impl Mixer {
+
+    /// Starts mixing on a new thread, whenever there is someone listening, until the mixer is
+    /// dropped. The file is looked up in *media_dir*.
+    pub fn start(name: &str, settings: &MixerSettings, media_dir: &Path) -> Result<Arc<Mixer>, String> {
+        let latency = (settings.latency_ms / 1000.0 * SAMPLE_RATE) as usize;
+        // Room for at least a couple of blocks on top of the latency, or nothing would ever play
+        let max_latency = std::cmp::max(
+            (settings.max_latency_ms / 1000.0 * SAMPLE_RATE) as usize,
+            latency + 2 * BLOCK_LENGTH);
+
+        let mixer = Arc::new(Mixer {
+            name: name.to_string(),
+            broadcast: Arc::new(Broadcast::new()),
+            channels: Arc::new(Mutex::new(Vec::new())),
+            next_id: AtomicU64::new(1),
+            latency,
+            max_latency,
+        });
+
+        // The file is opened again on the mixer thread, this is to find out early if it's no good
+        let file = match settings.file {
+            Some(ref name) => {
+                let path = media_path(media_dir, name)?;
+                MediaSource::open(&path, SAMPLE_RATE, None)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                mixer.add("file", settings.file_gain_db, settings.file_pan, Feed::File, None);
+                Some(path)
+            }
+            None => None,
+        };
+        let generator = match settings.generator {
+            Some(ref options) => {
+                let handshake = Handshake::parse(&format!("stream gen 0s {}", options))?;
+                let generator_settings = GeneratorSettings::from_handshake(&handshake)?;
+                mixer.add("generator", settings.generator_gain_db, settings.generator_pan, Feed::Generator, None);
+                Some(generator_settings)
+            }
+            None => None,
+        };
+
+        let thread_mixer = Arc::downgrade(&mixer);
+        std::thread::spawn(move || {
+            run(thread_mixer, file, generator);
+        });
+
+        Ok(mixer)
+    }
+
+    pub fn subscribe(&self) -> Receiver<Packet> {
+        self.broadcast.subscribe()
+    }
+
+    /// Everyone hearing the room: listeners of the mix, and duplex participants.
+    pub fn listener_count(&self) -> usize {
+        let participants = self.channels.lock().unwrap().iter()
+            .filter(|channel| channel.mix_minus.is_some())
+            .count();
+        self.broadcast.listener_count() + participants
+    }
+
+    pub fn upload_count(&self) -> usize {
+        self.channels.lock().unwrap().iter()
+            .filter(|channel| matches!(channel.feed, Feed::Upload(_)))
+            .count()
+    }
+
+    /// Adds an upload to the mix, until the input is dropped.
+    pub fn add_input(&self, name: &str, gain_db: f32, pan: f32) -> Input {
+        self.add_upload(name, gain_db, pan, None)
+    }
+
+    /// Same as add_input(), and whoever uploads hears the mix-minus from the receiver: stereo
+    /// audio packets, until the input is dropped.
+    pub fn add_participant(&self, name: &str, gain_db: f32, pan: f32) -> (Input, Receiver<Packet>) {
+        let (sender, receiver) = sync_channel(MIX_MINUS_QUEUE);
+        let mix_minus = MixMinus {
+            sender,
+            own: vec![0.0; BLOCK_LENGTH * 2],
+            limiter: Limiter::new(LIMITER_THRESHOLD_DB),
+        };
+        (self.add_upload(name, gain_db, pan, Some(mix_minus)), receiver)
+    }
+
+    fn add_upload(&self, name: &str, gain_db: f32, pan: f32, mix_minus: Option<MixMinus>) -> Input {
+        let buffer = Arc::new(Mutex::new(JitterBuffer::new(self.latency, self.max_latency)));
+        let id = self.add(name, gain_db, pan, Feed::Upload(buffer.clone()), mix_minus);
+        println!("Mixer {}: {} joined", self.name, name);
+
+        Input {
+            id,
+            name: format!("{}: {}", self.name, name),
+            buffer,
+            channels: self.channels.clone(),
+        }
+    }
+
+    /// Applies a control message if it is meant for the mixer, returns false if it isn't.
+    pub fn control(&self, control: &Control) -> bool {
+        match control {
+            Control::SourceGain(name, db) => self.update(name, |channel| channel.gain = gain(*db)),
+            Control::SourcePan(name, pan) => self.update(name, |channel| channel.pan = *pan),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Applies *change* to the sources called *name*.
+    fn update<F: Fn(&mut Channel)>(&self, name: &str, change: F) {
+        let mut found = false;
+        for channel in self.channels.lock().unwrap().iter_mut().filter(|c| c.name == name) {
+            change(channel);
+            found = true;
+        }
+        if !found {
+            println!("Mixer {}: no source named {}", self.name, name);
+        }
+    }
+
+    fn add(&self, name: &str, gain_db: f32, pan: f32, feed: Feed, mix_minus: Option<MixMinus>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.channels.lock().unwrap().push(Channel {
+            id,
+            name: name.to_string(),
+            gain: gain(gain_db),
+            pan: pan.clamp(-1.0, 1.0),
+            feed,
+            mix_minus,
+        });
+        id
+    }
+}
+
+/// An upload's place in the mix. It leaves the mix when this is dropped.
+pub struct Input {
+    id: u64,
+    name: String,
+    buffer: Arc<Mutex<JitterBuffer>>,
+    channels: Arc<Mutex<Vec<Channel>>>,
+}
+
+impl Input {
+
+    /// Queues what the client sent, to be mixed once it has been buffered long enough.
+    pub fn push(&self, samples: &[f32]) {
+        let dropped = self.buffer.lock().unwrap().push(samples);
+        if dropped > 0 {
+            println!("Mixer {} too far behind, dropped {} samples", self.name, dropped);
+        }
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        self.channels.lock().unwrap().retain(|channel| channel.id != self.id);
+        println!("Mixer {} left", self.name);
+    }
+}
+
+/// What an upload sent that hasn't been mixed yet, see the module doc.
+struct JitterBuffer {
+    samples: VecDeque<f32>,
+    latency: usize,
+    max_latency: usize,
+    // False while filling up to the latency
+    playing: bool,
+}
+
+impl JitterBuffer {
+
+    fn new(latency: usize, max_latency: usize) -> JitterBuffer {
+        JitterBuffer {
+            samples: VecDeque::with_capacity(max_latency),
+            latency,
+            max_latency,
+            playing: false,
+        }
+    }
+
+    /// Returns how many samples were dropped to get back to the latency.
+    fn push(&mut self, samples: &[f32]) -> usize {
+        self.samples.extend(samples);
+        if self.samples.len() <= self.max_latency {
+            return 0;
+        }
+        let excess = self.samples.len() - self.latency;
+        self.samples.drain(..excess);
+        excess
+    }
+
+    /// Fills *buffer*, padding with silence if it runs dry. False if there is nothing to hear yet.
+    fn read(&mut self, buffer: &mut [f32]) -> bool {
+        if !self.playing {
+            if self.samples.len() < self.latency {
+                return false;
+            }
+            self.playing = true;
+        }
+
+        let len = std::cmp::min(buffer.len(), self.samples.len());
+        for (sample, queued) in buffer.iter_mut().zip(self.samples.drain(..len)) {
+            *sample = queued;
+        }
+        for sample in buffer[len..].iter_mut() {
+            *sample = 0.0;
+        }
+        if len < buffer.len() {
+            self.playing = false;
+        }
+        true
+    }
+}
+
+/// The mixer thread, until the mixer is dropped.
+fn run(mixer: Weak<Mixer>, file: Option<PathBuf>, generator: Option<GeneratorSettings>) {
+    let mut file = file.and_then(|path| match MediaSource::open(&path, SAMPLE_RATE, None) {
+        Ok(source) => Some(source),
+        Err(e) => {
+            println!("Mixer: {}: {}", path.display(), e);
+            None
+        }
+    });
+    let mut generator = generator.map(|settings| Generator::new(settings, SAMPLE_RATE));
+
+    let mut limiter = Limiter::new(LIMITER_THRESHOLD_DB);
+    let mut mono = [0.0f32; BLOCK_LENGTH];
+    let mut mix = vec![0.0f32; BLOCK_LENGTH * 2];
+
+    let mut start = Instant::now();
+    let mut frames_sent = 0;
+
+    loop {
+        let mixer = match mixer.upgrade() {
+            Some(mixer) => mixer,
+            None => return,
+        };
+        // Nothing to do without listeners, uploads keep their buffers at the latency meanwhile.
+        // (The mixer is never held while waiting, so an unused room can go away, see rooms.rs.)
+        if mixer.listener_count() == 0 {
+            drop(mixer);
+            std::thread::sleep(Duration::from_millis(100));
+            start = Instant::now();
+            frames_sent = 0;
+            continue;
+        }
+
+        let mut channels = mixer.channels.lock().unwrap();
+        mix_block(&mut channels, &mut file, &mut generator, &mut mono, &mut mix);
+        drop(channels);
+        limiter.process(&mut mix);
+
+        mixer.broadcast.send(&Packet::Stereo(mix.clone()));
+        frames_sent += BLOCK_LENGTH;
+        drop(mixer);
+
+        // Pace at real time, a little ahead
+        let due = Duration::from_secs_f64(frames_sent as f64 / SAMPLE_RATE);
+        let elapsed = start.elapsed() + MIX_LEAD;
+        if due > elapsed {
+            std::thread::sleep(due - elapsed);
+        }
+    }
+}
+
+/// Sums one block of every channel into *mix* (stereo), and sends the participants their
+/// mix-minus. *mono* is scratch space, a block long.
+fn mix_block(channels: &mut [Channel], file: &mut Option<MediaSource>,
+             generator: &mut Option<Generator>, mono: &mut [f32], mix: &mut [f32]) {
+    for sample in mix.iter_mut() {
+        *sample = 0.0;
+    }
+    for channel in channels.iter_mut() {
+        let audible = match channel.feed {
+            Feed::Upload(ref buffer) => buffer.lock().unwrap().read(mono),
+            Feed::File => match *file {
+                Some(ref mut source) => read_looped(source, mono),
+                None => false,
+            },
+            Feed::Generator => match *generator {
+                Some(ref mut generator) => {
+                    generator.fill(mono);
+                    true
+                }
+                None => false,
+            },
+        };
+        if !audible {
+            if let Some(ref mut mix_minus) = channel.mix_minus {
+                for sample in mix_minus.own.iter_mut() {
+                    *sample = 0.0;
+                }
+            }
+            continue;
+        }
+
+        let (left, right) = pan_gains(channel.pan);
+        let (left, right) = (left * channel.gain, right * channel.gain);
+        match channel.mix_minus {
+            // Kept, to be taken back out
+            Some(ref mut mix_minus) => {
+                let frames = mix.chunks_mut(2).zip(mix_minus.own.chunks_mut(2)).zip(mono.iter());
+                for ((frame, own), sample) in frames {
+                    own[0] = sample * left;
+                    own[1] = sample * right;
+                    frame[0] += own[0];
+                    frame[1] += own[1];
+                }
+            }
+            None => {
+                for (frame, sample) in mix.chunks_mut(2).zip(mono.iter()) {
+                    frame[0] += sample * left;
+                    frame[1] += sample * right;
+                }
+            }
+        }
+    }
+
+    // Every participant gets the total without themselves, before the total is limited
+    for mix_minus in channels.iter_mut().filter_map(|channel| channel.mix_minus.as_mut()) {
+        let mut samples: Vec<f32> = mix.iter().zip(mix_minus.own.iter())
+            .map(|(total, own)| total - own)
+            .collect();
+        mix_minus.limiter.process(&mut samples);
+        mix_minus.sender.try_send(Packet::Stereo(samples)).ok();
+    }
+}
+
+/// Fills *buffer* from *source*, starting over at the end. False if the file can't be read.
+fn read_looped(source: &mut MediaSource, buffer: &mut [f32]) -> bool {
+    let mut filled = 0;
+    let mut rewound = false;
+
+    while filled < buffer.len() {
+        match source.read(&mut buffer[filled..]) {
+            Ok(0) if !rewound => {
+                if let Err(e) = source.seek(0.0) {
+                    println!("Mixer: {}", e);
+                    return false;
+                }
+                rewound = true;
+            }
+            // Nothing in the file at all
+            Ok(0) => return false,
+            Ok(len) => {
+                filled += len;
+                rewound = false;
+            }
+            Err(e) => {
+                println!("Mixer: {}", e);
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Left and right gains for *pan*, constant power (-3dB each in the middle).
+fn pan_gains(pan: f32) -> (f32, f32) {
+    let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+    (angle.cos(), angle.sin())
+}
+
+fn gain(db: f32) -> f32 {
+    db_to_linear(db.min(MAX_GAIN_DB))
+}
+
+/// Interleaved stereo to mono, for whatever only plays or records mono.
+pub fn downmix(samples: &[f32]) -> Vec<f32> {
+    samples.chunks_exact(2)
+        .map(|frame| (frame[0] + frame[1]) * 0.5)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Samples the limiter holds back (its look-ahead, see processing.rs)
+    const LIMITER_DELAY: usize = 64;
+
+    // Not started, the tests call mix_block() themselves
+    fn mixer(latency: usize) -> Mixer {
+        Mixer {
+            name: "test".to_string(),
+            broadcast: Arc::new(Broadcast::new()),
+            channels: Arc::new(Mutex::new(Vec::new())),
+            next_id: AtomicU64::new(1),
+            latency,
+            max_latency: latency + 2 * BLOCK_LENGTH,
+        }
+    }
+
+    fn mix_once(mixer: &Mixer) -> Vec<f32> {
+        let mut mono = [0.0f32; BLOCK_LENGTH];
+        let mut mix = vec![0.0f32; BLOCK_LENGTH * 2];
+        mix_block(&mut mixer.channels.lock().unwrap(), &mut None, &mut None, &mut mono, &mut mix);
+        mix
+    }
+
+    fn received(receiver: &Receiver<Packet>) -> Vec<f32> {
+        match receiver.try_recv() {
+            Ok(Packet::Stereo(samples)) => samples,
+            other => panic!("expected a stereo block, got {:?}", other),
+        }
+    }
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|sample| sample * sample).sum()
+    }
+
+    #[test]
+    fn mix_minus_leaves_out_own_input() {
+        let mixer = mixer(BLOCK_LENGTH);
+        let (alice, alice_hears) = mixer.add_participant("alice", 0.0, -0.5);
+        let (bob, bob_hears) = mixer.add_participant("bob", -6.0, 0.5);
+        let _carol = mixer.add_input("carol", 0.0, 0.0);
+
+        // Alice talks, Bob is silent, Carol isn't buffered enough to be heard yet
+        let tone: Vec<f32> = (0..BLOCK_LENGTH).map(|i| (i as f32 * 0.1).sin() * 0.5).collect();
+        for _ in 0..3 {
+            alice.push(&tone);
+            bob.push(&[0.0; BLOCK_LENGTH]);
+            let mix = mix_once(&mixer);
+            assert!(energy(&mix) > 1.0);
+            assert_eq!(energy(&received(&alice_hears)), 0.0);
+            assert!(energy(&received(&bob_hears)) > 1.0);
+        }
+    }
+
+    #[test]
+    fn mix_minus_hears_everyone_else() {
+        let mixer = mixer(BLOCK_LENGTH);
+        let (alice, alice_hears) = mixer.add_participant("alice", 0.0, 0.0);
+        let (bob, bob_hears) = mixer.add_participant("bob", 0.0, 0.0);
+
+        // Steady levels, each under the limiter threshold on its own
+        let (left, right) = pan_gains(0.0);
+        for block in 0..3 {
+            alice.push(&[0.4; BLOCK_LENGTH]);
+            bob.push(&[-0.2; BLOCK_LENGTH]);
+            mix_once(&mixer);
+            // Past the limiter's look-ahead delay
+            let skip = if block == 0 { LIMITER_DELAY } else { 0 };
+            for (hears, other) in [(&alice_hears, -0.2), (&bob_hears, 0.4)] {
+                for frame in received(hears)[skip..].chunks(2) {
+                    assert!((frame[0] - other * left).abs() < 1e-6, "{:?}", frame);
+                    assert!((frame[1] - other * right).abs() < 1e-6, "{:?}", frame);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn jitter_buffer_fills_up_first() {
+        let mut buffer = JitterBuffer::new(100, 300);
+        let mut block = [1.0f32; 50];
+        assert_eq!(buffer.push(&[0.5; 60]), 0);
+        assert!(!buffer.read(&mut block));
+        assert_eq!(buffer.push(&[0.5; 40]), 0);
+        assert!(buffer.read(&mut block));
+        assert_eq!(block, [0.5; 50]);
+        // Keeps playing under the latency, once started
+        assert!(buffer.read(&mut block));
+        assert_eq!(block, [0.5; 50]);
+    }
+
+    #[test]
+    fn jitter_buffer_drops_back_to_latency() {
+        let mut buffer = JitterBuffer::new(100, 300);
+        assert_eq!(buffer.push(&[0.0; 300]), 0);
+        let samples: Vec<f32> = (0..50).map(|i| i as f32).collect();
+        assert_eq!(buffer.push(&samples), 250);
+        assert_eq!(buffer.samples.len(), 100);
+
+        // The newest are kept
+        let mut block = [0.0f32; 100];
+        assert!(buffer.read(&mut block));
+        assert_eq!(block[50..], samples[..]);
+    }
+
+    #[test]
+    fn jitter_buffer_refills_after_running_dry() {
+        let mut buffer = JitterBuffer::new(100, 300);
+        buffer.push(&[0.5; 130]);
+        let mut block = [1.0f32; 100];
+        assert!(buffer.read(&mut block));
+
+        // Runs dry: what's left, then silence
+        assert!(buffer.read(&mut block));
+        assert_eq!(block[..30], [0.5; 30]);
+        assert_eq!(block[30..], [0.0; 70]);
+
+        // Not heard again until it is back to the latency
+        buffer.push(&[0.25; 60]);
+        assert!(!buffer.read(&mut block));
+        buffer.push(&[0.25; 40]);
+        assert!(buffer.read(&mut block));
+        assert_eq!(block, [0.25; 100]);
+    }
+}
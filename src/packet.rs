@@ -0,0 +1,161 @@
+//! Framing for the audio sent from the server to the client.
+//!
+//! Every packet is a one byte tag, a little-endian u32 payload length, then the payload:
+//! - 'A' audio: little-endian f32 samples.
+//! - 'B' stereo audio: little-endian f32 samples, left and right interleaved (see mixer.rs).
+//! - 'S' silence: u32 frame count then f32 noise level (RMS), the client fills in comfort noise.
+//! - 'V' voice activity: one byte, 1 when someone starts talking, 0 when they stop.
+//! - 'T' track change: UTF-8 title of what is playing now.
+//! - 'F' flush: no payload, drop whatever audio is still waiting to be played (after a seek).
+//! - 'E' end of stream: no payload, the server is done (end of file, duration reached...).
+//! - 'K' key exchange: the server's public key, for end-to-end encryption (see e2e.rs).
+//! - 'X' encrypted: u64 sequence number, then another packet, encrypted.
+
+use std::io::{self, Read, Write};
+
+const TAG_AUDIO: u8 = b'A';
+const TAG_STEREO: u8 = b'B';
+const TAG_SILENCE: u8 = b'S';
+const TAG_VAD: u8 = b'V';
+const TAG_TRACK: u8 = b'T';
+const TAG_FLUSH: u8 = b'F';
+const TAG_END: u8 = b'E';
+const TAG_KEY: u8 = b'K';
+const TAG_SEALED: u8 = b'X';
+
+// Nothing we send is anywhere near this, anything bigger means the stream is corrupt.
+const MAX_PAYLOAD: usize = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    Audio(Vec<f32>),
+    Stereo(Vec<f32>),
+    Silence { frames: u32, level: f32 },
+    Vad(bool),
+    Track(String),
+    Flush,
+    End,
+    Key(Vec<u8>),
+    Sealed { seq: u64, data: Vec<u8> },
+}
+
+impl Packet {
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut payload = Vec::new();
+        let tag = match self {
+            Packet::Audio(samples) => {
+                for sample in samples {
+                    payload.extend_from_slice(&sample.to_le_bytes());
+                }
+                TAG_AUDIO
+            }
+            Packet::Stereo(samples) => {
+                for sample in samples {
+                    payload.extend_from_slice(&sample.to_le_bytes());
+                }
+                TAG_STEREO
+            }
+            Packet::Silence { frames, level } => {
+                payload.extend_from_slice(&frames.to_le_bytes());
+                payload.extend_from_slice(&level.to_le_bytes());
+                TAG_SILENCE
+            }
+            Packet::Vad(speech) => {
+                payload.push(*speech as u8);
+                TAG_VAD
+            }
+            Packet::Track(title) => {
+                payload.extend_from_slice(title.as_bytes());
+                TAG_TRACK
+            }
+            Packet::Flush => TAG_FLUSH,
+            Packet::End => TAG_END,
+            Packet::Key(public) => {
+                payload.extend_from_slice(public);
+                TAG_KEY
+            }
+            Packet::Sealed { seq, data } => {
+                payload.extend_from_slice(&seq.to_le_bytes());
+                payload.extend_from_slice(data);
+                TAG_SEALED
+            }
+        };
+
+        let mut header = [0u8; 5];
+        header[0] = tag;
+        header[1..].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+
+        writer.write_all(&header)?;
+        writer.write_all(&payload)
+    }
+
+    /// Blocks until a whole packet has been read.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Packet> {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header)?;
+
+        let length = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if length > MAX_PAYLOAD {
+            return Err(invalid("packet too large"));
+        }
+        let mut payload = vec![0u8; length];
+        reader.read_exact(&mut payload)?;
+
+        match header[0] {
+            TAG_AUDIO => {
+                if !length.is_multiple_of(4) {
+                    return Err(invalid("audio payload is not a whole number of samples"));
+                }
+                Ok(Packet::Audio(le_bytes_to_f32(&payload)))
+            }
+            TAG_STEREO => {
+                if !length.is_multiple_of(8) {
+                    return Err(invalid("stereo payload is not a whole number of frames"));
+                }
+                Ok(Packet::Stereo(le_bytes_to_f32(&payload)))
+            }
+            TAG_SILENCE => {
+                if length != 8 {
+                    return Err(invalid("bad silence packet"));
+                }
+                let frames = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                let level = f32::from_le_bytes([payload[4], payload[5], payload[6], payload[7]]);
+                Ok(Packet::Silence { frames, level })
+            }
+            TAG_VAD => {
+                if length != 1 {
+                    return Err(invalid("bad vad packet"));
+                }
+                Ok(Packet::Vad(payload[0] != 0))
+            }
+            TAG_TRACK => {
+                String::from_utf8(payload)
+                    .map(Packet::Track)
+                    .map_err(|_| invalid("track title is not UTF-8"))
+            }
+            TAG_FLUSH => Ok(Packet::Flush),
+            TAG_END => Ok(Packet::End),
+            TAG_KEY => Ok(Packet::Key(payload)),
+            TAG_SEALED => {
+                if length < 8 {
+                    return Err(invalid("bad encrypted packet"));
+                }
+                let mut seq = [0u8; 8];
+                seq.copy_from_slice(&payload[..8]);
+                Ok(Packet::Sealed { seq: u64::from_le_bytes(seq), data: payload[8..].to_vec() })
+            }
+            _ => Err(invalid("unknown packet type")),
+        }
+    }
+}
+
+pub fn le_bytes_to_f32(bytes: &[u8]) -> Vec<f32> {
+    bytes.chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
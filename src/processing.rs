@@ -0,0 +1,318 @@
+//! Per-session processing stage: optional AGC, gain, mute and a look-ahead peak limiter.
+//!
+//! Every block of samples goes through a `Processor` before it is pushed into the ringbuffer
+//! (or written to the TCP stream), so whatever the source, the output never hard-clips.
+
+use crate::control::Control;
+
+const SAMPLE_RATE: f32 = 44_100.0;
+
+// Limiter parameters
+const LIMITER_THRESHOLD_DB: f32 = -1.0;
+const LIMITER_LOOKAHEAD: usize = 64; // ~1.5ms at 44.1K
+const LIMITER_RELEASE_SECS: f32 = 0.1;
+
+// Gain is clamped to something sensible, +40dB on a full-scale sine is just noise.
+const MIN_GAIN_DB: f32 = -96.0;
+const MAX_GAIN_DB: f32 = 40.0;
+
+// AGC level detector window, and the level under which we consider there is no one talking
+// (we don't want to pump the background noise up to the target level).
+const AGC_DETECTOR_SECS: f32 = 0.05;
+const AGC_NOISE_FLOOR_DB: f32 = -55.0;
+
+pub fn db_to_linear(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+pub fn linear_to_db(linear: f32) -> f32 {
+    20.0 * linear.max(1e-9).log10()
+}
+
+pub struct Processor {
+    agc: Option<Agc>,
+    gain: f32,
+    mute: bool,
+    limiter_enabled: bool,
+    limiter: Limiter,
+}
+
+impl Processor {
+
+    /// Unity gain, not muted, limiter on, no AGC.
+    pub fn new() -> Processor {
+        Processor {
+            agc: None,
+            gain: 1.0,
+            mute: false,
+            limiter_enabled: true,
+            limiter: Limiter::new(LIMITER_THRESHOLD_DB),
+        }
+    }
+
+    /// Same as new(), with an AGC stage in front (used for microphone capture).
+    pub fn with_agc(settings: &AgcSettings) -> Processor {
+        let mut processor = Processor::new();
+        processor.agc = Some(Agc::new(settings));
+        processor
+    }
+
+    pub fn set_gain_db(&mut self, db: f32) {
+        // clamp() lets NaN through, and one NaN sample makes the whole output NaN
+        let db = if db.is_nan() { MIN_GAIN_DB } else { db.clamp(MIN_GAIN_DB, MAX_GAIN_DB) };
+        self.gain = db_to_linear(db);
+    }
+
+    pub fn set_mute(&mut self, mute: bool) {
+        self.mute = mute;
+    }
+
+    pub fn set_limiter(&mut self, enabled: bool) {
+        self.limiter_enabled = enabled;
+    }
+
+    /// Apply a control message coming from the client.
+    pub fn apply(&mut self, control: &Control) {
+        match *control {
+            Control::Gain(db) => self.set_gain_db(db),
+            Control::Mute(mute) => self.set_mute(mute),
+            Control::Limiter(enabled) => self.set_limiter(enabled),
+            Control::Agc(enabled) => {
+                if let Some(agc) = self.agc.as_mut() {
+                    agc.enabled = enabled;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Process *buffer* in place.
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        if let Some(agc) = self.agc.as_mut() {
+            agc.process(buffer);
+        }
+
+        let gain = if self.mute { 0.0 } else { self.gain };
+        for sample in buffer.iter_mut() {
+            *sample *= gain;
+        }
+
+        if self.limiter_enabled {
+            self.limiter.process(buffer);
+        } else {
+            // Even without the limiter, never send anything out of range.
+            for sample in buffer.iter_mut() {
+                *sample = sample.clamp(-1.0, 1.0);
+            }
+        }
+    }
+}
+
+/// A processor per channel, for interleaved stereo (the mix, or a relayed mix). Each side has
+/// its own limiter, and control messages go to both.
+pub struct StereoProcessor {
+    left: Processor,
+    right: Processor,
+    // One channel at a time, deinterleaved
+    channel: Vec<f32>,
+}
+
+impl StereoProcessor {
+
+    pub fn new() -> StereoProcessor {
+        StereoProcessor {
+            left: Processor::new(),
+            right: Processor::new(),
+            channel: Vec::new(),
+        }
+    }
+
+    pub fn apply(&mut self, control: &Control) {
+        self.left.apply(control);
+        self.right.apply(control);
+    }
+
+    /// Process interleaved *buffer* in place.
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        process_channel(&mut self.left, &mut self.channel, buffer, 0);
+        process_channel(&mut self.right, &mut self.channel, buffer, 1);
+    }
+}
+
+/// Runs *processor* on one side (0 or 1) of interleaved *buffer*, through *channel*.
+fn process_channel(processor: &mut Processor, channel: &mut Vec<f32>, buffer: &mut [f32], side: usize) {
+    channel.clear();
+    channel.extend(buffer.iter().skip(side).step_by(2));
+    processor.process(channel);
+    for (sample, processed) in buffer.iter_mut().skip(side).step_by(2).zip(channel.iter()) {
+        *sample = *processed;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AgcSettings {
+    pub enabled: bool,
+    pub target_db: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+    pub max_gain_db: f32,
+}
+
+impl Default for AgcSettings {
+    fn default() -> AgcSettings {
+        AgcSettings {
+            enabled: false,
+            target_db: -18.0,
+            attack_ms: 10.0,
+            release_ms: 500.0,
+            max_gain_db: 30.0,
+        }
+    }
+}
+
+/// Automatic gain control: brings the speech level towards *target_db*.
+///
+/// The level is a short mean-square average. The gain goes down with the attack time
+/// (someone shouting into the mic) and comes back up with the release time. Below the
+/// noise floor the gain is held, so silence doesn't get pumped up to the target level.
+struct Agc {
+    enabled: bool,
+    target: f32,
+    max_gain: f32,
+    level: f32,
+    gain: f32,
+    detector_coef: f32,
+    attack_coef: f32,
+    release_coef: f32,
+    noise_floor: f32,
+}
+
+impl Agc {
+
+    fn new(settings: &AgcSettings) -> Agc {
+        Agc {
+            enabled: settings.enabled,
+            target: db_to_linear(settings.target_db),
+            max_gain: db_to_linear(settings.max_gain_db),
+            level: 0.0,
+            gain: 1.0,
+            detector_coef: time_constant(AGC_DETECTOR_SECS * 1000.0),
+            attack_coef: time_constant(settings.attack_ms),
+            release_coef: time_constant(settings.release_ms),
+            noise_floor: db_to_linear(AGC_NOISE_FLOOR_DB),
+        }
+    }
+
+    fn process(&mut self, buffer: &mut [f32]) {
+        if !self.enabled {
+            return;
+        }
+        for sample in buffer.iter_mut() {
+            // Mean-square level detector
+            let square = *sample * *sample;
+            self.level = square + (self.level - square) * self.detector_coef;
+            let rms = self.level.sqrt();
+
+            if rms > self.noise_floor {
+                let target_gain = (self.target / rms).min(self.max_gain);
+                let coef = if target_gain < self.gain { self.attack_coef } else { self.release_coef };
+                self.gain = target_gain + (self.gain - target_gain) * coef;
+            }
+
+            *sample *= self.gain;
+        }
+    }
+}
+
+/// One-pole smoothing coefficient for a time constant in milliseconds.
+fn time_constant(ms: f32) -> f32 {
+    (-1000.0 / (ms.max(0.01) * SAMPLE_RATE)).exp()
+}
+
+/// Look-ahead peak limiter followed by a soft clipper.
+///
+/// Samples are delayed by LIMITER_LOOKAHEAD so the gain can come down *before* a peak gets
+/// to the output. The soft clipper catches whatever is left over, so the output is always
+/// within [-1.0, 1.0]. The mixer runs one on its whole stereo bus.
+pub(crate) struct Limiter {
+    threshold: f32,
+    delay: [f32; LIMITER_LOOKAHEAD],
+    needed: [f32; LIMITER_LOOKAHEAD],
+    pos: usize,
+    gain: f32,
+    attack_coef: f32,
+    release_coef: f32,
+}
+
+impl Limiter {
+
+    pub(crate) fn new(threshold_db: f32) -> Limiter {
+        Limiter {
+            threshold: db_to_linear(threshold_db),
+            delay: [0.0; LIMITER_LOOKAHEAD],
+            needed: [1.0; LIMITER_LOOKAHEAD],
+            pos: 0,
+            gain: 1.0,
+            // Attack fast enough to settle within the look-ahead window.
+            attack_coef: (-5.0 / LIMITER_LOOKAHEAD as f32).exp(),
+            release_coef: (-1.0 / (LIMITER_RELEASE_SECS * SAMPLE_RATE)).exp(),
+        }
+    }
+
+    pub(crate) fn process(&mut self, buffer: &mut [f32]) {
+        for sample in buffer.iter_mut() {
+            // Gain needed for the incoming sample to stay under the threshold
+            let peak = sample.abs();
+            let needed = if peak > self.threshold { self.threshold / peak } else { 1.0 };
+
+            // Swap the incoming sample into the delay line
+            let delayed = self.delay[self.pos];
+            self.delay[self.pos] = *sample;
+            self.needed[self.pos] = needed;
+            self.pos = (self.pos + 1) % LIMITER_LOOKAHEAD;
+
+            // Target is the lowest gain needed for anything in the look-ahead window
+            let target = self.needed.iter().cloned().fold(1.0, f32::min);
+
+            let coef = if target < self.gain { self.attack_coef } else { self.release_coef };
+            self.gain = target + (self.gain - target) * coef;
+
+            *sample = soft_clip(delayed * self.gain, self.threshold);
+        }
+    }
+}
+
+/// Linear up to *threshold*, then bends smoothly towards 1.0.
+fn soft_clip(sample: f32, threshold: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= threshold {
+        return sample;
+    }
+    let headroom = 1.0 - threshold;
+    let over = (magnitude - threshold) / headroom;
+    let clipped = threshold + headroom * over.tanh();
+    clipped.copysign(sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stereo_channels_are_limited_apart() {
+        let mut processor = StereoProcessor::new();
+        processor.apply(&Control::Gain(6.0));
+        // Too loud on the left, quiet on the right
+        let mut buffer: Vec<f32> = (0..2000).flat_map(|_| [1.0, 0.1]).collect();
+        processor.process(&mut buffer);
+
+        // Both delayed by the look-ahead
+        assert!(buffer[..LIMITER_LOOKAHEAD * 2].iter().all(|&sample| sample == 0.0));
+        let gain = db_to_linear(6.0);
+        for frame in buffer[LIMITER_LOOKAHEAD * 2..].chunks(2) {
+            assert!(frame[0] <= 1.0 && frame[0] < gain * 0.9, "{:?}", frame);
+            // Not brought down with the left
+            assert!((frame[1] - 0.1 * gain).abs() < 1e-6, "{:?}", frame);
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Records a stream to a WAV file, or AIFF or raw PCM going by the file extension.
+//!
+//! Samples are handed over through a channel to a writer thread, so nothing that streams audio
+//! ever waits on the disk. The writer updates the WAV header every second, and finalizes it
+//! when the `Recorder` is dropped, so the file is valid however the session ended.
+//! Raw PCM (.raw, .pcm) is written as "f32le:1:<sample rate>".
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::media::AiffWriter;
+
+// Blocks waiting to be written, at ~250 samples a block that's a few seconds of audio.
+const RECORDER_QUEUE: usize = 1024;
+
+const FLUSH_EVERY_SAMPLES: usize = 44_100;
+
+pub struct Recorder {
+    sender: Option<SyncSender<Vec<f32>>>,
+    handle: Option<JoinHandle<()>>,
+    dropped_blocks: usize,
+}
+
+impl Recorder {
+
+    /// Creates the file and starts the writer thread. Samples are recorded as 32-bit float,
+    /// except in AIFF which gets 16-bit.
+    pub fn start(path: &Path, sample_rate: u32) -> Result<Recorder, hound::Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut writer = Sink::create(path, sample_rate)?;
+
+        let (sender, receiver) = sync_channel::<Vec<f32>>(RECORDER_QUEUE);
+        let thread_path = path.to_path_buf();
+
+        let handle = std::thread::spawn(move || {
+            let mut since_flush = 0;
+            let mut result = Ok(());
+
+            // Ends when the Recorder is dropped
+            for block in receiver {
+                for &sample in &block {
+                    result = writer.write_sample(sample);
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                if result.is_err() {
+                    break;
+                }
+
+                // Keep the header up to date in case the whole process goes down
+                since_flush += block.len();
+                if since_flush >= FLUSH_EVERY_SAMPLES {
+                    since_flush = 0;
+                    result = writer.flush();
+                    if result.is_err() {
+                        break;
+                    }
+                }
+            }
+
+            if let Err(e) = result.and_then(|_| writer.finalize()) {
+                println!("Recording {} failed: {}", thread_path.display(), e);
+            } else {
+                println!("Recording saved to {}", thread_path.display());
+            }
+        });
+
+        println!("Recording to {}", path.display());
+
+        Ok(Recorder {
+            sender: Some(sender),
+            handle: Some(handle),
+            dropped_blocks: 0,
+        })
+    }
+
+    /// Queues *samples* for writing. Never blocks: if the writer can't keep up, the block is
+    /// dropped.
+    pub fn write(&mut self, samples: &[f32]) {
+        if let Some(sender) = self.sender.as_ref() {
+            match sender.try_send(samples.to_vec()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    self.dropped_blocks += 1;
+                    if self.dropped_blocks == 1 {
+                        println!("Recorder can't keep up, dropping audio.");
+                    }
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    // Writer thread failed, it already said why.
+                    self.sender = None;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        // Closing the channel lets the writer finish and finalize the file
+        self.sender = None;
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+/// Where the samples go, depending on the file extension.
+enum Sink {
+    Wav(hound::WavWriter<BufWriter<File>>),
+    Aiff(AiffWriter),
+    Raw(BufWriter<File>),
+}
+
+impl Sink {
+
+    fn create(path: &Path, sample_rate: u32) -> Result<Sink, hound::Error> {
+        let extension = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase())
+            .unwrap_or_default();
+
+        match extension.as_str() {
+            "aif" | "aiff" => Ok(Sink::Aiff(AiffWriter::create(path, sample_rate)?)),
+            "raw" | "pcm" => {
+                println!("Raw recording format: f32le:1:{}", sample_rate);
+                Ok(Sink::Raw(BufWriter::new(File::create(path)?)))
+            }
+            _ => {
+                let spec = hound::WavSpec {
+                    channels: 1,
+                    sample_rate,
+                    bits_per_sample: 32,
+                    sample_format: hound::SampleFormat::Float,
+                };
+                Ok(Sink::Wav(hound::WavWriter::create(path, spec)?))
+            }
+        }
+    }
+
+    fn write_sample(&mut self, sample: f32) -> Result<(), hound::Error> {
+        match self {
+            Sink::Wav(writer) => writer.write_sample(sample),
+            Sink::Aiff(writer) => Ok(writer.write_sample(sample)?),
+            Sink::Raw(writer) => Ok(writer.write_all(&sample.to_le_bytes())?),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), hound::Error> {
+        match self {
+            Sink::Wav(writer) => writer.flush(),
+            Sink::Aiff(writer) => Ok(writer.flush()?),
+            Sink::Raw(writer) => Ok(writer.flush()?),
+        }
+    }
+
+    fn finalize(self) -> Result<(), hound::Error> {
+        match self {
+            Sink::Wav(writer) => writer.finalize(),
+            Sink::Aiff(writer) => Ok(writer.finalize()?),
+            Sink::Raw(mut writer) => Ok(writer.flush()?),
+        }
+    }
+}
+
+/// Fills in a file name template: {peer}, {timestamp} (seconds since 1970) and {session}.
+/// Characters that don't belong in a file name (the ':' of the peer address...) become '-'.
+pub fn file_name_from_template(template: &str, peer: &str, session_id: u64) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs())
+        .unwrap_or(0);
+
+    let peer: String = peer.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '-' })
+        .collect();
+
+    template
+        .replace("{peer}", &peer)
+        .replace("{timestamp}", &timestamp.to_string())
+        .replace("{session}", &session_id.to_string())
+}
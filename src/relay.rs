@@ -0,0 +1,163 @@
+//! Relay: this server restreams what another server sends, so listeners far from the capture box
+//! connect to a relay near them instead, and the capture box only ever has one client.
+//!
+//! With `relay_upstream` set, the relay connects to that server like any client (TLS and login
+//! included, see client::connect), with the `relay_handshake` line, as soon as one of its own
+//! listeners asks for the "relay" mode. Everything the upstream server sends goes out to all of
+//! them, each getting it in the format they asked for (`wire=wav`, `bits=32`...). When the last
+//! one leaves, the upstream connection is closed; when the upstream stream ends or fails, the relay
+//! reconnects, waiting longer each time it fails again soon after.
+//!
+//! For the relay not to see the audio at all, put `e2e=group` in `relay_handshake`: the upstream
+//! server encrypts with the group key (see e2e.rs), which the listeners have and the relay doesn't,
+//! and the relay passes the encrypted packets on as they are. Its listeners must then ask for
+//! `e2e=group` too, with `wire=packets`, and controls have no effect. Without it, the relay gets
+//! the audio in clear and any `e2e=<public key>` a listener asks for is between it and the relay.
+
+use std::error::Error;
+use std::io::Write;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::Credentials;
+use crate::broadcast::Broadcast;
+use crate::client;
+use crate::handshake::Handshake;
+use crate::packet::Packet;
+use crate::tls;
+use crate::transport::Address;
+
+// Before trying the upstream server again, doubling up to the max while it keeps failing
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+// An upstream stream that lasted this long was fine, the delay goes back to RETRY_DELAY
+const HEALTHY_STREAM: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct RelaySettings {
+    /// The server to restream, None for no relay.
+    pub upstream: Option<Address>,
+    /// What to ask the upstream server for.
+    pub handshake: String,
+    pub tls: Option<tls::ClientSettings>,
+    pub credentials: Option<Credentials>,
+}
+
+impl Default for RelaySettings {
+    fn default() -> RelaySettings {
+        RelaySettings {
+            upstream: None,
+            handshake: "stream mic 3600s".to_string(),
+            tls: None,
+            credentials: None,
+        }
+    }
+}
+
+pub struct Relay {
+    broadcast: Arc<Broadcast>,
+    /// Whether upstream is asked for an e2e=group stream.
+    sealed: bool,
+    /// Upstream's latest 'K' packet, which listeners joining later need first.
+    key: Arc<Mutex<Option<Packet>>>,
+}
+
+impl Relay {
+
+    /// Starts the relay thread, it connects upstream when the first listener comes.
+    pub fn start(upstream: Address, settings: RelaySettings) -> Result<Arc<Relay>, String> {
+        // Upstream always sends us packets, whatever our listeners want
+        let handshake = Handshake::parse(&settings.handshake)?.with("wire", "packets");
+        let sealed = match handshake.get("e2e") {
+            Some("group") => true,
+            Some(_) => return Err("relay_handshake can only ask for e2e=group".to_string()),
+            None => false,
+        };
+        let relay = Arc::new(Relay {
+            broadcast: Arc::new(Broadcast::new()),
+            sealed,
+            key: Arc::new(Mutex::new(None)),
+        });
+        let thread_relay = relay.clone();
+        std::thread::spawn(move || {
+            run(&thread_relay, &upstream, &handshake, &settings);
+        });
+
+        Ok(relay)
+    }
+
+    pub fn subscribe(&self) -> Receiver<Packet> {
+        // Under the lock, so a new key can't come in between
+        let key = self.key.lock().unwrap();
+        match *key {
+            Some(ref key) => self.broadcast.subscribe_with(key.clone()),
+            None => self.broadcast.subscribe(),
+        }
+    }
+
+    /// Whether what the relay passes on is encrypted, e2e=group.
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+}
+
+/// The relay thread.
+fn run(relay: &Relay, upstream: &Address, handshake: &Handshake, settings: &RelaySettings) {
+    let broadcast = &relay.broadcast;
+    let mut retry_delay = RETRY_DELAY;
+    loop {
+        if broadcast.listener_count() == 0 {
+            retry_delay = RETRY_DELAY;
+            std::thread::sleep(Duration::from_millis(100));
+            continue;
+        }
+        println!("Relay: connecting to {}", upstream);
+        let started = Instant::now();
+        match pull(relay, upstream, handshake, settings) {
+            Ok(()) => println!("Relay: upstream stream over."),
+            Err(e) => println!("Relay: {}", e),
+        }
+        // Good for that stream only
+        *relay.key.lock().unwrap() = None;
+        if broadcast.listener_count() == 0 {
+            continue;
+        }
+
+        // Don't hammer an upstream server that is down, or that ends streams straight away
+        if started.elapsed() >= HEALTHY_STREAM {
+            retry_delay = RETRY_DELAY;
+        }
+        println!("Relay: reconnecting in {}s", retry_delay.as_secs());
+        std::thread::sleep(retry_delay);
+        retry_delay = std::cmp::min(retry_delay * 2, MAX_RETRY_DELAY);
+    }
+}
+
+/// Restreams from upstream until it's done, or there are no listeners left.
+fn pull(relay: &Relay, upstream: &Address, handshake: &Handshake, settings: &RelaySettings)
+    -> Result<(), Box<dyn Error>> {
+    let broadcast = &relay.broadcast;
+    let mut stream = client::connect(upstream, settings.tls.as_ref(), settings.credentials.as_ref())?;
+    stream.write_all(handshake.to_line().as_bytes())?;
+
+    loop {
+        if broadcast.listener_count() == 0 {
+            stream.shutdown().ok();
+            return Ok(());
+        }
+        match Packet::read_from(&mut stream)? {
+            Packet::End => return Ok(()),
+            Packet::Key(key) if relay.sealed => {
+                let mut latest = relay.key.lock().unwrap();
+                *latest = Some(Packet::Key(key));
+                broadcast.send(latest.as_ref().unwrap());
+            }
+            packet @ Packet::Sealed { .. } if relay.sealed => broadcast.send(&packet),
+            // Nothing in clear when we asked for encryption, nothing encrypted when we didn't
+            _ if relay.sealed => {}
+            Packet::Key(_) | Packet::Sealed { .. } => {}
+            packet => broadcast.send(&packet),
+        }
+    }
+}
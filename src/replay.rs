@@ -0,0 +1,120 @@
+//! Instant replay: the last few minutes of mic capture, kept in memory.
+//!
+//! Everything the mic captures goes into a circular buffer. Samples are addressed by their
+//! position since the server started, so a reader can follow the capture from any point still
+//! in the buffer (time-shifted listening), and the buffer can be dumped to a WAV file at any
+//! time. Memory is only taken as the buffer fills up.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub struct ReplayBuffer {
+    inner: Mutex<Inner>,
+    capacity: usize,
+    sample_rate: u32,
+}
+
+struct Inner {
+    samples: Vec<f32>,
+    // Samples written since the start, the next one goes to samples[written % capacity]
+    written: u64,
+}
+
+impl ReplayBuffer {
+
+    /// Buffer holding the last *seconds* of capture.
+    pub fn new(seconds: f64, sample_rate: u32) -> ReplayBuffer {
+        ReplayBuffer {
+            inner: Mutex::new(Inner { samples: Vec::new(), written: 0 }),
+            capacity: std::cmp::max(1, (seconds * sample_rate as f64) as usize),
+            sample_rate,
+        }
+    }
+
+    pub fn write(&self, samples: &[f32]) {
+        let mut inner = self.inner.lock().unwrap();
+        for &sample in samples {
+            if inner.samples.len() < self.capacity {
+                inner.samples.push(sample);
+            } else {
+                let index = (inner.written % self.capacity as u64) as usize;
+                inner.samples[index] = sample;
+            }
+            inner.written += 1;
+        }
+    }
+
+    /// Position of the next sample to be written.
+    pub fn position(&self) -> u64 {
+        self.inner.lock().unwrap().written
+    }
+
+    /// Position of the oldest sample still in the buffer.
+    pub fn oldest(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.written - inner.samples.len() as u64
+    }
+
+    /// Position *seconds* back from now, or the oldest one if that's gone already.
+    pub fn position_ago(&self, seconds: f64) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        let back = std::cmp::min((seconds * self.sample_rate as f64) as u64, inner.samples.len() as u64);
+        inner.written - back
+    }
+
+    /// Reads from *position* on into *buf*. If *position* is no longer in the buffer, reading
+    /// starts at the oldest sample instead. Returns the position actually read from and the
+    /// number of samples read, 0 when caught up with the capture.
+    pub fn read(&self, position: u64, buf: &mut [f32]) -> (u64, usize) {
+        let inner = self.inner.lock().unwrap();
+        let oldest = inner.written - inner.samples.len() as u64;
+        let position = std::cmp::max(position, oldest);
+        let available = std::cmp::min(inner.written.saturating_sub(position), buf.len() as u64) as usize;
+
+        for (i, sample) in buf[..available].iter_mut().enumerate() {
+            let index = ((position + i as u64) % self.capacity as u64) as usize;
+            *sample = inner.samples[index];
+        }
+        (position, available)
+    }
+
+    /// Saves the last *seconds* (everything if None) to *path*. The samples are copied out
+    /// right away and written on a separate thread, so capture carries on undisturbed.
+    pub fn dump(&self, path: &Path, seconds: Option<f64>) {
+        let start = match seconds {
+            Some(seconds) => self.position_ago(seconds),
+            None => self.oldest(),
+        };
+        let mut samples = vec![0.0; (self.position() - start) as usize];
+        let (_, len) = self.read(start, &mut samples);
+        samples.truncate(len);
+
+        let path: PathBuf = path.to_path_buf();
+        let sample_rate = self.sample_rate;
+        std::thread::spawn(move || {
+            match write_wav(&path, &samples, sample_rate) {
+                Ok(()) => println!("Replay of {:.1}s saved to {}",
+                                   samples.len() as f64 / sample_rate as f64, path.display()),
+                Err(e) => println!("Replay dump to {} failed: {}", path.display(), e),
+            }
+        });
+    }
+}
+
+fn write_wav(path: &Path, samples: &[f32], sample_rate: u32) -> Result<(), hound::Error> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for &sample in samples {
+        writer.write_sample(sample)?;
+    }
+    writer.finalize()
+}
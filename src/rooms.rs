@@ -0,0 +1,88 @@
+//! Rooms: independent conference mixes on one server, so several teams can each have their own
+//! channel.
+//!
+//! Uploads and mix listeners say which room they are in with "room=<name>" in the handshake, or
+//! are in "main". A room is opened when someone first joins it, with a mixer of its own (see
+//! mixer.rs): its own uploads, its own listeners, and the configured `mix_file` and
+//! `mix_generator` if any. It closes when the last of them leaves, except for "main" which is
+//! always open.
+//!
+//! "stream rooms 0s" lists the open rooms instead of streaming, one text line each, e.g.
+//! "main 3 listeners 2 uploads".
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::mixer::{Mixer, MixerSettings};
+
+pub const DEFAULT_ROOM: &str = "main";
+
+const MAX_ROOMS: usize = 64;
+const MAX_NAME_LENGTH: usize = 32;
+
+pub struct Rooms {
+    settings: MixerSettings,
+    media_dir: PathBuf,
+    rooms: Mutex<BTreeMap<String, Arc<Mixer>>>,
+}
+
+impl Rooms {
+
+    /// Opens the main room, which also checks the mixer settings.
+    pub fn new(settings: &MixerSettings, media_dir: &Path) -> Result<Rooms, String> {
+        let mut rooms = BTreeMap::new();
+        rooms.insert(DEFAULT_ROOM.to_string(), Mixer::start(DEFAULT_ROOM, settings, media_dir)?);
+
+        Ok(Rooms {
+            settings: settings.clone(),
+            media_dir: media_dir.to_path_buf(),
+            rooms: Mutex::new(rooms),
+        })
+    }
+
+    /// The mixer of the room called *name*, opening it if needed. Whoever joins keeps the room
+    /// open for as long as they hold on to it.
+    pub fn join(&self, name: &str) -> Result<Arc<Mixer>, String> {
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(format!("invalid room name: '{}'", name));
+        }
+
+        let mut rooms = self.rooms.lock().unwrap();
+        close_unused(&mut rooms);
+        if let Some(mixer) = rooms.get(name) {
+            return Ok(mixer.clone());
+        }
+        if rooms.len() >= MAX_ROOMS {
+            return Err("too many rooms".to_string());
+        }
+
+        let mixer = Mixer::start(name, &self.settings, &self.media_dir)?;
+        println!("Room {} opened", name);
+        rooms.insert(name.to_string(), mixer.clone());
+        Ok(mixer)
+    }
+
+    /// One line per open room: name, listeners and uploads.
+    pub fn list(&self) -> Vec<String> {
+        let mut rooms = self.rooms.lock().unwrap();
+        close_unused(&mut rooms);
+        rooms.iter()
+            .map(|(name, mixer)| {
+                format!("{} {} listeners {} uploads", name, mixer.listener_count(), mixer.upload_count())
+            })
+            .collect()
+    }
+}
+
+/// Closes the rooms nobody holds on to but us, which ends their mixer threads.
+fn close_unused(rooms: &mut BTreeMap<String, Arc<Mixer>>) {
+    rooms.retain(|name, mixer| {
+        let open = name == DEFAULT_ROOM || Arc::strong_count(mixer) > 1;
+        if !open {
+            println!("Room {} closed", name);
+        }
+        open
+    });
+}
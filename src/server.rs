@@ -1,94 +1,597 @@
 use std::thread;
-use std::net::{TcpListener, TcpStream, Shutdown};
-use std::io::{Read, Write};
-use std::f64::consts::PI;
+use std::io::{self, Write};
+use std::net::{TcpStream, Shutdown};
 
 extern crate portaudio;
 use portaudio as pa;
 
-use ringbuf;
-use hound::Error;
+use crate::control;
+use crate::processing::{Processor, StereoProcessor};
+use crate::config::{self, ServerConfig};
+use crate::packet::Packet;
+use crate::vad::Vad;
+use crate::echo::HowlDetector;
+use crate::handshake::{self, Handshake};
+use crate::auth::{self, User, Users};
+use crate::generator::{Generator, GeneratorSettings};
+use crate::media::{MediaSource, PcmFormat};
+use crate::wire::Wire;
+use crate::live::Live;
+use crate::http::{self, Request};
+use crate::websocket::{self, Message};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::session::Session;
+use crate::transport::{Address, Connection, Listener};
+use crate::tls::TlsAcceptor;
+use crate::guard::{Deadline, Guard};
+use crate::e2e::GroupKey;
+use crate::relay::Relay;
+use crate::mixer::Mixer;
+use crate::rooms::{self, Rooms};
+use crate::recorder::{self, Recorder};
+use crate::jukebox::{Jukebox, Playlist};
+use crate::replay::ReplayBuffer;
+use crate::archive::Archive;
+use crate::control::Control;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError};
 
 const RINGBUFFER_SIZE:usize = 5000;
 
 const SAMPLE_RATE: f64 = 44_100.0;
-const FRAMES: u32 = 256;
 const CHANNELS: i32 = 1;
 const INTERLEAVED: bool = true;
 const INPUT_FRAMES_PER_BUFFER: u32 = 256;
 
-// Sine Wave Parameters
-const TABLE_SIZE: usize = 100;
+// How far ahead of real time files are sent
+const FILE_LEAD: Duration = Duration::from_millis(50);
 
-pub(crate) fn run_server() -> Result<(), Box::<std::error::Error>> {
-    return Ok(());
+// Before accepting again after an error
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
 
-    let listener = TcpListener::bind("0.0.0.0:3333").unwrap();
+// Longest silence an upload may ask for at once
+const MAX_UPLOAD_SILENCE: u32 = 44_100;
+
+// Instant replay dumps, in the record directory
+const REPLAY_TEMPLATE: &str = "replay_{timestamp}_{session}.wav";
+
+/// What every connection thread shares.
+struct ServerState {
+    config: ServerConfig,
+    jukebox: Option<Arc<Jukebox>>,
+    /// The last minutes of the live capture.
+    replay: Option<ReplayBuffer>,
+    archive: Option<Archive>,
+    tls: Option<TlsAcceptor>,
+    /// Who may connect, and to what. Everyone to everything when authentication is off.
+    users: Option<Users>,
+    guard: Arc<Guard>,
+    relay: Option<Arc<Relay>>,
+    /// The conference mixes, when mixing is enabled.
+    rooms: Option<Rooms>,
+    next_session_id: AtomicU64,
+}
+
+pub(crate) fn run_server(address: &Address) -> Result<(), Box::<dyn std::error::Error>> {
+    let config = ServerConfig::load(config::CONFIG_PATH)?;
+
+    // The jukebox plays as soon as there is someone listening
+    let jukebox = match config.playlist {
+        Some(ref path) => Some(Jukebox::start(Playlist::load(path)?, config.media_dir.clone())),
+        None => None,
+    };
+    let replay = if config.replay_secs > 0.0 {
+        Some(ReplayBuffer::new(config.replay_secs, SAMPLE_RATE as u32))
+    } else {
+        None
+    };
+    let archive = if config.archive.enabled {
+        Some(Archive::start(&config.archive, SAMPLE_RATE as u32)?)
+    } else {
+        None
+    };
+    let tls = if config.tls.enabled {
+        Some(TlsAcceptor::new(&config.tls)?)
+    } else {
+        None
+    };
+    let users = if config.auth.enabled {
+        Some(Users::load(&config.auth.users)?)
+    } else {
+        None
+    };
+    let relay = match config.relay.upstream {
+        Some(ref upstream) => Some(Relay::start(upstream.clone(), config.relay.clone())?),
+        None => None,
+    };
+    let rooms = if config.mixer.enabled {
+        Some(Rooms::new(&config.mixer, &config.media_dir)?)
+    } else {
+        None
+    };
+    let guard = Arc::new(Guard::new(&config.guard));
+    let http_port = config.http_port;
+    let live = Live::start(config.agc.clone());
+    let state = Arc::new(ServerState {
+        config,
+        jukebox,
+        replay,
+        archive,
+        tls,
+        users,
+        guard: guard.clone(),
+        relay,
+        rooms,
+        next_session_id: AtomicU64::new(1),
+    });
+
+    // The instant replay and the archive keep listening to the live capture, so it runs all
+    // the time and doesn't depend on who is connected
+    if state.replay.is_some() || state.archive.is_some() {
+        let capture = live.subscribe();
+        let capture_state = state.clone();
+        thread::spawn(move || feed_capture(&capture, &capture_state));
+    }
+
+    // Browsers and media players listen to the live mic over HTTP, or get a session of their
+    // own over WebSocket
+    if http_port != 0 {
+        let authorize_state = state.clone();
+        let authorize = Arc::new(move |request: &Request| authorize_stream(request, &authorize_state));
+        let websocket_state = state.clone();
+        let on_websocket = Arc::new(move |stream: TcpStream, request: Request| {
+            let session_id = websocket_state.next_session_id.fetch_add(1, Ordering::SeqCst);
+            handle_websocket(stream, &request, session_id, &websocket_state);
+        });
+        thread::spawn(move || {
+            if let Err(e) = http::run(http_port, live, guard, authorize, on_websocket) {
+                println!("HTTP server stopped: {}", e);
+            }
+        });
+    }
+
+    let listener = Listener::bind(address)?;
     // accept connections and process them, spawning a new thread for each one
-    println!("Server listening on port 3333");
+    println!("Server listening on {}", address);
 
-    for result in listener.incoming() {
-        if result.is_err() {
-            break;
+    loop {
+        let mut stream = match listener.accept() {
+            Ok(stream) => stream,
+            // An in-process channel with nobody left to connect
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => break,
+            // Out of file descriptors or a connection reset before we got it, which a flood
+            // causes: give it a moment rather than give up
+            Err(e) => {
+                println!("Accept failed: {}", e);
+                thread::sleep(ACCEPT_RETRY);
+                continue;
+            }
+        };
+        let ticket = match state.guard.admit(stream.peer_ip()) {
+            Ok(ticket) => ticket,
+            Err(e) => {
+                println!("Refused connection from {}: {}", stream.peer(), e);
+                stream.shutdown().ok();
+                continue;
+            }
+        };
+        println!("New connection: {}", stream.peer());
+
+        let session_id = state.next_session_id.fetch_add(1, Ordering::SeqCst);
+        let state = state.clone();
+        thread::spawn(move || {
+            // Counts as open until the session is over
+            let _ticket = ticket;
+            handle_connection(stream, session_id, &state);
+        });
+    }
+    // close the socket listener
+    drop(listener);
+
+    Ok(())
+}
+
+fn handle_connection(stream: Connection, session_id: u64, state: &ServerState) {
+    // Everything up to the handshake must be quick, so idle connections don't hang around
+    if stream.set_read_timeout(Some(state.guard.handshake_timeout())).is_err() {
+        return;
+    }
+    let deadline = match stream.try_clone() {
+        Ok(mut socket) => Deadline::start(state.guard.handshake_timeout(), move || {
+            println!("Handshake with {} timed out.", socket.peer());
+            socket.shutdown().ok();
+        }),
+        Err(_) => return,
+    };
+
+    // The TLS handshake comes first, on the connection's own thread
+    let mut stream = match state.tls {
+        Some(ref tls) => {
+            let peer = stream.peer();
+            match tls.accept(stream) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            }
         }
-        // Connection succeeded
-        let mut stream = result.unwrap();
-        println!("New connection: {}", stream.peer_addr().unwrap());
+        None => stream,
+    };
 
-        //=========================================
-        // Series of checks to verify protocol
+    //=========================================
+    // Authenticate if required (see auth.rs), then read the handshake:
+    // "stream <mode> <seconds>s [key=value...]"
 
-        let mut data = [0 as u8; 14]; // read 14 byte header
-        if stream.read_exact(&mut data).is_err() {
-            stream.shutdown(Shutdown::Both).unwrap();
-            break;
+    let peer = stream.peer();
+    let mut line = handshake::read_line(&mut stream);
+    let user = match (&state.users, &line) {
+        (Some(users), Ok(first_line)) => {
+            let mut writer = match stream.try_clone() {
+                Ok(writer) => writer,
+                Err(_) => return,
+            };
+            let result = users.authenticate(
+                &peer,
+                first_line,
+                &mut || handshake::read_line(&mut stream),
+                &mut |reply| writer.write_all(format!("{}\n", reply).as_bytes()));
+            match result {
+                Ok(user) => {
+                    println!("Authenticated {} from {}", user.identity, peer);
+                    line = handshake::read_line(&mut stream);
+                    Some(user)
+                }
+                Err(e) => {
+                    println!("Rejected connection from {}", e);
+                    stream.shutdown().ok();
+                    return;
+                }
+            }
         }
+        _ => None,
+    };
 
-        // Check start and end of header
-        if !data.starts_with(b"stream") || !data.ends_with(b"s") {
-            stream.shutdown(Shutdown::Both).unwrap();
-            break;
+    let handshake = match line.map_err(|e| e.to_string()).and_then(|line| Handshake::parse(&line)) {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            println!("Invalid handshake: {}", e);
+            stream.shutdown().ok();
+            return;
         }
+    };
+    if !authorized(user, &handshake, &peer) {
+        stream.shutdown().ok();
+        return;
+    }
+    drop(deadline);
+    if stream.set_read_timeout(None).is_err() {
+        return;
+    }
 
-        // Check "choice" between mic and sine audio input
-        let choice = &data[7..10];
-        let str_result = std::str::from_utf8(&data[11..13]);
-        if str_result.is_err() {
-            println!("Invalid message.");
-            stream.shutdown(Shutdown::Both).unwrap();
-            break;
+    // Framed packets, or a WAV file anything can play
+    let wire = match Wire::from_handshake(&handshake) {
+        Ok(wire) => wire,
+        Err(e) => {
+            println!("Invalid handshake: {}", e);
+            stream.shutdown().ok();
+            return;
         }
-        let string = str_result.unwrap();
+    };
 
-        let str_parse_result = string.parse();
-        if str_parse_result.is_err() {
-            println!("Invalid time");
-            stream.shutdown(Shutdown::Both).unwrap();
-            break;
+    // Control messages from the client come in on the same connection.
+    let control_receiver = if handshake.mode == "upload" || handshake.mode == "duplex" {
+        // Uploads send audio up it instead, and have no control messages
+        mpsc::channel().1
+    } else {
+        match stream.try_clone() {
+            Ok(clone) => control::spawn_reader(clone),
+            Err(e) => {
+                println!("Could not read control messages: {}", e);
+                return;
+            }
         }
+    };
 
-        let audio_msg_length: f64 = str_parse_result.unwrap();
+    serve(Session::new(session_id, stream, control_receiver), &handshake, wire, user, state);
+}
 
-        println!("Length: {}.", audio_msg_length);
+/// A browser connecting to /ws: same as a TCP connection, over WebSocket messages.
+fn handle_websocket(mut stream: TcpStream, request: &Request, session_id: u64, state: &ServerState) {
+    if let Err(e) = websocket::accept(&mut stream, request) {
+        println!("WebSocket upgrade failed: {}", e);
+        return;
+    }
 
-        if choice.eq(b"sin") {
+    // The first message is the handshake line, or the authentication that comes before it
+    let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+    if stream.set_read_timeout(Some(state.guard.handshake_timeout())).is_err() {
+        return;
+    }
+    let deadline = match stream.try_clone() {
+        Ok(socket) => Deadline::start(state.guard.handshake_timeout(), move || {
+            println!("WebSocket handshake timed out.");
+            socket.shutdown(Shutdown::Both).ok();
+        }),
+        Err(_) => return,
+    };
+    let mut line = read_text_message(&mut stream);
+    let user = match (&state.users, &line) {
+        (Some(users), Ok(first_line)) => {
+            let mut writer = match stream.try_clone() {
+                Ok(writer) => writer,
+                Err(_) => return,
+            };
+            let result = users.authenticate(
+                &peer,
+                first_line,
+                &mut || read_text_message(&mut stream),
+                &mut |reply| websocket::write_message(&mut writer, &Message::Text(reply.to_string())));
+            match result {
+                Ok(user) => {
+                    println!("Authenticated {} from {}", user.identity, peer);
+                    line = read_text_message(&mut stream);
+                    Some(user)
+                }
+                Err(e) => {
+                    println!("Rejected WebSocket connection from {}", e);
+                    websocket::write_message(&mut stream, &Message::Close).ok();
+                    stream.shutdown(Shutdown::Both).ok();
+                    return;
+                }
+            }
+        }
+        _ => None,
+    };
 
-            println!("Choose play sine");
-            stream_sine(&mut stream, audio_msg_length as f32);
-        } else if choice.eq(b"mic") {
+    let handshake = match line {
+        Ok(line) => Handshake::parse(&line),
+        Err(e) => Err(e.to_string()),
+    };
+    let handshake = match handshake {
+        Ok(handshake) if authorized(user, &handshake, &peer) => handshake,
+        Ok(_) => {
+            websocket::write_message(&mut stream, &Message::Close).ok();
+            stream.shutdown(Shutdown::Both).ok();
+            return;
+        }
+        Err(e) => {
+            println!("Invalid handshake: {}", e);
+            websocket::write_message(&mut stream, &Message::Close).ok();
+            stream.shutdown(Shutdown::Both).ok();
+            return;
+        }
+    };
 
+    if handshake.mode == "upload" || handshake.mode == "duplex" {
+        println!("Uploads are not supported over WebSocket.");
+        websocket::write_message(&mut stream, &Message::Close).ok();
+        stream.shutdown(Shutdown::Both).ok();
+        return;
+    }
+    drop(deadline);
+    if stream.set_read_timeout(None).is_err() {
+        return;
+    }
+
+    let (control_receiver, pings) = match stream.try_clone() {
+        Ok(clone) => websocket::spawn_reader(clone),
+        Err(e) => {
+            println!("Could not read control messages: {}", e);
+            return;
+        }
+    };
+
+    let mut session = Session::new(session_id, Connection::Tcp(stream), control_receiver);
+    session.answer_pings(pings);
+    serve(session, &handshake, Wire::WebSocket, user, state);
+}
+
+/// The next WebSocket message, which must be text.
+fn read_text_message(stream: &mut TcpStream) -> io::Result<String> {
+    match websocket::read_message(stream)? {
+        Message::Text(line) => Ok(line),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "expected a text message")),
+    }
+}
+
+/// Whether *user* (None when authentication is off) may stream what *handshake* asks for.
+fn authorized(user: Option<&User>, handshake: &Handshake, peer: &str) -> bool {
+    match user {
+        Some(user) if !user.allows(&handshake.mode) => {
+            println!("Rejected {} from {}: mode '{}' not allowed", user.identity, peer, handshake.mode);
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Whether an HTTP request may listen to the live mic: anyone when authentication is off,
+/// otherwise a user allowed the "mic" mode, with a good token.
+fn authorize_stream(request: &Request, state: &ServerState) -> Result<(), String> {
+    let users = match state.users {
+        Some(ref users) => users,
+        None => return Ok(()),
+    };
+    let identity = request.query("user").ok_or("no user")?;
+    let user = users.check_token(identity, request.query("token").unwrap_or(""))?;
+    if !user.allows("mic") {
+        return Err(format!("mode 'mic' not allowed for {}", user.identity));
+    }
+    Ok(())
+}
+
+/// Streams what the handshake asks for, until done or the client goes away. *user* is who
+/// logged in, None when authentication is off.
+fn serve(mut session: Session, handshake: &Handshake, wire: Wire, user: Option<&User>, state: &ServerState) {
+    let config = &state.config;
+    let audio_msg_length = handshake.duration as f64;
+    println!("Length: {}.", audio_msg_length);
+
+    if let Err(e) = session.set_wire(wire, SAMPLE_RATE as u32) {
+        println!("Could not send the stream header: {}", e);
+        return;
+    }
+
+    // Not a stream, just the list of rooms as text
+    if handshake.mode == "rooms" {
+        let rooms = state.rooms.as_ref().map(|rooms| rooms.list()).unwrap_or_default();
+        for line in rooms {
+            if session.send_text(&line).is_err() {
+                break;
+            }
+        }
+        session.close();
+        return;
+    }
+
+    // End-to-end encryption, if the client sent its half of the key exchange
+    if let Some(public) = handshake.get("e2e") {
+        let result = match (auth::from_hex(public), user) {
+            // The upload itself would go in clear
+            _ if handshake.mode == "upload" || handshake.mode == "duplex" => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "uploads can't be encrypted"))
+            }
+            // A relay passes on what the origin server encrypted, see stream_relay()
+            _ if public == "group" && handshake.mode == "relay" => session.check_packets(),
+            _ if public == "group" => match config.e2e_group_key {
+                Some(ref group_key) => session.start_group_e2e(&GroupKey::new(group_key)),
+                None => Err(io::Error::new(io::ErrorKind::InvalidInput, "no e2e_group_key here")),
+            },
+            // Nothing to vouch for our key with
+            (_, None) => Err(io::Error::new(io::ErrorKind::InvalidInput, "encryption needs authentication")),
+            (Some(public), Some(user)) => session.start_e2e(&public, user.psk()),
+            (None, _) => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid e2e key")),
+        };
+        if let Err(e) = result {
+            println!("Could not set up encryption with {}: {}", session.peer, e);
+            session.close();
+            return;
+        }
+        println!("Encrypting for {}", session.peer);
+    }
+
+    if config.record_enabled {
+        let name = recorder::file_name_from_template(&config.record_template, &session.peer, session.id);
+        match Recorder::start(&config.record_dir.join(name), SAMPLE_RATE as u32) {
+            Ok(recorder) => session.recorder = Some(recorder),
+            Err(e) => println!("Could not start recording: {}", e),
+        }
+    }
+
+    let result = match handshake.mode.as_str() {
+        "sin" | "gen" => {
+            match GeneratorSettings::from_handshake(handshake) {
+                Ok(settings) => {
+                    println!("Choose play generator: {:?}", settings.waveform);
+                    stream_generator(&mut session, audio_msg_length, settings)
+                        .map_err(|e| e.into())
+                }
+                Err(e) => Err(e.into()),
+            }
+        }
+        "mic" => {
             println!("Choose play mic");
-            stream_mic(&mut stream, audio_msg_length);
-        } else {
-            stream.shutdown(Shutdown::Both).unwrap();
-            break;
+            stream_mic(&mut session, audio_msg_length, state)
+        }
+        "file" => {
+            // Raw PCM has no header, its format comes with the request
+            let raw = match handshake.get("format").map(PcmFormat::parse) {
+                Some(Ok(format)) => Ok(Some(format)),
+                Some(Err(e)) => Err(e),
+                None => Ok(None),
+            };
+            match (media_path(&config.media_dir, handshake.get("file").unwrap_or("")), raw) {
+                (Ok(path), Ok(raw)) => {
+                    println!("Choose play file: {}", path.display());
+                    stream_file(&mut session, &path, raw.as_ref(), audio_msg_length)
+                }
+                (Err(e), _) | (_, Err(e)) => Err(e.into()),
+            }
+        }
+        "replay" => {
+            // "offset" is how many seconds back to start from
+            match handshake.get("offset").unwrap_or("60").parse::<f64>() {
+                Ok(offset) => {
+                    println!("Choose play replay from {}s ago", offset);
+                    stream_replay(&mut session, audio_msg_length, offset, state)
+                        .map_err(|e| e.into())
+                }
+                Err(_) => Err("invalid replay offset".into()),
+            }
+        }
+        "jukebox" => {
+            match state.jukebox {
+                Some(ref jukebox) => {
+                    println!("Choose play jukebox");
+                    stream_jukebox(&mut session, jukebox).map_err(|e| e.into())
+                }
+                None => Err("no playlist configured".into()),
+            }
+        }
+        "relay" => {
+            match state.relay {
+                // Encrypted upstream, nothing but encrypted packets to give
+                Some(ref relay) if relay.is_sealed() != (handshake.get("e2e") == Some("group")) => {
+                    Err(if relay.is_sealed() {
+                        "this relay only passes on an e2e=group stream"
+                    } else {
+                        "this relay's upstream is not e2e=group"
+                    }.into())
+                }
+                Some(ref relay) => {
+                    println!("Choose play relay");
+                    stream_relay(&mut session, relay).map_err(|e| e.into())
+                }
+                None => Err("no relay upstream configured".into()),
+            }
+        }
+        "upload" => {
+            match join_room(handshake, state) {
+                Ok(mixer) => {
+                    println!("Choose upload to room {}", mixer.name);
+                    receive_upload(&mut session, &mixer, handshake)
+                }
+                Err(e) => Err(e.into()),
+            }
         }
+        "duplex" => {
+            match join_room(handshake, state) {
+                Ok(mixer) => {
+                    println!("Choose talk in room {}", mixer.name);
+                    stream_duplex(&mut session, &mixer, handshake)
+                }
+                Err(e) => Err(e.into()),
+            }
+        }
+        "mix" => {
+            match join_room(handshake, state) {
+                Ok(mixer) => {
+                    println!("Choose play room {}", mixer.name);
+                    stream_mix(&mut session, &mixer).map_err(|e| e.into())
+                }
+                Err(e) => Err(e.into()),
+            }
+        }
+        mode => Err(format!("unknown mode: {}", mode).into()),
+    };
+
+    // Let the client know we're done
+    match result {
+        Ok(()) => { session.send(&Packet::End).ok(); }
+        Err(e) => println!("Stream ended with error: {}", e),
     }
-    // close the socket listener
-    drop(listener);
+    session.close();
+    // (dropping the session finalizes the recording)
 }
 
-fn stream_mic(tcp_stream: &mut TcpStream, mut duration: f64) -> Result<(), Box<dyn std::error::Error>> {
+fn stream_mic(session: &mut Session, mut duration: f64, state: &ServerState)
+    -> Result<(), Box<dyn std::error::Error>> {
+    let agc_settings = &state.config.agc;
+    let vad_settings = &state.config.vad;
+
     // Launch PortAudio
     let pa = pa::PortAudio::new()?;
 
@@ -101,7 +604,7 @@ fn stream_mic(tcp_stream: &mut TcpStream, mut duration: f64) -> Result<(), Box<d
         def_input, CHANNELS, INTERLEAVED, latency);
 
     pa.is_input_format_supported(input_params, SAMPLE_RATE)?;
-    let mut input_settings = pa::InputStreamSettings::new(
+    let input_settings = pa::InputStreamSettings::new(
         input_params, SAMPLE_RATE, INPUT_FRAMES_PER_BUFFER);
 
     // Create audio -> tcp ringbuffer
@@ -111,6 +614,18 @@ fn stream_mic(tcp_stream: &mut TcpStream, mut duration: f64) -> Result<(), Box<d
     // Create message channel
     let (msg_sender, msg_receiver) = ::std::sync::mpsc::channel();
 
+    // Processing stage, and a buffer to process into (don't allocate in the callback)
+    let (control_sender, control_receiver) = ::std::sync::mpsc::channel();
+    let mut processor = Processor::with_agc(agc_settings);
+    let mut processed = vec![0.0; INPUT_FRAMES_PER_BUFFER as usize];
+
+    // When a client plays in the same room, the mic picks it up again.
+    let mut howl_detector = if state.config.howl_detector {
+        Some(HowlDetector::new())
+    } else {
+        None
+    };
+
     // Define callback -> send input stream into ringbuffer
     let input_stream_callback = move |pa::InputStreamCallbackArgs {
                                           buffer,
@@ -125,8 +640,19 @@ fn stream_mic(tcp_stream: &mut TcpStream, mut duration: f64) -> Result<(), Box<d
             std::thread::sleep(std::time::Duration::from_millis(1));
         }
 
+        // Apply AGC / gain / mute / limiter
+        while let Ok(control) = control_receiver.try_recv() {
+            processor.apply(&control);
+        }
+        let processed = &mut processed[..frames];
+        processed.copy_from_slice(buffer);
+        if let Some(howl_detector) = howl_detector.as_mut() {
+            howl_detector.process(processed);
+        }
+        processor.process(processed);
+
         // Push audio to the RingBuffer
-        rb_producer.push_slice(buffer);
+        rb_producer.push_slice(processed);
 
         if duration > 0.0 {
             pa::Continue
@@ -144,14 +670,41 @@ fn stream_mic(tcp_stream: &mut TcpStream, mut duration: f64) -> Result<(), Box<d
     const BUFFER_LENGTH:usize = 1000;
     let mut data:[f32;BUFFER_LENGTH / 4] = [0.0; BUFFER_LENGTH / 4];
 
+    // Voice activity detection, silence is not sent when enabled
+    let mut vad = Vad::new(vad_settings);
+
     // Start the audio input stream
     input_stream.start()?;
 
     // Loop while the non-blocking stream is active.
-    while let true = input_stream.is_active()? {
+    while input_stream.is_active()? {
         // Transfer data from the RingBuffer to the TCP Stream !
-        rb_consumer.pop_slice(&mut data);
-        tcp_stream.write_all(f32_to_u8(&data))?;
+        let len = rb_consumer.pop_slice(&mut data);
+        if len == 0 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            continue;
+        }
+        let block = &data[..len];
+
+        if let Some(speech) = vad.process(block) {
+            println!("VAD: {}", if speech { "talking" } else { "silent" });
+            session.send(&Packet::Vad(speech))?;
+        }
+
+        if vad_settings.enabled && !vad.is_speech() {
+            session.record(block);
+            session.send(&Packet::Silence { frames: len as u32, level: vad.noise_level() })?;
+        } else {
+            session.send_audio(block)?;
+        }
+
+        // Pass control messages on to the callback
+        while let Ok(control) = session.control_receiver.try_recv() {
+            match control {
+                Control::Dump(seconds) => dump_replay(session, seconds, state),
+                control => { control_sender.send(control).ok(); }
+            }
+        }
 
         // Pass countdown message to the msg channel.
         while let Ok(count_down) = msg_receiver.try_recv() {
@@ -165,69 +718,357 @@ fn stream_mic(tcp_stream: &mut TcpStream, mut duration: f64) -> Result<(), Box<d
     Ok(())
 }
 
-fn stream_sine(stream: &mut TcpStream, mut duration: f32) -> std::io::Result<()> {
-    // Create sin table
-    let mut sine = [0.0; TABLE_SIZE];
-    for i in 0..TABLE_SIZE {
-        sine[i] = (i as f64 / TABLE_SIZE as f64 * PI * 4.0).sin() as f32; // 2x freq sounds better...
-    }
+fn stream_generator(session: &mut Session, duration: f64, settings: GeneratorSettings)
+    -> std::io::Result<()> {
+    let mut generator = Generator::new(settings, SAMPLE_RATE);
 
     const BUFFER_LENGTH:usize = 1000;
 
     // Write to stream
-    let data = &mut [0 as u8; BUFFER_LENGTH];
+    let samples = &mut [0.0f32; BUFFER_LENGTH / 4];
+    let mut processor = Processor::new();
+    let mut frames_left = (duration * SAMPLE_RATE) as usize;
+
+    while frames_left > 0 {
+        let len = std::cmp::min(frames_left, samples.len());
+        let samples = &mut samples[..len];
+        generator.fill(samples);
+
+        // Apply gain / mute / limiter
+        while let Ok(control) = session.control_receiver.try_recv() {
+            processor.apply(&control);
+        }
+        processor.process(samples);
+
+        session.send_audio(samples)?;
+
+        frames_left -= len;
+        println!("duration left: {}", frames_left as f64 / SAMPLE_RATE);
+    }
+
+    Ok(())
+}
+
+/// Streams a file from the media directory, paced at real time.
+/// A *duration* of 0 plays the whole file. The client can pause, resume, seek and change the
+/// playback rate; *duration* counts what is actually played.
+fn stream_file(session: &mut Session, path: &Path, raw: Option<&PcmFormat>, duration: f64)
+    -> Result<(), Box<dyn std::error::Error>> {
+    let mut source = MediaSource::open(path, SAMPLE_RATE, raw)?;
+    if let Some(length) = source.duration_secs() {
+        println!("File length: {:.1}s", length);
+    }
+
+    const BUFFER_LENGTH:usize = 1000;
+
+    let samples = &mut [0.0f32; BUFFER_LENGTH / 4];
+    let mut processor = Processor::new();
+
+    let max_frames = if duration > 0.0 { (duration * SAMPLE_RATE) as usize } else { usize::MAX };
+    let mut frames_sent = 0;
+    let mut paused = false;
+
+    // Pacing starts over after a pause or a seek
+    let mut start = Instant::now();
+    let mut paced_frames = 0;
 
     loop {
-        let size_left = fill_buffer_with_table_loop(&mut data[..], &sine, duration);
-        duration = size_left;
-        println!("duration left: {}", duration);
+        // Transport controls, the others go to gain / mute / limiter
+        while let Ok(control) = session.control_receiver.try_recv() {
+            match control {
+                Control::Pause => paused = true,
+                Control::Resume => paused = false,
+                Control::Seek(seconds) => {
+                    println!("Seek to {:.1}s", seconds);
+                    source.seek(seconds)?;
+                    // Don't let the client play what it still has from before the seek
+                    session.send(&Packet::Flush)?;
+                    start = Instant::now();
+                    paced_frames = 0;
+                }
+                Control::Rate(rate) => source.set_rate(rate),
+                control => processor.apply(&control),
+            }
+        }
 
-        stream.write_all(&data[..])?;
+        if paused {
+            std::thread::sleep(Duration::from_millis(10));
+            start = Instant::now();
+            paced_frames = 0;
+            continue;
+        }
 
-        if duration < 0.0 {
-            // todo close message
+        let len = source.read(&mut samples[..])?;
+        if len == 0 {
             break;
         }
+        let block = &mut samples[..len];
+        processor.process(block);
+
+        session.send_audio(block)?;
+        frames_sent += len;
+        paced_frames += len;
+
+        if len < BUFFER_LENGTH / 4 || frames_sent >= max_frames {
+            println!("Finished streaming file.");
+            break;
+        }
+
+        // Pace at real time, staying a little ahead so the client never runs dry
+        let due = Duration::from_secs_f64(paced_frames as f64 / SAMPLE_RATE);
+        let elapsed = start.elapsed() + FILE_LEAD;
+        if due > elapsed {
+            std::thread::sleep(due - elapsed);
+        }
     }
 
     Ok(())
 }
 
+/// Writes the live capture to the instant replay buffer and the archive.
+fn feed_capture(capture: &mpsc::Receiver<Packet>, state: &ServerState) {
+    for packet in capture.iter() {
+        if let Packet::Audio(block) = packet {
+            if let Some(ref replay) = state.replay {
+                replay.write(&block);
+            }
+            if let Some(ref archive) = state.archive {
+                archive.write(&block);
+            }
+        }
+    }
+}
+
+/// Streams the mic capture from *offset* seconds ago, from the instant replay buffer.
+/// A *duration* of 0 plays until the point the replay was asked for.
+fn stream_replay(session: &mut Session, duration: f64, offset: f64, state: &ServerState)
+    -> std::io::Result<()> {
+    let replay = match state.replay {
+        Some(ref replay) => replay,
+        None => return Err(std::io::Error::other("instant replay is disabled")),
+    };
 
-/// Use this to fill *buffer* with looping *table*.
-/// Additionally, it subtracts the appropriate time from *size_in_secs* relative
-/// to the sample rate.
-fn fill_buffer_with_table_loop(buffer: &mut[u8], table: &[f32], mut size_in_secs: f32) -> f32 {
-    let table_u8 = f32_to_u8(table);
-    let mut index = 0;
+    let mut position = replay.position_ago(offset);
+    let max_frames = if duration > 0.0 {
+        (duration * SAMPLE_RATE) as u64
+    } else {
+        replay.position() - position
+    };
 
-    const SAMPLE_RATE:i32 = 44100; //Assuming 44.1K sample rate
-    let size_leftover_in_secs = size_in_secs - (buffer.len() as f32 / SAMPLE_RATE as f32 / 4.0);
-    let table_len_in_secs = table.len() as f32 / SAMPLE_RATE as f32;
+    const BUFFER_LENGTH:usize = 1000;
 
-    while size_in_secs > table_len_in_secs
-        && index + table_u8.len() < buffer.len()
-    {
-        // Copy table
-        buffer[index.. index + table_u8.len()].copy_from_slice(table_u8);
+    let samples = &mut [0.0f32; BUFFER_LENGTH / 4];
+    let mut processor = Processor::new();
+    let mut frames_sent: u64 = 0;
+    let start = Instant::now();
 
-        size_in_secs -= table_len_in_secs;
-        index += table.len();
+    while frames_sent < max_frames {
+        let len = std::cmp::min(samples.len() as u64, max_frames - frames_sent) as usize;
+        let (read_from, len) = replay.read(position, &mut samples[..len]);
+        if read_from > position {
+            println!("Replay fell behind, skipped {} samples.", read_from - position);
+        }
+        position = read_from + len as u64;
+
+        // Caught up with the capture, wait for more
+        if len == 0 {
+            std::thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+        let block = &mut samples[..len];
+
+        while let Ok(control) = session.control_receiver.try_recv() {
+            match control {
+                Control::Dump(seconds) => dump_replay(session, seconds, state),
+                control => processor.apply(&control),
+            }
+        }
+        processor.process(block);
+
+        session.send_audio(block)?;
+        frames_sent += len as u64;
+
+        // Pace at real time
+        let due = Duration::from_secs_f64(frames_sent as f64 / SAMPLE_RATE);
+        let elapsed = start.elapsed() + FILE_LEAD;
+        if due > elapsed {
+            std::thread::sleep(due - elapsed);
+        }
     }
-    if size_in_secs as i32 > 0 {
-        // Copy what's left of table
-        let leftover_len = buffer[index..].len();
-        buffer[index..].copy_from_slice(&table_u8[..leftover_len]);
+    println!("Finished streaming replay.");
+
+    Ok(())
+}
+
+/// Saves the instant replay buffer to the record directory ("dump" control message).
+fn dump_replay(session: &Session, seconds: Option<f64>, state: &ServerState) {
+    match state.replay {
+        Some(ref replay) => {
+            let name = recorder::file_name_from_template(REPLAY_TEMPLATE, &session.peer, session.id);
+            replay.dump(&state.config.record_dir.join(name), seconds);
+        }
+        None => println!("Instant replay is disabled."),
+    }
+}
+
+/// Listens to the jukebox until the client goes away. Jukebox control messages are passed on,
+/// the others apply to this listener only.
+fn stream_jukebox(session: &mut Session, jukebox: &Jukebox) -> std::io::Result<()> {
+    let receiver = jukebox.subscribe();
+    let mut processor = Processor::new();
+
+    loop {
+        while let Ok(control) = session.control_receiver.try_recv() {
+            if !jukebox.control(&control) {
+                processor.apply(&control);
+            }
+        }
+
+        match receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(Packet::Audio(mut samples)) => {
+                processor.process(&mut samples);
+                session.send_audio(&samples)?;
+            }
+            Ok(packet) => session.send(&packet)?,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+/// Passes on what the relay gets from upstream, until the client goes away. Encrypted 'K' and
+/// 'X' packets (e2e=group) go out as they came.
+fn stream_relay(session: &mut Session, relay: &Relay) -> std::io::Result<()> {
+    let receiver = relay.subscribe();
+    let mut processor = Processor::new();
+    let mut stereo_processor = StereoProcessor::new();
+
+    loop {
+        while let Ok(control) = session.control_receiver.try_recv() {
+            processor.apply(&control);
+            stereo_processor.apply(&control);
+        }
+
+        match receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(Packet::Audio(mut samples)) => {
+                processor.process(&mut samples);
+                session.send_audio(&samples)?;
+            }
+            // Upstream may be a mix
+            Ok(Packet::Stereo(mut samples)) => {
+                stereo_processor.process(&mut samples);
+                session.send_stereo(&samples)?;
+            }
+            Ok(packet) => session.send(&packet)?,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
     }
+}
 
-    size_leftover_in_secs
+/// The mixer of the room the handshake asks for ("room", the main room by default).
+fn join_room(handshake: &Handshake, state: &ServerState) -> Result<Arc<Mixer>, String> {
+    match state.rooms {
+        Some(ref rooms) => rooms.join(handshake.get("room").unwrap_or(rooms::DEFAULT_ROOM)),
+        None => Err("mixing is disabled".to_string()),
+    }
 }
 
-pub fn f32_to_u8(floats: &[f32]) -> &[u8] {
-    unsafe {
-        let bytes = floats.align_to::<u8>();
-        assert_eq!(bytes.0.len() + bytes.2.len(), 0);
-        assert_eq!(bytes.1.len(), floats.len() * 4);
-        bytes.1
+/// Takes what the client uploads into the mix, until it sends the end of stream or goes away.
+fn receive_upload(session: &mut Session, mixer: &Mixer, handshake: &Handshake)
+    -> Result<(), Box<dyn std::error::Error>> {
+    let (name, gain_db, pan) = upload_settings(handshake, &session.peer)?;
+    let input = mixer.add_input(&name, gain_db, pan);
+
+    while let Some(samples) = read_upload(&mut session.stream)? {
+        session.record(&samples);
+        input.push(&samples);
     }
-}
\ No newline at end of file
+    Ok(())
+}
+
+/// Same as receive_upload(), with the client hearing everyone else in the room on the same
+/// connection (the mix-minus, see mixer.rs).
+fn stream_duplex(session: &mut Session, mixer: &Mixer, handshake: &Handshake)
+    -> Result<(), Box<dyn std::error::Error>> {
+    let (name, gain_db, pan) = upload_settings(handshake, &session.peer)?;
+    let (input, mix_minus) = mixer.add_participant(&name, gain_db, pan);
+
+    // The upload comes in on its own thread. When it's over the input leaves the mix, which ends
+    // the mix-minus.
+    let mut stream = session.stream.try_clone()?;
+    thread::spawn(move || {
+        while let Ok(Some(samples)) = read_upload(&mut stream) {
+            input.push(&samples);
+        }
+    });
+
+    while let Ok(packet) = mix_minus.recv() {
+        match packet {
+            Packet::Stereo(samples) => session.send_stereo(&samples)?,
+            packet => session.send(&packet)?,
+        }
+    }
+    Ok(())
+}
+
+/// Who the upload is in the room and how it's mixed: "name" (the address by default), "gain"
+/// in dB and "pan" from the handshake.
+fn upload_settings(handshake: &Handshake, peer: &str) -> Result<(String, f32, f32), String> {
+    let gain_db: f32 = handshake.get("gain").unwrap_or("0").parse().ok()
+        .filter(|gain_db: &f32| gain_db.is_finite())
+        .ok_or("invalid gain")?;
+    let pan: f32 = handshake.get("pan").unwrap_or("0").parse().ok()
+        .filter(|pan| *pan >= -1.0 && *pan <= 1.0)
+        .ok_or("invalid pan")?;
+    let name = handshake.get("name").unwrap_or(peer).to_string();
+    Ok((name, gain_db, pan))
+}
+
+/// The next block of audio the client uploads, None at the end of the stream. Uploads are the
+/// same packets the server sends, the other way.
+fn read_upload(stream: &mut Connection) -> io::Result<Option<Vec<f32>>> {
+    loop {
+        match Packet::read_from(stream)? {
+            Packet::Audio(samples) => return Ok(Some(samples)),
+            Packet::Silence { frames, .. } => {
+                return Ok(Some(vec![0.0; std::cmp::min(frames, MAX_UPLOAD_SILENCE) as usize]));
+            }
+            Packet::End => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+/// Listens to the mix until the client goes away. Source gain and pan control messages go to
+/// the mixer, the others apply to this listener only.
+fn stream_mix(session: &mut Session, mixer: &Mixer) -> std::io::Result<()> {
+    let receiver = mixer.subscribe();
+    let mut processor = StereoProcessor::new();
+
+    loop {
+        while let Ok(control) = session.control_receiver.try_recv() {
+            if !mixer.control(&control) {
+                processor.apply(&control);
+            }
+        }
+
+        match receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(Packet::Stereo(mut samples)) => {
+                processor.process(&mut samples);
+                session.send_stereo(&samples)?;
+            }
+            Ok(packet) => session.send(&packet)?,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+/// Only plain file names are allowed, nothing outside the media directory.
+pub(crate) fn media_path(media_dir: &Path, name: &str) -> Result<PathBuf, String> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\\') {
+        return Err(format!("invalid file name: '{}'", name));
+    }
+    Ok(media_dir.join(name))
+}
@@ -0,0 +1,169 @@
+//! One client connection on the server side.
+//!
+//! Holds the connection, the control messages coming from the client and the recording of the
+//! session if there is one. All audio to the client goes through `send_audio`, so whatever
+//! the source, the recording gets exactly what was sent. It also turns packets into what the
+//! client asked for on the wire.
+
+use std::io::{self, Write};
+use std::sync::mpsc::Receiver;
+
+use ring::hmac;
+
+use crate::control::Control;
+use crate::e2e::{GroupKey, KeyExchange, Sealer};
+use crate::mixer;
+use crate::packet::Packet;
+use crate::recorder::Recorder;
+use crate::transport::Connection;
+use crate::vad::ComfortNoise;
+use crate::websocket::{self, Message};
+use crate::wire::{self, Wire};
+
+pub struct Session {
+    pub id: u64,
+    pub peer: String,
+    pub stream: Connection,
+    pub control_receiver: Receiver<Control>,
+    pub recorder: Option<Recorder>,
+    wire: Wire,
+    comfort_noise: ComfortNoise,
+    /// Set when the client asked for end-to-end encryption.
+    sealer: Option<Sealer>,
+    /// WebSocket pings waiting for their pong.
+    pings: Option<Receiver<Vec<u8>>>,
+}
+
+impl Session {
+
+    pub fn new(id: u64, stream: Connection, control_receiver: Receiver<Control>) -> Session {
+        let peer = stream.peer();
+
+        Session {
+            id,
+            peer,
+            stream,
+            control_receiver,
+            recorder: None,
+            wire: Wire::Packets,
+            comfort_noise: ComfortNoise::new(),
+            sealer: None,
+            pings: None,
+        }
+    }
+
+    /// Switches to *wire* and sends its header. Must come before anything else is sent.
+    pub fn set_wire(&mut self, wire: Wire, sample_rate: u32) -> io::Result<()> {
+        self.wire = wire;
+        self.stream.write_all(&wire.header(sample_rate))
+    }
+
+    pub fn send(&mut self, packet: &Packet) -> io::Result<()> {
+        let sealed;
+        let packet = match self.sealer {
+            Some(ref mut sealer) => {
+                sealed = sealer.seal(packet)?;
+                &sealed
+            }
+            None => packet,
+        };
+        match self.wire {
+            Wire::Packets => packet.write_to(&mut self.stream),
+            Wire::WebSocket => {
+                let mut bytes = Vec::new();
+                packet.write_to(&mut bytes)?;
+                self.write_message(&Message::Binary(bytes))
+            }
+            Wire::Wav { float } => match packet {
+                Packet::Audio(samples) => self.stream.write_all(&wire::pcm_bytes(samples, float)),
+                Packet::Stereo(samples) => {
+                    self.stream.write_all(&wire::pcm_bytes(&mixer::downmix(samples), float))
+                }
+                Packet::Silence { frames, level } => {
+                    let mut noise = vec![0.0; *frames as usize];
+                    self.comfort_noise.fill(&mut noise, *level);
+                    self.stream.write_all(&wire::pcm_bytes(&noise, float))
+                }
+                // No room for anything but audio in a WAV file
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /// Sends a line of text, for answers that are not a stream (the room list).
+    pub fn send_text(&mut self, line: &str) -> io::Result<()> {
+        match self.wire {
+            Wire::WebSocket => self.write_message(&Message::Text(line.to_string())),
+            _ => self.stream.write_all(format!("{}\n", line).as_bytes()),
+        }
+    }
+
+    /// Answers the WebSocket pings coming out of *pings* (see websocket::spawn_reader), each
+    /// before the next message sent.
+    pub fn answer_pings(&mut self, pings: Receiver<Vec<u8>>) {
+        self.pings = Some(pings);
+    }
+
+    fn write_message(&mut self, message: &Message) -> io::Result<()> {
+        if let Some(ref pings) = self.pings {
+            while let Ok(payload) = pings.try_recv() {
+                websocket::write_pong(&mut self.stream, &payload)?;
+            }
+        }
+        websocket::write_message(&mut self.stream, message)
+    }
+
+    /// Answers the client's half of the key exchange, after which everything sent is encrypted
+    /// (see e2e.rs). Only packets can carry that. *psk* is the client's pre-shared key.
+    pub fn start_e2e(&mut self, client_public: &[u8], psk: &hmac::Key) -> io::Result<()> {
+        self.check_packets()?;
+        let key_exchange = KeyExchange::new()?;
+        self.send(&key_exchange.key_packet(client_public, psk))?;
+        self.sealer = Some(key_exchange.seal_for(client_public)?);
+        Ok(())
+    }
+
+    /// Same as start_e2e(), for everyone who knows the group key.
+    pub fn start_group_e2e(&mut self, group: &GroupKey) -> io::Result<()> {
+        self.check_packets()?;
+        let (key_packet, sealer) = group.start()?;
+        self.send(&key_packet)?;
+        self.sealer = Some(sealer);
+        Ok(())
+    }
+
+    /// Whether the wire can carry encrypted packets.
+    pub fn check_packets(&self) -> io::Result<()> {
+        if self.wire != Wire::Packets {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "encryption needs wire=packets"));
+        }
+        Ok(())
+    }
+
+    /// Closes the connection, saying goodbye first on WebSocket.
+    pub fn close(&mut self) {
+        if self.wire == Wire::WebSocket {
+            websocket::write_message(&mut self.stream, &Message::Close).ok();
+        }
+        self.stream.shutdown().ok();
+    }
+
+    /// Sends audio to the client, and to the recording.
+    pub fn send_audio(&mut self, samples: &[f32]) -> io::Result<()> {
+        self.record(samples);
+        self.send(&Packet::Audio(samples.to_vec()))
+    }
+
+    /// Same as send_audio() for interleaved stereo, the recording gets it in mono.
+    pub fn send_stereo(&mut self, samples: &[f32]) -> io::Result<()> {
+        self.record(&mixer::downmix(samples));
+        self.send(&Packet::Stereo(samples.to_vec()))
+    }
+
+    /// Records audio that is not sent as is (suppressed silence).
+    pub fn record(&mut self, samples: &[f32]) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.write(samples);
+        }
+    }
+}
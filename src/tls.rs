@@ -0,0 +1,457 @@
+//! TLS (rustls) on top of any transport, for the audio and control connection.
+//!
+//! The server side is set up in the config (`tls_enabled`, `tls_cert`, `tls_key`), and with
+//! `tls_client_ca` it only accepts clients with a certificate signed by that CA. If the
+//! certificate and key don't exist yet and `tls_generate` is on, a development CA is created next
+//! to them, with a server certificate for "localhost" and a client certificate, both signed by it:
+//!
+//! ```text
+//! tls/ca.crt  tls/ca.key  tls/server.crt  tls/server.key  tls/client.crt  tls/client.key
+//! ```
+//!
+//! The client then trusts that CA with `tls_ca=tls/ca.crt`, and shows its own certificate with
+//! `tls_cert=tls/client.crt tls_key=tls/client.key`.
+//!
+//! The keys are only readable by their owner. TLS covers the audio and control listener only: the
+//! HTTP and WebSocket port (`http_port`, see http.rs) stays plain HTTP, for use behind a TLS
+//! proxy or on a trusted network.
+//!
+//! Reading and writing happen on different threads (control messages and audio), so a
+//! `TlsStream` can be cloned like a socket: every clone shares the TLS session, and the socket is
+//! only read without holding the session lock, so a waiting reader never blocks a writer.
+
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+            KeyUsagePurpose};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+
+use crate::transport::Connection;
+
+// As much as a TLS record holds
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+// Names the generated server certificate is valid for
+const DEV_SERVER_NAMES: [&str; 2] = ["localhost", "127.0.0.1"];
+
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub enabled: bool,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Require client certificates signed by this CA.
+    pub client_ca: Option<PathBuf>,
+    /// Create a development CA and certificates if *cert* and *key* don't exist.
+    pub generate: bool,
+}
+
+impl Default for TlsSettings {
+    fn default() -> TlsSettings {
+        TlsSettings {
+            enabled: false,
+            cert: PathBuf::from("tls/server.crt"),
+            key: PathBuf::from("tls/server.key"),
+            client_ca: None,
+            generate: true,
+        }
+    }
+}
+
+/// What the client needs to connect with TLS.
+#[derive(Debug, Clone, Default)]
+pub struct ClientSettings {
+    /// The CA the server certificate must be signed by.
+    pub ca: PathBuf,
+    /// Our own certificate and key, for servers that ask for one.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// The name the server certificate must be valid for, the host connected to by default.
+    pub server_name: Option<String>,
+}
+
+/// Server side: turns accepted connections into TLS ones.
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+
+    pub fn new(settings: &TlsSettings) -> io::Result<TlsAcceptor> {
+        if settings.generate && !settings.cert.exists() && !settings.key.exists() {
+            generate_dev_certs(&settings.cert, &settings.key)?;
+        }
+        let certs = load_certs(&settings.cert)?;
+        let key = load_key(&settings.key)?;
+
+        let builder = ServerConfig::builder();
+        let builder = match settings.client_ca {
+            Some(ref path) => {
+                let verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(path)?))
+                    .build()
+                    .map_err(tls_error)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_single_cert(certs, key).map_err(tls_error)?;
+
+        println!("TLS with {}{}", settings.cert.display(),
+                 if settings.client_ca.is_some() { ", client certificates required" } else { "" });
+        Ok(TlsAcceptor { config: Arc::new(config) })
+    }
+
+    /// Does the TLS handshake with a new client.
+    pub fn accept(&self, mut socket: Connection) -> io::Result<Connection> {
+        let session = ServerConnection::new(self.config.clone()).map_err(tls_error)?;
+        let mut session = rustls::Connection::from(session);
+        session.complete_io(&mut socket)?;
+        Ok(Connection::Tls(TlsStream::new(socket, session)))
+    }
+}
+
+/// Client side: turns a connection to the server into a TLS one.
+pub struct TlsConnector {
+    config: Arc<ClientConfig>,
+    server_name: Option<String>,
+}
+
+impl TlsConnector {
+
+    pub fn new(settings: &ClientSettings) -> io::Result<TlsConnector> {
+        let builder = ClientConfig::builder().with_root_certificates(load_roots(&settings.ca)?);
+        let config = match (&settings.cert, &settings.key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+                .map_err(tls_error)?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err(invalid("a client certificate needs both tls_cert and tls_key")),
+        };
+        Ok(TlsConnector { config: Arc::new(config), server_name: settings.server_name.clone() })
+    }
+
+    /// Does the TLS handshake with the server, *host* being the name we connected to.
+    pub fn connect(&self, mut socket: Connection, host: &str) -> io::Result<Connection> {
+        let name = self.server_name.as_deref().unwrap_or(host);
+        let name = ServerName::try_from(name.to_string())
+            .map_err(|_| invalid(&format!("invalid server name: {}", name)))?;
+        let session = ClientConnection::new(self.config.clone(), name).map_err(tls_error)?;
+        let mut session = rustls::Connection::from(session);
+        session.complete_io(&mut socket)?;
+        Ok(Connection::Tls(TlsStream::new(socket, session)))
+    }
+}
+
+/// A TLS connection, once the handshake is done.
+pub struct TlsStream {
+    socket: Box<Connection>,
+    session: Arc<Mutex<rustls::Connection>>,
+}
+
+impl TlsStream {
+
+    fn new(socket: Connection, session: rustls::Connection) -> TlsStream {
+        TlsStream { socket: Box::new(socket), session: Arc::new(Mutex::new(session)) }
+    }
+
+    pub fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream { socket: Box::new(self.socket.try_clone()?), session: self.session.clone() })
+    }
+
+    /// Says goodbye, then closes the socket.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        {
+            let mut session = self.session.lock().unwrap();
+            session.send_close_notify();
+            while session.wants_write() {
+                if session.write_tls(&mut *self.socket).is_err() {
+                    break;
+                }
+            }
+        }
+        self.socket.shutdown()
+    }
+
+    pub fn peer(&self) -> String {
+        self.socket.peer()
+    }
+
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        self.socket.peer_ip()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.session.lock().unwrap().reader().read(buf) {
+                Ok(n) => return Ok(n),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            // Nothing decrypted yet: wait for more from the socket, without the lock
+            let mut raw = [0u8; READ_BUFFER_SIZE];
+            let n = self.socket.read(&mut raw)?;
+            if n == 0 {
+                return Ok(0);
+            }
+
+            let mut session = self.session.lock().unwrap();
+            let mut received = &raw[..n];
+            while !received.is_empty() {
+                session.read_tls(&mut received)?;
+                session.process_new_packets().map_err(tls_error)?;
+            }
+            // Alerts, key updates...
+            while session.wants_write() {
+                session.write_tls(&mut *self.socket)?;
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let n = session.writer().write(buf)?;
+        while session.wants_write() {
+            session.write_tls(&mut *self.socket)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+/// Creates a development CA, and server and client certificates signed by it. The server ones
+/// go to *cert* and *key*, the rest next to them.
+pub fn generate_dev_certs(cert: &Path, key: &Path) -> io::Result<()> {
+    let dir = cert.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir)?;
+
+    let ca_key = KeyPair::generate().map_err(tls_error)?;
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).map_err(tls_error)?;
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params.distinguished_name.push(DnType::CommonName, "rust_test development CA");
+    ca_params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    let ca_cert = ca_params.self_signed(&ca_key).map_err(tls_error)?;
+
+    let server_key = KeyPair::generate().map_err(tls_error)?;
+    let names: Vec<String> = DEV_SERVER_NAMES.iter().map(|name| name.to_string()).collect();
+    let mut server_params = CertificateParams::new(names).map_err(tls_error)?;
+    server_params.distinguished_name.push(DnType::CommonName, "localhost");
+    server_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    let server_cert = server_params.signed_by(&server_key, &ca_cert, &ca_key).map_err(tls_error)?;
+
+    let client_key = KeyPair::generate().map_err(tls_error)?;
+    let mut client_params = CertificateParams::new(Vec::<String>::new()).map_err(tls_error)?;
+    client_params.distinguished_name.push(DnType::CommonName, "client");
+    client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let client_cert = client_params.signed_by(&client_key, &ca_cert, &ca_key).map_err(tls_error)?;
+
+    fs::write(dir.join("ca.crt"), ca_cert.pem())?;
+    write_private(&dir.join("ca.key"), &ca_key.serialize_pem())?;
+    fs::write(cert, server_cert.pem())?;
+    write_private(key, &server_key.serialize_pem())?;
+    fs::write(dir.join("client.crt"), client_cert.pem())?;
+    write_private(&dir.join("client.key"), &client_key.serialize_pem())?;
+
+    println!("Generated a development CA and certificates in {}", dir.display());
+    Ok(())
+}
+
+/// Writes a private key, readable by us alone.
+fn write_private(path: &Path, pem: &str) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    // mode() is only for new files
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(pem.as_bytes())
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid(&format!("no certificate in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| invalid(&format!("no private key in {}", path.display())))
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    Ok(roots)
+}
+
+fn tls_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    // A fresh directory of development certificates, removed when dropped
+    struct DevCerts {
+        dir: PathBuf,
+    }
+
+    impl DevCerts {
+        fn new(name: &str) -> DevCerts {
+            let dir = std::env::temp_dir().join(format!("rust_test_tls_{}_{}", std::process::id(), name));
+            fs::remove_dir_all(&dir).ok();
+            generate_dev_certs(&dir.join("server.crt"), &dir.join("server.key")).unwrap();
+            DevCerts { dir }
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.dir.join(name)
+        }
+
+        fn acceptor(&self) -> TlsAcceptor {
+            TlsAcceptor::new(&TlsSettings {
+                enabled: true,
+                cert: self.path("server.crt"),
+                key: self.path("server.key"),
+                client_ca: Some(self.path("ca.crt")),
+                generate: false,
+            }).unwrap()
+        }
+    }
+
+    impl Drop for DevCerts {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+
+    /// Connects to a server that echoes one line, and returns what came back (or the client's
+    /// error) and whether the server accepted the handshake.
+    fn echo(acceptor: TlsAcceptor, client: ClientSettings) -> (io::Result<String>, bool) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let socket = Connection::Tcp(listener.accept().unwrap().0);
+            match acceptor.accept(socket) {
+                Ok(mut connection) => {
+                    let mut line = [0u8; 6];
+                    connection.read_exact(&mut line).unwrap();
+                    connection.write_all(&line).unwrap();
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+
+        let result = (|| {
+            let connector = TlsConnector::new(&client)?;
+            let socket = Connection::Tcp(TcpStream::connect(address)?);
+            socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+            let mut connection = connector.connect(socket, "localhost")?;
+            connection.write_all(b"hello\n")?;
+            let mut line = [0u8; 6];
+            connection.read_exact(&mut line)?;
+            Ok(String::from_utf8_lossy(&line).to_string())
+        })();
+
+        (result, server.join().unwrap())
+    }
+
+    #[test]
+    fn accepts_client_signed_by_ca() {
+        let certs = DevCerts::new("accept");
+        let client = ClientSettings {
+            ca: certs.path("ca.crt"),
+            cert: Some(certs.path("client.crt")),
+            key: Some(certs.path("client.key")),
+            server_name: None,
+        };
+        let (result, accepted) = echo(certs.acceptor(), client);
+        assert_eq!(result.unwrap(), "hello\n");
+        assert!(accepted);
+    }
+
+    #[test]
+    fn rejects_client_without_certificate() {
+        let certs = DevCerts::new("no_cert");
+        let client = ClientSettings { ca: certs.path("ca.crt"), ..Default::default() };
+        let (result, accepted) = echo(certs.acceptor(), client);
+        assert!(result.is_err());
+        assert!(!accepted);
+    }
+
+    #[test]
+    fn rejects_client_signed_by_other_ca() {
+        let certs = DevCerts::new("other_ca");
+        let other = DevCerts::new("other_ca_client");
+        let client = ClientSettings {
+            ca: certs.path("ca.crt"),
+            cert: Some(other.path("client.crt")),
+            key: Some(other.path("client.key")),
+            server_name: None,
+        };
+        let (result, accepted) = echo(certs.acceptor(), client);
+        assert!(result.is_err());
+        assert!(!accepted);
+    }
+
+    #[test]
+    fn client_rejects_server_of_other_ca() {
+        let certs = DevCerts::new("server_check");
+        let other = DevCerts::new("server_check_other");
+        let client = ClientSettings {
+            ca: other.path("ca.crt"),
+            cert: Some(other.path("client.crt")),
+            key: Some(other.path("client.key")),
+            server_name: None,
+        };
+        let (result, accepted) = echo(certs.acceptor(), client);
+        assert!(result.is_err());
+        assert!(!accepted);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keys_are_private() {
+        let certs = DevCerts::new("private");
+        for name in ["ca.key", "server.key", "client.key"] {
+            let mode = fs::metadata(certs.path(name)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,380 @@
+//! Where the server listens and the client connects: TCP, a Unix domain socket, or an
+//! in-process channel.
+//!
+//! Addresses are written "tcp:host:port" (or just "host:port"), "unix:/path/to/socket" or
+//! "channel:name". A channel is a pair of in-memory pipes, for a server and client in the same
+//! process (as main.rs runs them) without going through the network stack at all.
+//!
+//! A `Connection` reads and writes the same whatever it runs on, so the rest of the code never
+//! needs to know. That includes TLS on top of any of them (see tls.rs).
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::tls::TlsStream;
+
+// Bytes a channel holds before the writer has to wait, like a socket buffer
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+    Channel(String),
+}
+
+impl Address {
+
+    pub fn parse(text: &str) -> Result<Address, String> {
+        if let Some(path) = text.strip_prefix("unix:") {
+            Ok(Address::Unix(PathBuf::from(path)))
+        } else if let Some(name) = text.strip_prefix("channel:") {
+            Ok(Address::Channel(name.to_string()))
+        } else {
+            let address = text.strip_prefix("tcp:").unwrap_or(text);
+            if !address.contains(':') {
+                return Err(format!("invalid address '{}', expected host:port", text));
+            }
+            Ok(Address::Tcp(address.to_string()))
+        }
+    }
+
+    /// The host name, e.g. to check the server's TLS certificate against.
+    pub fn host(&self) -> &str {
+        match self {
+            Address::Tcp(address) => {
+                let host = address.rsplitn(2, ':').last().unwrap_or(address);
+                host.trim_start_matches('[').trim_end_matches(']')
+            }
+            // Same host, by definition
+            Address::Unix(_) | Address::Channel(_) => "localhost",
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Tcp(address) => write!(f, "tcp:{}", address),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+            Address::Channel(name) => write!(f, "channel:{}", name),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+    Channel(Receiver<Connection>, String),
+}
+
+impl Listener {
+
+    pub fn bind(address: &Address) -> io::Result<Listener> {
+        match address {
+            Address::Tcp(address) => Ok(Listener::Tcp(TcpListener::bind(address.as_str())?)),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                // A socket left over from a previous run, but nothing else
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if !metadata.file_type().is_socket() {
+                        return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                            format!("{} exists and is not a socket", path.display())));
+                    }
+                    std::fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.clone()))
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(unsupported("Unix sockets")),
+            Address::Channel(name) => {
+                let (sender, receiver) = channel();
+                let mut channels = CHANNELS.lock().unwrap();
+                if channels.iter().any(|(other, _)| other == name) {
+                    return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("channel:{}", name)));
+                }
+                channels.push((name.clone(), sender));
+                Ok(Listener::Channel(receiver, name.clone()))
+            }
+        }
+    }
+
+    /// Waits for the next connection.
+    pub fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => Ok(Connection::Tcp(listener.accept()?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => Ok(Connection::Unix(listener.accept()?.0)),
+            Listener::Channel(receiver, _) => receiver.recv()
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "channel closed")),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        match self {
+            #[cfg(unix)]
+            Listener::Unix(_, path) => { std::fs::remove_file(path).ok(); }
+            Listener::Channel(_, name) => CHANNELS.lock().unwrap().retain(|(other, _)| other != name),
+            _ => {}
+        }
+    }
+}
+
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Channel(ChannelStream),
+    Tls(TlsStream),
+}
+
+impl Connection {
+
+    pub fn connect(address: &Address) -> io::Result<Connection> {
+        match address {
+            Address::Tcp(address) => Ok(Connection::Tcp(TcpStream::connect(address.as_str())?)),
+            #[cfg(unix)]
+            Address::Unix(path) => Ok(Connection::Unix(UnixStream::connect(path)?)),
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(unsupported("Unix sockets")),
+            Address::Channel(name) => {
+                let channels = CHANNELS.lock().unwrap();
+                let server = channels.iter()
+                    .find(|(other, _)| other == name)
+                    .map(|(_, sender)| sender)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionRefused, format!("channel:{}", name)))?;
+
+                let (client_end, server_end) = ChannelStream::pair(name);
+                server.send(Connection::Channel(server_end))
+                    .map_err(|_| io::Error::new(io::ErrorKind::ConnectionRefused, format!("channel:{}", name)))?;
+                Ok(Connection::Channel(client_end))
+            }
+        }
+    }
+
+    /// Another handle on the same connection, e.g. to read on one thread and write on another.
+    pub fn try_clone(&self) -> io::Result<Connection> {
+        match self {
+            Connection::Tcp(stream) => Ok(Connection::Tcp(stream.try_clone()?)),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Ok(Connection::Unix(stream.try_clone()?)),
+            Connection::Channel(stream) => Ok(Connection::Channel(stream.clone())),
+            Connection::Tls(stream) => Ok(Connection::Tls(stream.try_clone()?)),
+        }
+    }
+
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.shutdown(Shutdown::Both),
+            Connection::Channel(stream) => {
+                stream.close();
+                Ok(())
+            }
+            Connection::Tls(stream) => stream.shutdown(),
+        }
+    }
+
+    /// Who is on the other end, for logs and file names.
+    pub fn peer(&self) -> String {
+        match self {
+            Connection::Tcp(stream) => stream.peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| "unknown".to_string()),
+            #[cfg(unix)]
+            Connection::Unix(_) => "unix".to_string(),
+            Connection::Channel(stream) => format!("channel:{}", stream.name),
+            Connection::Tls(stream) => stream.peer(),
+        }
+    }
+
+    /// The other end's IP address, None for local connections.
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        match self {
+            Connection::Tcp(stream) => stream.peer_addr().ok().map(|addr| addr.ip()),
+            Connection::Tls(stream) => stream.peer_ip(),
+            _ => None,
+        }
+    }
+
+    /// Reads give up with a `WouldBlock` or `TimedOut` error after *timeout*, None to wait forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.set_read_timeout(timeout),
+            Connection::Channel(stream) => {
+                stream.incoming.set_timeout(timeout);
+                Ok(())
+            }
+            Connection::Tls(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.read(buf),
+            Connection::Channel(stream) => stream.incoming.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.write(buf),
+            Connection::Channel(stream) => stream.outgoing.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.flush(),
+            Connection::Channel(_) => Ok(()),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+// Servers listening on channels, by name
+static CHANNELS: Mutex<Vec<(String, Sender<Connection>)>> = Mutex::new(Vec::new());
+
+/// One end of an in-process connection.
+#[derive(Clone)]
+pub struct ChannelStream {
+    name: String,
+    incoming: Pipe,
+    outgoing: Pipe,
+    // When the last handle on this end goes, the connection closes
+    _end: Arc<End>,
+}
+
+impl ChannelStream {
+
+    fn pair(name: &str) -> (ChannelStream, ChannelStream) {
+        let up = Pipe::new();
+        let down = Pipe::new();
+        let stream = |incoming: &Pipe, outgoing: &Pipe| ChannelStream {
+            name: name.to_string(),
+            incoming: incoming.clone(),
+            outgoing: outgoing.clone(),
+            _end: Arc::new(End(vec![incoming.clone(), outgoing.clone()])),
+        };
+        (stream(&down, &up), stream(&up, &down))
+    }
+
+    fn close(&self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+struct End(Vec<Pipe>);
+
+impl Drop for End {
+    fn drop(&mut self) {
+        for pipe in &self.0 {
+            pipe.close();
+        }
+    }
+}
+
+/// Bytes going one way, with a bounded buffer.
+#[derive(Clone)]
+struct Pipe {
+    shared: Arc<(Mutex<PipeState>, Condvar)>,
+}
+
+struct PipeState {
+    buffer: VecDeque<u8>,
+    closed: bool,
+    timeout: Option<Duration>,
+}
+
+impl Pipe {
+
+    fn new() -> Pipe {
+        let state = PipeState { buffer: VecDeque::new(), closed: false, timeout: None };
+        Pipe { shared: Arc::new((Mutex::new(state), Condvar::new())) }
+    }
+
+    fn close(&self) {
+        let (state, condvar) = &*self.shared;
+        state.lock().unwrap().closed = true;
+        condvar.notify_all();
+    }
+
+    fn set_timeout(&self, timeout: Option<Duration>) {
+        self.shared.0.lock().unwrap().timeout = timeout;
+    }
+
+    /// Waits for something to read. Returns 0 once closed and empty.
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let (state, condvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        let deadline = state.timeout.map(|timeout| Instant::now() + timeout);
+        while state.buffer.is_empty() && !state.closed {
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"));
+                    }
+                    condvar.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => condvar.wait(state).unwrap(),
+            };
+        }
+        let len = std::cmp::min(buf.len(), state.buffer.len());
+        for (byte, value) in buf.iter_mut().zip(state.buffer.drain(..len)) {
+            *byte = value;
+        }
+        condvar.notify_all();
+        Ok(len)
+    }
+
+    /// Waits for room to write.
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let (state, condvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        while state.buffer.len() >= PIPE_CAPACITY && !state.closed {
+            state = condvar.wait(state).unwrap();
+        }
+        if state.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "channel closed"));
+        }
+        let len = std::cmp::min(buf.len(), PIPE_CAPACITY - state.buffer.len());
+        state.buffer.extend(&buf[..len]);
+        condvar.notify_all();
+        Ok(len)
+    }
+}
+
+#[cfg(not(unix))]
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{} are not supported on this platform", what))
+}
@@ -0,0 +1,149 @@
+//! Voice activity detection, and the comfort noise the client plays in place of silence.
+//!
+//! The detector looks at the energy and the zero-crossing rate of each block. The noise floor
+//! is tracked continuously (drops straight away, rises slowly, and not at all while someone is
+//! talking), and a block counts as speech when it is loud enough above it. Quiet blocks with a
+//! high zero-crossing rate still count, so "s" and "f" sounds at the start of words aren't cut
+//! off. A hangover keeps the state on speech for a little while after the last loud block, so
+//! we don't gate between words.
+
+use crate::processing::{db_to_linear, linear_to_db};
+
+const SAMPLE_RATE: f32 = 44_100.0;
+
+// Nothing under this is speech, however quiet the room is.
+const ABSOLUTE_FLOOR_DB: f32 = -60.0;
+// How fast the noise floor is allowed to rise, in dB per second.
+const NOISE_FLOOR_RISE_DB: f32 = 3.0;
+// Zero-crossing rate (crossings per sample) typical of unvoiced consonants.
+const FRICATIVE_ZCR: f32 = 0.25;
+
+#[derive(Debug, Clone)]
+pub struct VadSettings {
+    pub enabled: bool,
+    pub margin_db: f32,
+    pub hangover_ms: f32,
+}
+
+impl Default for VadSettings {
+    fn default() -> VadSettings {
+        VadSettings {
+            enabled: false,
+            margin_db: 9.0,
+            hangover_ms: 300.0,
+        }
+    }
+}
+
+pub struct Vad {
+    margin_db: f32,
+    hangover_frames: usize,
+    noise_floor_db: f32,
+    frames_since_speech: usize,
+    speech: bool,
+}
+
+impl Vad {
+
+    pub fn new(settings: &VadSettings) -> Vad {
+        Vad {
+            margin_db: settings.margin_db,
+            hangover_frames: (settings.hangover_ms / 1000.0 * SAMPLE_RATE) as usize,
+            noise_floor_db: ABSOLUTE_FLOOR_DB,
+            frames_since_speech: usize::MAX,
+            speech: false,
+        }
+    }
+
+    /// Feeds a block of samples to the detector.
+    /// Returns Some(new state) when the state changes, None otherwise.
+    pub fn process(&mut self, block: &[f32]) -> Option<bool> {
+        if block.is_empty() {
+            return None;
+        }
+
+        let energy_db = linear_to_db(rms(block));
+        let zcr = zero_crossing_rate(block);
+
+        let above_floor = energy_db - self.noise_floor_db;
+        let loud = energy_db > ABSOLUTE_FLOOR_DB && above_floor > self.margin_db;
+        let fricative = energy_db > ABSOLUTE_FLOOR_DB
+            && above_floor > self.margin_db / 2.0
+            && zcr > FRICATIVE_ZCR;
+
+        if loud || fricative {
+            self.frames_since_speech = 0;
+        } else {
+            self.frames_since_speech = self.frames_since_speech.saturating_add(block.len());
+        }
+
+        let speech = self.frames_since_speech <= self.hangover_frames;
+
+        // Track the noise floor. It is held while someone talks, or long speech would end up
+        // taken for noise.
+        if energy_db < self.noise_floor_db {
+            self.noise_floor_db = energy_db.max(ABSOLUTE_FLOOR_DB);
+        } else if !speech {
+            let block_secs = block.len() as f32 / SAMPLE_RATE;
+            self.noise_floor_db += NOISE_FLOOR_RISE_DB * block_secs;
+        }
+
+        if speech != self.speech {
+            self.speech = speech;
+            Some(speech)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_speech(&self) -> bool {
+        self.speech
+    }
+
+    /// The background noise level (RMS), to be reproduced as comfort noise.
+    pub fn noise_level(&self) -> f32 {
+        db_to_linear(self.noise_floor_db)
+    }
+}
+
+/// Gently low-passed white noise, played by the client while the server suppresses silence.
+/// Hearing nothing at all makes people think the connection dropped.
+pub struct ComfortNoise {
+    seed: u32,
+    last: f32,
+}
+
+impl ComfortNoise {
+
+    pub fn new() -> ComfortNoise {
+        ComfortNoise { seed: 0x1234_5678, last: 0.0 }
+    }
+
+    /// Fills *buffer* with noise at roughly *level* RMS.
+    pub fn fill(&mut self, buffer: &mut [f32], level: f32) {
+        // Uniform white noise has an RMS of 1/sqrt(3), the one-pole filter takes off about half.
+        let scale = level * 3.0_f32.sqrt() * 1.4;
+        for sample in buffer.iter_mut() {
+            // xorshift32
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 17;
+            self.seed ^= self.seed << 5;
+            let white = self.seed as f32 / u32::MAX as f32 * 2.0 - 1.0;
+
+            self.last = 0.5 * white + 0.5 * self.last;
+            *sample = self.last * scale;
+        }
+    }
+}
+
+fn rms(block: &[f32]) -> f32 {
+    let sum: f32 = block.iter().map(|s| s * s).sum();
+    (sum / block.len() as f32).sqrt()
+}
+
+fn zero_crossing_rate(block: &[f32]) -> f32 {
+    let crossings = block.windows(2)
+        .filter(|pair| (pair[0] >= 0.0) != (pair[1] >= 0.0))
+        .count();
+    crossings as f32 / block.len() as f32
+}
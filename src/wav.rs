@@ -1,13 +1,13 @@
 use std::f32::consts::PI;
-use std::i16;
 use std::fs::File;
 
-use hound;
 use portaudio as pa;
 
 // Define buffer size
 const BUFFER_SIZE: usize = 1024;
 
+// Not called from main, run it by hand to check the WAV output
+#[allow(dead_code)]
 pub(crate) fn audio_test() -> Result<(), pa::Error> {
 
     //=================================================================================
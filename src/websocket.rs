@@ -0,0 +1,313 @@
+//! WebSocket (RFC 6455) for browser listeners, on the HTTP port at `/ws`.
+//!
+//! Once the connection is upgraded it works like the TCP protocol: the first text message is the
+//! handshake line ("stream file 0s file=song.wav"), further text messages are control messages,
+//! and the server sends every packet (see packet.rs) as one binary message. `/` serves a page
+//! that does all that and plays the stream with the Web Audio API.
+//!
+//! Pings from the client are answered with a pong, sent by the session along with the next
+//! message so the two never get mixed up on the connection.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Receiver};
+
+use ring::digest;
+
+use crate::control::Control;
+use crate::http::Request;
+
+// Appended to the client's key to prove we speak WebSocket
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Nothing the browser sends us is anywhere near this
+const MAX_MESSAGE: usize = 1 << 16;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Close,
+}
+
+/// Answers an upgrade request. Returns an error (after telling the client) if it isn't one.
+pub fn accept(stream: &mut TcpStream, request: &Request) -> io::Result<()> {
+    let upgrade = request.header("Upgrade").map(|u| u.eq_ignore_ascii_case("websocket")).unwrap_or(false);
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) if upgrade => key,
+        _ => {
+            crate::http::respond(stream, "400 Bad Request", "text/plain", b"WebSocket only.\n")?;
+            return Err(invalid("not a WebSocket upgrade"));
+        }
+    };
+
+    let accept = accept_key(key);
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n", accept);
+    stream.write_all(response.as_bytes())
+}
+
+/// Sends a whole message in one frame. Server frames are not masked.
+pub fn write_message<W: Write>(writer: &mut W, message: &Message) -> io::Result<()> {
+    match message {
+        Message::Text(text) => write_frame(writer, OPCODE_TEXT, text.as_bytes()),
+        Message::Binary(bytes) => write_frame(writer, OPCODE_BINARY, bytes),
+        Message::Close => write_frame(writer, OPCODE_CLOSE, &[]),
+    }
+}
+
+/// Answers a ping, with the payload it came with.
+pub fn write_pong<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    write_frame(writer, OPCODE_PONG, payload)
+}
+
+fn write_frame<W: Write>(writer: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else if payload.len() <= 0xFFFF {
+        frame.push(126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)
+}
+
+/// Reads the next message, putting fragments back together. Pings and pongs are skipped.
+pub fn read_message<R: Read>(reader: &mut R) -> io::Result<Message> {
+    read_message_with_pings(reader, |_| {})
+}
+
+/// Same as read_message(), handing the payload of every ping to *on_ping* to be answered.
+pub fn read_message_with_pings<R: Read, F: FnMut(Vec<u8>)>(reader: &mut R, mut on_ping: F)
+    -> io::Result<Message> {
+    let mut message = Vec::new();
+    let mut message_opcode = None;
+
+    loop {
+        let mut header = [0u8; 2];
+        reader.read_exact(&mut header)?;
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0F;
+        let masked = header[1] & 0x80 != 0;
+
+        let length = match header[1] & 0x7F {
+            126 => {
+                let mut bytes = [0u8; 2];
+                reader.read_exact(&mut bytes)?;
+                u16::from_be_bytes(bytes) as usize
+            }
+            127 => {
+                let mut bytes = [0u8; 8];
+                reader.read_exact(&mut bytes)?;
+                u64::from_be_bytes(bytes) as usize
+            }
+            length => length as usize,
+        };
+        if message.len().checked_add(length).is_none_or(|total| total > MAX_MESSAGE) {
+            return Err(invalid("message too large"));
+        }
+
+        let mut mask = [0u8; 4];
+        if masked {
+            reader.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0u8; length];
+        reader.read_exact(&mut payload)?;
+        if masked {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+
+        match opcode {
+            OPCODE_CLOSE => return Ok(Message::Close),
+            OPCODE_PING => {
+                on_ping(payload);
+                continue;
+            }
+            OPCODE_PONG => continue,
+            OPCODE_CONTINUATION if message_opcode.is_none() => {
+                return Err(invalid("continuation without a message"));
+            }
+            OPCODE_CONTINUATION => {}
+            OPCODE_TEXT | OPCODE_BINARY => message_opcode = Some(opcode),
+            _ => return Err(invalid("unknown opcode")),
+        }
+        message.extend_from_slice(&payload);
+
+        if fin {
+            return match message_opcode {
+                Some(OPCODE_TEXT) => String::from_utf8(message)
+                    .map(Message::Text)
+                    .map_err(|_| invalid("text message is not UTF-8")),
+                _ => Ok(Message::Binary(message)),
+            };
+        }
+    }
+}
+
+/// Spawns a thread reading control messages sent as text messages, like control::spawn_reader
+/// does for the TCP protocol. The payloads of pings come out of the second receiver, for the
+/// session to answer (see Session::answer_pings).
+pub fn spawn_reader(mut tcp_stream: TcpStream) -> (Receiver<Control>, Receiver<Vec<u8>>) {
+    let (sender, receiver) = channel();
+    let (ping_sender, ping_receiver) = channel();
+
+    std::thread::spawn(move || {
+        loop {
+            let message = read_message_with_pings(&mut tcp_stream, |payload| {
+                ping_sender.send(payload).ok();
+            });
+            let line = match message {
+                Ok(Message::Text(line)) => line,
+                Ok(Message::Binary(_)) => continue,
+                Ok(Message::Close) | Err(_) => break,
+            };
+            match Control::parse(&line) {
+                Some(control) => {
+                    println!("Control message: {:?}", control);
+                    if sender.send(control).is_err() {
+                        break;
+                    }
+                }
+                None => println!("Unknown control message: {}", line),
+            }
+        }
+    });
+
+    (receiver, ping_receiver)
+}
+
+/// Sec-WebSocket-Accept for the client's Sec-WebSocket-Key.
+fn accept_key(key: &str) -> String {
+    let text = format!("{}{}", key, ACCEPT_GUID);
+    base64(digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, text.as_bytes()).as_ref())
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut text = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A client frame: masked, as browsers send them
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xFA, 0x21, 0x3D];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        // RFC 6455, section 1.3
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn base64_known_values() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+    }
+
+    #[test]
+    fn reads_masked_message() {
+        // RFC 6455, section 5.7
+        let frame = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        assert_eq!(read_message(&mut &frame[..]).unwrap(), Message::Text("Hello".to_string()));
+
+        let payload = vec![7; 300];
+        let frame = client_frame(true, OPCODE_BINARY, &payload);
+        assert_eq!(read_message(&mut &frame[..]).unwrap(), Message::Binary(payload));
+    }
+
+    #[test]
+    fn reads_fragmented_message() {
+        let mut stream = client_frame(false, OPCODE_TEXT, b"stream ");
+        // Control frames may come in the middle of a fragmented message
+        stream.extend(client_frame(true, OPCODE_PING, b"ping"));
+        stream.extend(client_frame(false, OPCODE_CONTINUATION, b"file "));
+        stream.extend(client_frame(true, OPCODE_CONTINUATION, b"0s"));
+        stream.extend(client_frame(true, OPCODE_CLOSE, b""));
+
+        let mut reader = &stream[..];
+        let mut pings = Vec::new();
+        let message = read_message_with_pings(&mut reader, |payload| pings.push(payload)).unwrap();
+        assert_eq!(message, Message::Text("stream file 0s".to_string()));
+        assert_eq!(pings, vec![b"ping".to_vec()]);
+        assert_eq!(read_message(&mut reader).unwrap(), Message::Close);
+    }
+
+    #[test]
+    fn rejects_bad_messages() {
+        let continuation = client_frame(true, OPCODE_CONTINUATION, b"lost");
+        assert!(read_message(&mut &continuation[..]).is_err());
+
+        let not_utf8 = client_frame(true, OPCODE_TEXT, &[0xFF, 0xFE]);
+        assert!(read_message(&mut &not_utf8[..]).is_err());
+
+        // A fragment, then one claiming a length that would overflow
+        let mut huge = client_frame(false, OPCODE_BINARY, b"x");
+        huge.extend_from_slice(&[0x80, 127]);
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(read_message(&mut &huge[..]).is_err());
+    }
+
+    #[test]
+    fn writes_unmasked_frames() {
+        let mut frame = Vec::new();
+        write_message(&mut frame, &Message::Text("Hello".to_string())).unwrap();
+        assert_eq!(frame, b"\x81\x05Hello");
+
+        let mut frame = Vec::new();
+        write_message(&mut frame, &Message::Binary(vec![0; 256])).unwrap();
+        assert_eq!(&frame[..4], &[0x82, 126, 0x01, 0x00]);
+        assert_eq!(frame.len(), 4 + 256);
+
+        let mut frame = Vec::new();
+        write_pong(&mut frame, b"ping").unwrap();
+        assert_eq!(frame, b"\x8A\x04ping");
+    }
+}
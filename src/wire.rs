@@ -0,0 +1,152 @@
+//! What goes on the wire after the handshake.
+//!
+//! By default that's framed packets (see packet.rs). With "wire=wav" in the handshake the server
+//! sends a WAV file instead: a RIFF/WAVE header with the sizes set as large as they go (length
+//! unknown, as for any live stream), then nothing but PCM. Anything can then save or play the
+//! stream, e.g. `echo "stream mic 30s wire=wav" | nc host 3333 > out.wav`. Samples are 16-bit,
+//! or 32-bit float with "bits=32". Only audio fits in a WAV file: suppressed silence is filled
+//! with comfort noise by the server, and the other packets are not sent.
+//!
+//! Browsers get the packets in WebSocket messages instead (see websocket.rs), that one is chosen
+//! by how they connect rather than in the handshake.
+
+use std::io::{self, Read};
+
+use crate::handshake::Handshake;
+use crate::packet::Packet;
+use crate::websocket::{self, Message};
+
+// Samples per audio packet when reading a WAV stream
+const READ_FRAMES: usize = 256;
+
+pub const FORMAT_PCM: u16 = 1;
+pub const FORMAT_FLOAT: u16 = 3;
+pub const FORMAT_MULAW: u16 = 7;
+
+// Streaming, the length is unknown
+const UNKNOWN_SIZE: u32 = 0xFFFF_FFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wire {
+    Packets,
+    Wav { float: bool },
+    /// One packet per binary WebSocket message.
+    WebSocket,
+}
+
+impl Wire {
+
+    pub fn from_handshake(handshake: &Handshake) -> Result<Wire, String> {
+        match handshake.get("wire").unwrap_or("packets") {
+            "packets" => Ok(Wire::Packets),
+            "wav" => match handshake.get("bits").unwrap_or("16") {
+                "16" => Ok(Wire::Wav { float: false }),
+                "32" => Ok(Wire::Wav { float: true }),
+                bits => Err(format!("unsupported bits for wire=wav: {}", bits)),
+            },
+            wire => Err(format!("unknown wire format: {}", wire)),
+        }
+    }
+
+    /// What the server sends first, before any audio.
+    pub fn header(&self, sample_rate: u32) -> Vec<u8> {
+        match *self {
+            Wire::Packets | Wire::WebSocket => Vec::new(),
+            Wire::Wav { float: true } => wav_header(FORMAT_FLOAT, 32, sample_rate),
+            Wire::Wav { float: false } => wav_header(FORMAT_PCM, 16, sample_rate),
+        }
+    }
+
+    /// Client side: reads the header the server sent, as header() writes it.
+    pub fn read_header<R: Read>(&mut self, reader: &mut R) -> io::Result<()> {
+        if let Wire::Wav { ref mut float } = *self {
+            let mut header = [0u8; 44];
+            reader.read_exact(&mut header)?;
+            if &header[..4] != b"RIFF" || &header[8..12] != b"WAVE" || &header[36..40] != b"data" {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "not a WAV stream"));
+            }
+            *float = u16::from_le_bytes([header[20], header[21]]) == FORMAT_FLOAT;
+        }
+        Ok(())
+    }
+
+    /// Client side: reads the next packet. A WAV stream is only audio, until it ends.
+    pub fn read_packet<R: Read>(&self, reader: &mut R) -> io::Result<Packet> {
+        let float = match *self {
+            Wire::Packets => return Packet::read_from(reader),
+            Wire::WebSocket => loop {
+                match websocket::read_message(reader)? {
+                    Message::Binary(bytes) => return Packet::read_from(&mut &bytes[..]),
+                    Message::Text(_) => continue,
+                    Message::Close => return Ok(Packet::End),
+                }
+            },
+            Wire::Wav { float } => float,
+        };
+        let sample_bytes = if float { 4 } else { 2 };
+
+        // Whatever arrives, as long as it's whole samples
+        let mut bytes = vec![0u8; READ_FRAMES * sample_bytes];
+        let mut len = 0;
+        while len == 0 || len % sample_bytes != 0 {
+            match reader.read(&mut bytes[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if len < sample_bytes {
+            return Ok(Packet::End);
+        }
+
+        let samples = bytes[..len - len % sample_bytes]
+            .chunks_exact(sample_bytes)
+            .map(|b| if float {
+                f32::from_le_bytes([b[0], b[1], b[2], b[3]])
+            } else {
+                i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0
+            })
+            .collect();
+        Ok(Packet::Audio(samples))
+    }
+}
+
+/// Header of a mono WAV stream of unknown length, in *format* (FORMAT_PCM...).
+pub fn wav_header(format: u16, bits: u16, sample_rate: u32) -> Vec<u8> {
+    let block_align: u16 = bits / 8;
+
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&UNKNOWN_SIZE.to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&format.to_le_bytes());
+    // Mono
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&bits.to_le_bytes());
+
+    header.extend_from_slice(b"data");
+    // Some readers want whole samples even here
+    header.extend_from_slice(&(UNKNOWN_SIZE - UNKNOWN_SIZE % block_align as u32).to_le_bytes());
+    header
+}
+
+/// Audio as PCM bytes for the WAV wire format, 16-bit or float.
+pub fn pcm_bytes(samples: &[f32], float: bool) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(samples.len() * 4);
+    for &sample in samples {
+        if float {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        } else {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+    }
+    bytes
+}